@@ -0,0 +1,12 @@
+-- Track which migrations have been applied
+CREATE TABLE IF NOT EXISTS public.schema_migrations (
+    version INTEGER PRIMARY KEY,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO public.schema_migrations (version) VALUES (1) ON CONFLICT DO NOTHING;
+
+-- Rate limit plan per key (free, pro, enterprise)
+ALTER TABLE public.keys ADD COLUMN plan TEXT NOT NULL DEFAULT 'free';
+
+INSERT INTO public.schema_migrations (version) VALUES (2);
@@ -0,0 +1,6 @@
+-- Monthly request quotas per key. monthly_quota overrides the plan's quota when set.
+ALTER TABLE public.keys ADD COLUMN monthly_quota BIGINT;
+ALTER TABLE public.keys ADD COLUMN period_uses INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE public.keys ADD COLUMN period_start TIMESTAMPTZ NOT NULL DEFAULT date_trunc('month', NOW());
+
+INSERT INTO public.schema_migrations (version) VALUES (3);
@@ -0,0 +1,22 @@
+-- Daily request counts per key, endpoint and response status
+CREATE TABLE public.usage_daily (
+    key_id BIGINT NOT NULL REFERENCES public.keys(id) ON DELETE CASCADE,
+    day DATE NOT NULL,
+    endpoint TEXT NOT NULL,
+    status SMALLINT NOT NULL,
+    requests BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (key_id, day, endpoint, status)
+);
+
+CREATE INDEX idx_usage_daily_day ON public.usage_daily(day);
+
+ALTER TABLE public.usage_daily ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Users can view usage of their keys"
+    ON public.usage_daily FOR SELECT
+    USING (EXISTS (
+        SELECT 1 FROM public.keys
+        WHERE keys.id = usage_daily.key_id AND keys.user_id = auth.uid()
+    ));
+
+INSERT INTO public.schema_migrations (version) VALUES (4);
@@ -0,0 +1,11 @@
+-- Store API keys as salted, peppered hashes instead of plaintext.
+-- After applying this migration run `ticketapi hash-keys` (with KEY_PEPPER set) to hash the
+-- existing keys, then apply 006 to drop the plaintext column.
+ALTER TABLE public.keys ADD COLUMN key_prefix TEXT;
+ALTER TABLE public.keys ADD COLUMN key_salt TEXT;
+ALTER TABLE public.keys ADD COLUMN key_hash TEXT;
+ALTER TABLE public.keys ALTER COLUMN api_key DROP NOT NULL;
+
+CREATE INDEX idx_keys_key_prefix ON public.keys(key_prefix);
+
+INSERT INTO public.schema_migrations (version) VALUES (5);
@@ -0,0 +1,15 @@
+-- Drop the plaintext API keys once `ticketapi hash-keys` has hashed all of them.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM public.keys WHERE key_hash IS NULL) THEN
+        RAISE EXCEPTION 'Some API keys are not hashed yet, run `ticketapi hash-keys` first';
+    END IF;
+END
+$$;
+
+ALTER TABLE public.keys DROP COLUMN api_key;
+ALTER TABLE public.keys ALTER COLUMN key_prefix SET NOT NULL;
+ALTER TABLE public.keys ALTER COLUMN key_salt SET NOT NULL;
+ALTER TABLE public.keys ALTER COLUMN key_hash SET NOT NULL;
+
+INSERT INTO public.schema_migrations (version) VALUES (6);
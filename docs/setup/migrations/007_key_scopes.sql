@@ -0,0 +1,11 @@
+-- Permissions per key. Existing keys keep full access.
+ALTER TABLE public.keys ADD COLUMN scopes TEXT[] NOT NULL DEFAULT ARRAY[
+    'tickets:read',
+    'tickets:write',
+    'tickets:delete',
+    'tickets:verify',
+    'usage:read',
+    'keys:manage'
+];
+
+INSERT INTO public.schema_migrations (version) VALUES (7);
@@ -0,0 +1,5 @@
+-- Key rotation: a rotated key keeps working until expires_at and points to its replacement.
+ALTER TABLE public.keys ADD COLUMN expires_at TIMESTAMPTZ;
+ALTER TABLE public.keys ADD COLUMN replaced_by BIGINT REFERENCES public.keys(id) ON DELETE SET NULL;
+
+INSERT INTO public.schema_migrations (version) VALUES (8);
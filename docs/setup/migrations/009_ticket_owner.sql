@@ -0,0 +1,43 @@
+-- Tickets belong to the account (keys.user_id) instead of the key that created them.
+-- key_id is kept as "created by" and cleared when that key is deleted.
+ALTER TABLE public.tickets ADD COLUMN owner_id UUID;
+
+UPDATE public.tickets
+SET owner_id = keys.user_id
+FROM public.keys
+WHERE keys.id = tickets.key_id;
+
+ALTER TABLE public.tickets ALTER COLUMN owner_id SET NOT NULL;
+
+ALTER TABLE public.tickets ALTER COLUMN key_id DROP NOT NULL;
+ALTER TABLE public.tickets DROP CONSTRAINT tickets_key_id_fkey;
+ALTER TABLE public.tickets
+    ADD CONSTRAINT tickets_key_id_fkey FOREIGN KEY (key_id) REFERENCES public.keys(id) ON DELETE SET NULL;
+
+COMMENT ON COLUMN public.tickets.key_id IS 'Key that created the ticket';
+
+CREATE INDEX idx_tickets_owner_id ON public.tickets(owner_id);
+
+-- Row Level Security policies for tickets table, now by owner
+DROP POLICY "Users can view tickets associated with their keys" ON public.tickets;
+DROP POLICY "Users can insert tickets associated with their keys" ON public.tickets;
+DROP POLICY "Users can update tickets associated with their keys" ON public.tickets;
+DROP POLICY "Users can delete tickets associated with their keys" ON public.tickets;
+
+CREATE POLICY "Users can view their tickets"
+    ON public.tickets FOR SELECT
+    USING (auth.uid() = owner_id);
+
+CREATE POLICY "Users can insert their tickets"
+    ON public.tickets FOR INSERT
+    WITH CHECK (auth.uid() = owner_id);
+
+CREATE POLICY "Users can update their tickets"
+    ON public.tickets FOR UPDATE
+    USING (auth.uid() = owner_id);
+
+CREATE POLICY "Users can delete their tickets"
+    ON public.tickets FOR DELETE
+    USING (auth.uid() = owner_id);
+
+INSERT INTO public.schema_migrations (version) VALUES (9);
@@ -0,0 +1,15 @@
+-- The server runs caller queries under this role (DATABASE_REQUEST_ROLE, "authenticated" by default)
+-- with request.jwt.claims set, so the Row Level Security policies apply to them.
+GRANT USAGE ON SCHEMA public TO authenticated;
+
+GRANT SELECT, INSERT, UPDATE, DELETE ON public.keys TO authenticated;
+GRANT SELECT, INSERT, UPDATE, DELETE ON public.tickets TO authenticated;
+GRANT SELECT ON public.usage_daily TO authenticated;
+
+GRANT USAGE ON SEQUENCE public.keys_id_seq TO authenticated;
+GRANT USAGE ON SEQUENCE public.tickets_id_seq TO authenticated;
+
+-- The server's own role must be allowed to switch to the request role.
+GRANT authenticated TO CURRENT_USER;
+
+INSERT INTO public.schema_migrations (version) VALUES (10);
@@ -0,0 +1,107 @@
+-- Organizations own tickets on behalf of their members. Keys can be bound to an organization and
+-- act with the role their user has in it.
+CREATE TABLE public.organizations (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE public.organization_members (
+    organization_id UUID NOT NULL REFERENCES public.organizations(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('owner', 'admin', 'editor', 'viewer', 'door-staff')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (organization_id, user_id)
+);
+
+CREATE INDEX idx_organization_members_user_id ON public.organization_members(user_id);
+
+ALTER TABLE public.keys ADD COLUMN organization_id UUID REFERENCES public.organizations(id) ON DELETE CASCADE;
+
+COMMENT ON COLUMN public.keys.organization_id IS 'Organization the key acts for, NULL for the user''s own account';
+
+-- Whether the current user has one of the given roles in an organization. SECURITY DEFINER so the
+-- policies on organization_members can use it without recursing into themselves.
+CREATE FUNCTION public.has_org_role(organization UUID, roles TEXT[])
+RETURNS BOOLEAN
+LANGUAGE sql STABLE SECURITY DEFINER SET search_path = public
+AS $$
+    SELECT EXISTS (
+        SELECT 1 FROM public.organization_members
+        WHERE organization_id = organization AND user_id = auth.uid() AND role = ANY(roles)
+    );
+$$;
+
+-- Creates an organization with the current user as its owner.
+CREATE FUNCTION public.create_organization(organization_name TEXT)
+RETURNS SETOF public.organizations
+LANGUAGE plpgsql SECURITY DEFINER SET search_path = public
+AS $$
+DECLARE
+    organization public.organizations;
+BEGIN
+    IF auth.uid() IS NULL THEN
+        RAISE EXCEPTION 'create_organization requires an authenticated user';
+    END IF;
+
+    INSERT INTO public.organizations (name) VALUES (organization_name) RETURNING * INTO organization;
+    INSERT INTO public.organization_members (organization_id, user_id, role)
+        VALUES (organization.id, auth.uid(), 'owner');
+
+    RETURN NEXT organization;
+END;
+$$;
+
+ALTER TABLE public.organizations ENABLE ROW LEVEL SECURITY;
+ALTER TABLE public.organization_members ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view their organizations"
+    ON public.organizations FOR SELECT
+    USING (public.has_org_role(id, ARRAY['owner', 'admin', 'editor', 'viewer', 'door-staff']));
+
+CREATE POLICY "Members can view the members of their organizations"
+    ON public.organization_members FOR SELECT
+    USING (public.has_org_role(organization_id, ARRAY['owner', 'admin', 'editor', 'viewer', 'door-staff']));
+
+CREATE POLICY "Owners and admins can add members"
+    ON public.organization_members FOR INSERT
+    WITH CHECK (public.has_org_role(organization_id, ARRAY['owner', 'admin']));
+
+CREATE POLICY "Owners and admins can change roles"
+    ON public.organization_members FOR UPDATE
+    USING (public.has_org_role(organization_id, ARRAY['owner', 'admin']));
+
+CREATE POLICY "Owners and admins can remove members"
+    ON public.organization_members FOR DELETE
+    USING (public.has_org_role(organization_id, ARRAY['owner', 'admin']));
+
+-- Tickets owned by an organization are visible to all members and changed by editors and up.
+DROP POLICY "Users can view their tickets" ON public.tickets;
+DROP POLICY "Users can insert their tickets" ON public.tickets;
+DROP POLICY "Users can update their tickets" ON public.tickets;
+DROP POLICY "Users can delete their tickets" ON public.tickets;
+
+CREATE POLICY "Users can view their tickets"
+    ON public.tickets FOR SELECT
+    USING (
+        auth.uid() = owner_id
+        OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor', 'viewer', 'door-staff'])
+    );
+
+CREATE POLICY "Users can insert their tickets"
+    ON public.tickets FOR INSERT
+    WITH CHECK (auth.uid() = owner_id OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor']));
+
+CREATE POLICY "Users can update their tickets"
+    ON public.tickets FOR UPDATE
+    USING (auth.uid() = owner_id OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor']));
+
+CREATE POLICY "Users can delete their tickets"
+    ON public.tickets FOR DELETE
+    USING (auth.uid() = owner_id OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor']));
+
+GRANT SELECT ON public.organizations TO authenticated;
+GRANT SELECT, INSERT, UPDATE, DELETE ON public.organization_members TO authenticated;
+GRANT EXECUTE ON FUNCTION public.create_organization(TEXT) TO authenticated;
+
+INSERT INTO public.schema_migrations (version) VALUES (11);
@@ -0,0 +1,99 @@
+-- Signed ticket codes carry a version that is bumped on every transfer, so codes issued to a
+-- previous holder stop verifying.
+ALTER TABLE public.tickets ADD COLUMN code_version INTEGER NOT NULL DEFAULT 1;
+
+-- Whether the current user may read or change data of an account (their own or an organization).
+CREATE FUNCTION public.can_read_account(account UUID)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE
+AS $$
+    SELECT auth.uid() = account
+        OR public.has_org_role(account, ARRAY['owner', 'admin', 'editor', 'viewer', 'door-staff']);
+$$;
+
+CREATE FUNCTION public.can_write_account(account UUID)
+RETURNS BOOLEAN
+LANGUAGE sql STABLE
+AS $$
+    SELECT auth.uid() = account OR public.has_org_role(account, ARRAY['owner', 'admin', 'editor']);
+$$;
+
+-- Per-event settings of an account, events are identified by the tickets' event_name
+CREATE TABLE public.event_policies (
+    owner_id UUID NOT NULL,
+    event_name TEXT NOT NULL,
+    transfers_enabled BOOLEAN NOT NULL DEFAULT TRUE,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (owner_id, event_name)
+);
+
+CREATE TABLE public.ticket_transfers (
+    id BIGSERIAL PRIMARY KEY,
+    ticket_id BIGINT NOT NULL REFERENCES public.tickets(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL,
+    from_name TEXT NOT NULL,
+    from_email TEXT NOT NULL,
+    to_name TEXT NOT NULL,
+    to_email TEXT NOT NULL,
+    -- Code version of the ticket when the transfer was created
+    code_version INTEGER NOT NULL,
+    -- SHA-256 of the acceptance token, NULL if no acceptance is required
+    token_hash TEXT,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'completed', 'cancelled')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    completed_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_ticket_transfers_ticket_id ON public.ticket_transfers(ticket_id);
+
+CREATE TABLE public.ticket_history (
+    id BIGSERIAL PRIMARY KEY,
+    ticket_id BIGINT NOT NULL REFERENCES public.tickets(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL,
+    action TEXT NOT NULL,
+    details JSONB NOT NULL DEFAULT 'null',
+    -- Caller that made the change
+    key_id BIGINT REFERENCES public.keys(id) ON DELETE SET NULL,
+    user_id UUID,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_ticket_history_ticket_id ON public.ticket_history(ticket_id);
+
+ALTER TABLE public.event_policies ENABLE ROW LEVEL SECURITY;
+ALTER TABLE public.ticket_transfers ENABLE ROW LEVEL SECURITY;
+ALTER TABLE public.ticket_history ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view event policies"
+    ON public.event_policies FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can set event policies"
+    ON public.event_policies FOR ALL
+    USING (public.can_write_account(owner_id))
+    WITH CHECK (public.can_write_account(owner_id));
+
+CREATE POLICY "Members can view transfers"
+    ON public.ticket_transfers FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can manage transfers"
+    ON public.ticket_transfers FOR ALL
+    USING (public.can_write_account(owner_id))
+    WITH CHECK (public.can_write_account(owner_id));
+
+CREATE POLICY "Members can view ticket history"
+    ON public.ticket_history FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can add ticket history"
+    ON public.ticket_history FOR INSERT
+    WITH CHECK (public.can_write_account(owner_id));
+
+GRANT SELECT, INSERT, UPDATE ON public.event_policies TO authenticated;
+GRANT SELECT, INSERT, UPDATE ON public.ticket_transfers TO authenticated;
+GRANT SELECT, INSERT ON public.ticket_history TO authenticated;
+GRANT USAGE ON SEQUENCE public.ticket_transfers_id_seq TO authenticated;
+GRANT USAGE ON SEQUENCE public.ticket_history_id_seq TO authenticated;
+
+INSERT INTO public.schema_migrations (version) VALUES (12);
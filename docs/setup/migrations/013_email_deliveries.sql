@@ -0,0 +1,29 @@
+-- Emails sent to ticket holders and their delivery status
+CREATE TABLE public.email_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    ticket_id BIGINT NOT NULL REFERENCES public.tickets(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL,
+    template TEXT NOT NULL,
+    recipient TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'sent', 'failed')),
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_email_deliveries_ticket_id ON public.email_deliveries(ticket_id);
+
+ALTER TABLE public.email_deliveries ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view email deliveries"
+    ON public.email_deliveries FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can send emails"
+    ON public.email_deliveries FOR INSERT
+    WITH CHECK (public.can_write_account(owner_id));
+
+GRANT SELECT, INSERT ON public.email_deliveries TO authenticated;
+GRANT USAGE ON SEQUENCE public.email_deliveries_id_seq TO authenticated;
+
+INSERT INTO public.schema_migrations (version) VALUES (13);
@@ -0,0 +1,24 @@
+-- Look of rendered tickets per account
+CREATE TABLE public.ticket_templates (
+    owner_id UUID PRIMARY KEY,
+    primary_color TEXT NOT NULL DEFAULT '#1f2937',
+    text_color TEXT NOT NULL DEFAULT '#111827',
+    -- JPEG, at most 512 KiB
+    logo BYTEA,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE public.ticket_templates ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view the ticket template"
+    ON public.ticket_templates FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can change the ticket template"
+    ON public.ticket_templates FOR ALL
+    USING (public.can_write_account(owner_id))
+    WITH CHECK (public.can_write_account(owner_id));
+
+GRANT SELECT, INSERT, UPDATE ON public.ticket_templates TO authenticated;
+
+INSERT INTO public.schema_migrations (version) VALUES (14);
@@ -0,0 +1,18 @@
+-- Devices registered for updates of Apple Wallet passes. Written by the Wallet web service, which
+-- authenticates passes by their token, so there is no access for authenticated users.
+CREATE TABLE public.pass_registrations (
+    device_id TEXT NOT NULL,
+    pass_type_id TEXT NOT NULL,
+    -- "<ticket id>.<code version>"
+    serial_number TEXT NOT NULL,
+    ticket_id BIGINT NOT NULL REFERENCES public.tickets(id) ON DELETE CASCADE,
+    push_token TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (device_id, pass_type_id, serial_number)
+);
+
+CREATE INDEX pass_registrations_ticket_id_idx ON public.pass_registrations (ticket_id);
+
+ALTER TABLE public.pass_registrations ENABLE ROW LEVEL SECURITY;
+
+INSERT INTO public.schema_migrations (version) VALUES (15);
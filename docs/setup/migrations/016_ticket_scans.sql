@@ -0,0 +1,38 @@
+-- Door scans of tickets, uploaded by offline scanners
+CREATE TABLE public.ticket_scans (
+    id BIGSERIAL PRIMARY KEY,
+    ticket_id BIGINT NOT NULL REFERENCES public.tickets(id) ON DELETE CASCADE,
+    owner_id UUID NOT NULL,
+    scanner_id TEXT NOT NULL,
+    gate TEXT NOT NULL,
+    -- Time of the scan on the scanner
+    scanned_at TIMESTAMPTZ NOT NULL,
+    result TEXT NOT NULL CHECK (result IN ('admitted', 'rejected', 'conflict')),
+    reason TEXT,
+    -- Caller that uploaded the scan
+    key_id BIGINT REFERENCES public.keys(id) ON DELETE SET NULL,
+    user_id UUID,
+    uploaded_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- A re-uploaded log does not record its scans twice
+    UNIQUE (ticket_id, scanner_id, scanned_at)
+);
+
+CREATE INDEX idx_ticket_scans_ticket_id ON public.ticket_scans(ticket_id, scanned_at);
+
+ALTER TABLE public.ticket_scans ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view ticket scans"
+    ON public.ticket_scans FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Door staff and editors can add ticket scans"
+    ON public.ticket_scans FOR INSERT
+    WITH CHECK (
+        auth.uid() = owner_id
+        OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor', 'door-staff'])
+    );
+
+GRANT SELECT, INSERT ON public.ticket_scans TO authenticated;
+GRANT USAGE ON SEQUENCE public.ticket_scans_id_seq TO authenticated;
+
+INSERT INTO public.schema_migrations (version) VALUES (16);
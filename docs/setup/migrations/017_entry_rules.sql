@@ -0,0 +1,15 @@
+-- Entry rules of tickets: 0 means no limit
+ALTER TABLE public.tickets
+    ADD COLUMN max_entries INTEGER NOT NULL DEFAULT 1 CHECK (max_entries >= 0),
+    ADD COLUMN requires_checkout BOOLEAN NOT NULL DEFAULT TRUE,
+    ADD COLUMN daily_entry_limit INTEGER NOT NULL DEFAULT 0 CHECK (daily_entry_limit >= 0);
+
+-- Online scans are recorded too, and exits next to entries
+ALTER TABLE public.ticket_scans
+    ADD COLUMN direction TEXT NOT NULL DEFAULT 'entry' CHECK (direction IN ('entry', 'exit'));
+
+ALTER TABLE public.ticket_scans DROP CONSTRAINT ticket_scans_result_check;
+ALTER TABLE public.ticket_scans
+    ADD CONSTRAINT ticket_scans_result_check CHECK (result IN ('admitted', 'exited', 'rejected', 'conflict'));
+
+INSERT INTO public.schema_migrations (version) VALUES (17);
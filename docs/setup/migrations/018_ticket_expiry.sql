@@ -0,0 +1,4 @@
+-- Finds the active tickets of past events for the expiry task
+CREATE INDEX idx_tickets_active_event_date ON public.tickets(event_date) WHERE status = 'Active';
+
+INSERT INTO public.schema_migrations (version) VALUES (18);
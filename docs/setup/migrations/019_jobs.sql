@@ -0,0 +1,44 @@
+-- Durable background jobs, performed by the workers of the API instances
+CREATE TABLE public.jobs (
+    id BIGSERIAL PRIMARY KEY,
+    -- Account the job was queued for
+    owner_id UUID NOT NULL,
+    kind TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued' CHECK (status IN ('queued', 'running', 'succeeded', 'dead')),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL DEFAULT 5,
+    -- Earliest time of the next attempt
+    run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    locked_at TIMESTAMPTZ,
+    last_error TEXT,
+    result JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX idx_jobs_due ON public.jobs(run_at) WHERE status IN ('queued', 'running');
+
+ALTER TABLE public.email_deliveries ADD COLUMN job_id BIGINT REFERENCES public.jobs(id) ON DELETE SET NULL;
+
+ALTER TABLE public.jobs ENABLE ROW LEVEL SECURITY;
+
+CREATE POLICY "Members can view jobs"
+    ON public.jobs FOR SELECT
+    USING (public.can_read_account(owner_id));
+
+CREATE POLICY "Editors can queue jobs"
+    ON public.jobs FOR INSERT
+    WITH CHECK (public.can_write_account(owner_id));
+
+GRANT SELECT, INSERT ON public.jobs TO authenticated;
+GRANT USAGE ON SEQUENCE public.jobs_id_seq TO authenticated;
+-- Deliveries are linked to their job in the transaction that queues it
+GRANT UPDATE (job_id) ON public.email_deliveries TO authenticated;
+
+CREATE POLICY "Editors can link email deliveries to jobs"
+    ON public.email_deliveries FOR UPDATE
+    USING (public.can_write_account(owner_id));
+
+INSERT INTO public.schema_migrations (version) VALUES (19);
@@ -0,0 +1,6 @@
+-- Rotation chains: a key issued by rotation points to the first key of its chain, whose id the
+-- rate limit is keyed by. The chain's newest key carries the quota counters.
+ALTER TABLE public.keys ADD COLUMN lineage_id BIGINT;
+CREATE INDEX keys_lineage_idx ON public.keys (COALESCE(lineage_id, id));
+
+INSERT INTO public.schema_migrations (version) VALUES (20);
@@ -0,0 +1,18 @@
+-- Scopes for organizations and their members. Keys that could manage keys could manage members
+-- before these existed and keep doing so.
+UPDATE public.keys
+    SET scopes = scopes || ARRAY['members:read', 'members:manage']
+    WHERE 'keys:manage' = ANY(scopes);
+
+ALTER TABLE public.keys ALTER COLUMN scopes SET DEFAULT ARRAY[
+    'tickets:read',
+    'tickets:write',
+    'tickets:delete',
+    'tickets:verify',
+    'usage:read',
+    'keys:manage',
+    'members:read',
+    'members:manage'
+];
+
+INSERT INTO public.schema_migrations (version) VALUES (21);
@@ -0,0 +1,4 @@
+-- Transfers are accepted by their token alone, so they are looked up by its hash.
+CREATE UNIQUE INDEX idx_ticket_transfers_token_hash ON public.ticket_transfers(token_hash) WHERE token_hash IS NOT NULL;
+
+INSERT INTO public.schema_migrations (version) VALUES (22);
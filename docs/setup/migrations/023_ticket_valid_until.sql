@@ -0,0 +1,8 @@
+-- End of a ticket's validity for tickets that are valid beyond their event_date, e.g. festival
+-- passes for several days. Tickets expire after it, or after event_date if it is not set.
+ALTER TABLE public.tickets ADD COLUMN valid_until TIMESTAMPTZ;
+
+DROP INDEX public.idx_tickets_active_event_date;
+CREATE INDEX idx_tickets_active_valid_until ON public.tickets((COALESCE(valid_until, event_date))) WHERE status = 'Active';
+
+INSERT INTO public.schema_migrations (version) VALUES (23);
@@ -0,0 +1,5 @@
+-- Email deliveries are kept when their ticket is deleted, so the cancellation email sent on
+-- deletion can still be looked up by the ticket's id. Ticket ids are never reused.
+ALTER TABLE public.email_deliveries DROP CONSTRAINT email_deliveries_ticket_id_fkey;
+
+INSERT INTO public.schema_migrations (version) VALUES (24);
@@ -0,0 +1,11 @@
+-- Offline scans uploaded late can change the results of the scans recorded after them
+GRANT UPDATE (result, reason) ON public.ticket_scans TO authenticated;
+
+CREATE POLICY "Door staff and editors can revise ticket scans"
+    ON public.ticket_scans FOR UPDATE
+    USING (
+        auth.uid() = owner_id
+        OR public.has_org_role(owner_id, ARRAY['owner', 'admin', 'editor', 'door-staff'])
+    );
+
+INSERT INTO public.schema_migrations (version) VALUES (25);
@@ -0,0 +1,6 @@
+-- Transfers that require acceptance expire if the recipient does not accept them in time
+ALTER TABLE public.ticket_transfers ADD COLUMN expires_at TIMESTAMPTZ;
+
+UPDATE public.ticket_transfers SET expires_at = created_at + INTERVAL '7 days' WHERE token_hash IS NOT NULL;
+
+INSERT INTO public.schema_migrations (version) VALUES (26);
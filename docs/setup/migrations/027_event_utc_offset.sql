@@ -0,0 +1,5 @@
+-- UTC offset in seconds the event date was given with. Dates are returned and put in calendars
+-- in the event's local time rather than in UTC.
+ALTER TABLE public.tickets ADD COLUMN event_utc_offset INTEGER NOT NULL DEFAULT 0;
+
+INSERT INTO public.schema_migrations (version) VALUES (27);
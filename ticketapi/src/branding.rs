@@ -0,0 +1,222 @@
+// Per-account look of rendered tickets: colors and an optional JPEG logo.
+
+use rocket::serde::{ Deserialize, Serialize };
+use tokio_postgres::{ Error, GenericClient };
+use uuid::Uuid;
+
+use crate::{ db, Caller };
+
+// Largest accepted logo, in bytes.
+pub const MAX_LOGO_SIZE: usize = 512 * 1024;
+
+const DEFAULT_PRIMARY_COLOR: &str = "#1f2937";
+const DEFAULT_TEXT_COLOR: &str = "#111827";
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TicketTemplate {
+    // Background of the header band, as #rrggbb.
+    pub primary_color: String,
+    // Color of the ticket details, as #rrggbb.
+    pub text_color: String,
+    #[serde(skip_deserializing)]
+    pub has_logo: bool,
+}
+
+impl Default for TicketTemplate {
+    fn default() -> TicketTemplate {
+        TicketTemplate {
+            primary_color: DEFAULT_PRIMARY_COLOR.to_string(),
+            text_color: DEFAULT_TEXT_COLOR.to_string(),
+            has_logo: false,
+        }
+    }
+}
+
+// Logo as stored: JPEG data with its dimensions and number of color components.
+pub struct Logo {
+    pub data: Vec<u8>,
+    pub width: u16,
+    pub height: u16,
+    pub components: u8,
+}
+
+impl Logo {
+    // Reads the dimensions from the JPEG's start-of-frame segment. Returns None if the data is
+    // not a JPEG PDF viewers can decode: 8-bit baseline, extended sequential or progressive.
+    pub fn from_jpeg(data: Vec<u8>) -> Option<Logo> {
+        if !data.starts_with(&[0xff, 0xd8]) {
+            return None;
+        }
+
+        let mut i = 2;
+        while i + 4 <= data.len() {
+            if data[i] != 0xff {
+                return None;
+            }
+            let marker = data[i + 1];
+            let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+
+            // SOF0 to SOF15, except DHT (c4), JPG (c8) and DAC (cc). Only SOF0 to SOF2 are
+            // supported by DCTDecode; lossless, hierarchical and arithmetic coded frames are not.
+            if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+                if marker > 0xc2 {
+                    return None;
+                }
+                let frame = data.get(i + 4..i + 10)?;
+                let precision = frame[0];
+                let height = u16::from_be_bytes([frame[1], frame[2]]);
+                let width = u16::from_be_bytes([frame[3], frame[4]]);
+                let components = frame[5];
+
+                if precision != 8 || width == 0 || height == 0 || ![1, 3, 4].contains(&components) {
+                    return None;
+                }
+                return Some(Logo { data, width, height, components });
+            }
+
+            i += 2 + length;
+        }
+
+        None
+    }
+}
+
+pub fn parse_color(color: &str) -> Option<(f32, f32, f32)> {
+    let hex = color.strip_prefix('#')?;
+    // from_str_radix alone would accept a sign, e.g. "#+fffff".
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let value = u32::from_str_radix(hex, 16).ok()?;
+
+    Some((
+        ((value >> 16) & 0xff) as f32 / 255.0,
+        ((value >> 8) & 0xff) as f32 / 255.0,
+        (value & 0xff) as f32 / 255.0,
+    ))
+}
+
+pub fn luminance((r, g, b): (f32, f32, f32)) -> f32 {
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+// Template of the caller's account, the defaults if none was stored.
+pub async fn get_template(caller: &Caller) -> Result<(TicketTemplate, Option<Logo>), Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let template = template_of(&transaction, caller.account_id).await?;
+
+    transaction.commit().await?;
+
+    Ok(template)
+}
+
+pub async fn template_of(client: &impl GenericClient, owner_id: Uuid) -> Result<(TicketTemplate, Option<Logo>), Error> {
+    let row = client.query_opt(
+        "SELECT primary_color, text_color, logo FROM ticket_templates WHERE owner_id = $1",
+        &[&owner_id]
+    ).await?;
+
+    Ok(match row {
+        Some(row) => {
+            let logo = row.get::<_, Option<Vec<u8>>>(2).and_then(Logo::from_jpeg);
+            let template = TicketTemplate {
+                primary_color: row.get(0),
+                text_color: row.get(1),
+                has_logo: logo.is_some(),
+            };
+            (template, logo)
+        }
+        None => (TicketTemplate::default(), None),
+    })
+}
+
+pub async fn set_colors(caller: &Caller, template: &TicketTemplate) -> Result<TicketTemplate, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query =
+        "INSERT INTO ticket_templates (owner_id, primary_color, text_color) VALUES ($1, $2, $3) ON CONFLICT (owner_id) DO UPDATE SET primary_color = EXCLUDED.primary_color, text_color = EXCLUDED.text_color, updated_at = NOW() RETURNING primary_color, text_color, logo IS NOT NULL";
+    let row = transaction
+        .query_one(query, &[&caller.account_id, &template.primary_color, &template.text_color]).await?;
+
+    transaction.commit().await?;
+
+    Ok(TicketTemplate { primary_color: row.get(0), text_color: row.get(1), has_logo: row.get(2) })
+}
+
+// Stores or (with None) removes the logo.
+pub async fn set_logo(caller: &Caller, logo: Option<&Logo>) -> Result<TicketTemplate, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let data = logo.map(|logo| logo.data.as_slice());
+    let query =
+        "INSERT INTO ticket_templates (owner_id, primary_color, text_color, logo) VALUES ($1, $2, $3, $4) ON CONFLICT (owner_id) DO UPDATE SET logo = EXCLUDED.logo, updated_at = NOW() RETURNING primary_color, text_color, logo IS NOT NULL";
+    let row = transaction
+        .query_one(query, &[&caller.account_id, &DEFAULT_PRIMARY_COLOR, &DEFAULT_TEXT_COLOR, &data]).await?;
+
+    transaction.commit().await?;
+
+    Ok(TicketTemplate { primary_color: row.get(0), text_color: row.get(1), has_logo: row.get(2) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // JPEG with an APP0 segment and a frame of the given type.
+    fn jpeg(marker: u8, precision: u8, height: u16, width: u16, components: u8) -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8, 0xff, 0xe0, 0x00, 0x10];
+        data.extend_from_slice(b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0");
+        data.extend_from_slice(&[0xff, marker, 0x00, 8 + 3 * components, precision]);
+        data.extend_from_slice(&height.to_be_bytes());
+        data.extend_from_slice(&width.to_be_bytes());
+        data.push(components);
+        for id in 1..=components {
+            data.extend_from_slice(&[id, 0x11, 0x00]);
+        }
+        data.extend_from_slice(&[0xff, 0xd9]);
+        data
+    }
+
+    #[test]
+    fn reads_baseline_and_progressive_frames() {
+        let logo = Logo::from_jpeg(jpeg(0xc0, 8, 120, 300, 3)).unwrap();
+        assert_eq!((logo.width, logo.height, logo.components), (300, 120, 3));
+
+        let logo = Logo::from_jpeg(jpeg(0xc2, 8, 64, 64, 1)).unwrap();
+        assert_eq!((logo.width, logo.height, logo.components), (64, 64, 1));
+    }
+
+    #[test]
+    fn rejects_frames_pdf_cannot_decode() {
+        // Lossless, arithmetic coded and 12-bit frames.
+        assert!(Logo::from_jpeg(jpeg(0xc3, 8, 64, 64, 3)).is_none());
+        assert!(Logo::from_jpeg(jpeg(0xc9, 8, 64, 64, 3)).is_none());
+        assert!(Logo::from_jpeg(jpeg(0xc1, 12, 64, 64, 3)).is_none());
+        assert!(Logo::from_jpeg(jpeg(0xc0, 8, 0, 64, 3)).is_none());
+        assert!(Logo::from_jpeg(jpeg(0xc0, 8, 64, 64, 2)).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_and_other_data() {
+        let data = jpeg(0xc0, 8, 120, 300, 3);
+        // Cut inside the frame header, inside the APP0 segment and after the SOI marker.
+        assert!(Logo::from_jpeg(data[..26].to_vec()).is_none());
+        assert!(Logo::from_jpeg(data[..10].to_vec()).is_none());
+        assert!(Logo::from_jpeg(data[..2].to_vec()).is_none());
+        assert!(Logo::from_jpeg(b"\x89PNG\r\n\x1a\n".to_vec()).is_none());
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        assert_eq!(parse_color("#ff0000"), Some((1.0, 0.0, 0.0)));
+        assert_eq!(parse_color("#FFFFFF"), Some((1.0, 1.0, 1.0)));
+
+        for invalid in ["ff0000", "#fff", "#ff00000", "#gg0000", "#+fffff", "#-fffff", "#ffé0", ""] {
+            assert_eq!(parse_color(invalid), None, "{}", invalid);
+        }
+    }
+}
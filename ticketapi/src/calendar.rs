@@ -0,0 +1,246 @@
+// iCalendar (RFC 5545) events for tickets, so holders can add the event to their calendar. Event
+// dates keep the UTC offset they were given with: the event is written in a VTIMEZONE for that
+// offset, so calendars show the event's local time next to the viewer's. Cancellations follow
+// iTIP (RFC 5546), which requires an ORGANIZER, here the address tickets are mailed from.
+
+use chrono::{ DateTime, FixedOffset, Utc };
+
+use crate::config;
+use crate::tickets::Ticket;
+
+// Longest content line in octets, longer lines are folded.
+const MAX_LINE: usize = 75;
+
+// Length of events whose ticket has no valid_until after the event date.
+const DEFAULT_DURATION_HOURS: i64 = 3;
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace(['\n', '\r'], "\\n")
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// Name of the VTIMEZONE of a UTC offset, e.g. UTC+02:00.
+fn tzid(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    format!("UTC{}{:02}:{:02}", sign, seconds.abs() / 3600, (seconds.abs() % 3600) / 60)
+}
+
+// Date-time property in UTC, or in the event's VTIMEZONE for other offsets.
+fn date_time(name: &str, time: DateTime<FixedOffset>) -> String {
+    if time.offset().local_minus_utc() == 0 {
+        format!("{}:{}", name, timestamp(time.with_timezone(&Utc)))
+    } else {
+        format!("{};TZID={}:{}", name, tzid(*time.offset()), time.format("%Y%m%dT%H%M%S"))
+    }
+}
+
+// VTIMEZONE of a fixed UTC offset, which has no daylight saving time.
+fn timezone(calendar: &mut String, offset: FixedOffset) {
+    let seconds = offset.local_minus_utc();
+    let utc_offset = format!(
+        "{}{:02}{:02}",
+        if seconds < 0 { '-' } else { '+' },
+        seconds.abs() / 3600,
+        (seconds.abs() % 3600) / 60
+    );
+
+    line(calendar, "BEGIN:VTIMEZONE");
+    line(calendar, &format!("TZID:{}", tzid(offset)));
+    line(calendar, "BEGIN:STANDARD");
+    line(calendar, "DTSTART:19700101T000000");
+    line(calendar, &format!("TZOFFSETFROM:{}", utc_offset));
+    line(calendar, &format!("TZOFFSETTO:{}", utc_offset));
+    line(calendar, &format!("TZNAME:{}", tzid(offset)));
+    line(calendar, "END:STANDARD");
+    line(calendar, "END:VTIMEZONE");
+}
+
+// ORGANIZER value of a mailbox such as "TicketAPI <tickets@example.com>".
+fn organizer(mailbox: &str) -> String {
+    match mailbox.rsplit_once('<') {
+        Some((name, address)) => {
+            let address = address.trim_end_matches('>').trim();
+            let name = name.trim().replace('"', "");
+            if name.is_empty() {
+                format!("ORGANIZER:mailto:{}", address)
+            } else {
+                format!("ORGANIZER;CN=\"{}\":mailto:{}", name, address)
+            }
+        }
+        None => format!("ORGANIZER:mailto:{}", mailbox.trim()),
+    }
+}
+
+// Appends a content line, folded into continuation lines that start with a space.
+fn line(calendar: &mut String, content: &str) {
+    let mut length = 0;
+    for c in content.chars() {
+        if length + c.len_utf8() > MAX_LINE {
+            calendar.push_str("\r\n ");
+            length = 1;
+        }
+        calendar.push(c);
+        length += c.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+// VCALENDAR with the ticket's event. A cancellation removes the event from calendars that added
+// it. Returns None if the ticket has no event date.
+pub fn event(ticket: &Ticket, cancelled: bool) -> Option<String> {
+    let start = DateTime::parse_from_rfc3339(ticket.event_date.as_deref()?).ok()?;
+    let end = ticket.valid_until
+        .as_deref()
+        .and_then(|valid_until| DateTime::parse_from_rfc3339(valid_until).ok())
+        .filter(|valid_until| *valid_until > start);
+    let ticket_id = ticket.id.unwrap_or_default();
+
+    let mut calendar = String::new();
+    line(&mut calendar, "BEGIN:VCALENDAR");
+    line(&mut calendar, "VERSION:2.0");
+    line(&mut calendar, "PRODID:-//TicketAPI//Tickets//EN");
+    line(&mut calendar, if cancelled { "METHOD:CANCEL" } else { "METHOD:PUBLISH" });
+    if start.offset().local_minus_utc() != 0 {
+        timezone(&mut calendar, *start.offset());
+    }
+    line(&mut calendar, "BEGIN:VEVENT");
+    line(&mut calendar, &format!("UID:ticket-{}@ticketapi", ticket_id));
+    line(&mut calendar, &format!("DTSTAMP:{}", timestamp(Utc::now())));
+    line(&mut calendar, &organizer(&config::get().mail_from));
+    line(&mut calendar, &date_time("DTSTART", start));
+    match end {
+        Some(end) => line(&mut calendar, &date_time("DTEND", end.with_timezone(start.offset()))),
+        None => line(&mut calendar, &format!("DURATION:PT{}H", DEFAULT_DURATION_HOURS)),
+    }
+    line(&mut calendar, &format!("SUMMARY:{}", escape(ticket.event_name.as_deref().unwrap_or("Event"))));
+    if let Some(location) = ticket.event_location.as_deref().filter(|location| !location.is_empty()) {
+        line(&mut calendar, &format!("LOCATION:{}", escape(location)));
+    }
+    line(&mut calendar, &format!("DESCRIPTION:{}", escape(&format!("Ticket #{}", ticket_id))));
+    if cancelled {
+        line(&mut calendar, "SEQUENCE:1");
+        line(&mut calendar, "STATUS:CANCELLED");
+    } else {
+        line(&mut calendar, "STATUS:CONFIRMED");
+    }
+    line(&mut calendar, "END:VEVENT");
+    line(&mut calendar, "END:VCALENDAR");
+
+    Some(calendar)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn folded(content: &str) -> String {
+        let mut calendar = String::new();
+        line(&mut calendar, content);
+        calendar
+    }
+
+    #[test]
+    fn short_lines_are_not_folded() {
+        assert_eq!(folded("VERSION:2.0"), "VERSION:2.0\r\n");
+
+        let longest = "X".repeat(MAX_LINE);
+        assert_eq!(folded(&longest), format!("{}\r\n", longest));
+    }
+
+    #[test]
+    fn long_lines_are_folded_at_75_octets() {
+        let content = format!("SUMMARY:{}", "a".repeat(200));
+        let calendar = folded(&content);
+
+        let lines: Vec<&str> = calendar.trim_end_matches("\r\n").split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE));
+        assert!(lines[1..].iter().all(|line| line.starts_with(' ')));
+        // Unfolding removes the line breaks and the space after them.
+        assert_eq!(calendar.replace("\r\n ", ""), format!("{}\r\n", content));
+    }
+
+    #[test]
+    fn multibyte_characters_are_not_split() {
+        let content = format!("LOCATION:{}", "ü".repeat(60));
+        let calendar = folded(&content);
+
+        for line in calendar.trim_end_matches("\r\n").split("\r\n") {
+            assert!(line.len() <= MAX_LINE);
+        }
+        assert_eq!(calendar.replace("\r\n ", ""), format!("{}\r\n", content));
+    }
+
+    fn ticket(event_date: &str, valid_until: Option<&str>) -> Ticket {
+        serde_json
+            ::from_value(
+                json!({
+                "id": 7,
+                "event_name": "Concert",
+                "event_location": "Hall",
+                "event_date": event_date,
+                "valid_until": valid_until,
+                "status": "Cancelled",
+            })
+            )
+            .unwrap()
+    }
+
+    fn lines(calendar: &str) -> Vec<String> {
+        calendar.replace("\r\n ", "").trim_end_matches("\r\n").split("\r\n").map(str::to_string).collect()
+    }
+
+    #[test]
+    fn cancelled_event_keeps_its_local_time() {
+        config::load_for_tests();
+        let calendar = event(&ticket("2026-10-01T19:00:00+02:00", Some("2026-10-01T21:30:00Z")), true).unwrap();
+        let lines = lines(&calendar);
+
+        for expected in [
+            "METHOD:CANCEL",
+            "TZID:UTC+02:00",
+            "TZOFFSETTO:+0200",
+            "ORGANIZER;CN=\"TicketAPI\":mailto:tickets@localhost",
+            "DTSTART;TZID=UTC+02:00:20261001T190000",
+            "DTEND;TZID=UTC+02:00:20261001T233000",
+            "SEQUENCE:1",
+            "STATUS:CANCELLED",
+        ] {
+            assert!(lines.iter().any(|line| line == expected), "missing {}", expected);
+        }
+        assert!(lines.iter().position(|line| line == "END:VTIMEZONE") < lines.iter().position(|line| line == "BEGIN:VEVENT"));
+    }
+
+    #[test]
+    fn utc_events_have_a_default_duration() {
+        config::load_for_tests();
+        let calendar = event(&ticket("2026-10-01T17:00:00Z", None), false).unwrap();
+        let lines = lines(&calendar);
+
+        assert!(lines.contains(&"DTSTART:20261001T170000Z".to_string()));
+        assert!(lines.contains(&format!("DURATION:PT{}H", DEFAULT_DURATION_HOURS)));
+        assert!(!lines.iter().any(|line| line.starts_with("BEGIN:VTIMEZONE")));
+    }
+
+    #[test]
+    fn negative_offsets_are_named() {
+        assert_eq!(tzid(FixedOffset::west_opt(5 * 3600 + 1800).unwrap()), "UTC-05:30");
+        assert_eq!(organizer("tickets@example.com"), "ORGANIZER:mailto:tickets@example.com");
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        assert_eq!(escape("Rock, Pop; Jazz\\Blues\r\nDoors 19:00\n"), r"Rock\, Pop\; Jazz\\Blues\nDoors 19:00\n");
+    }
+}
@@ -0,0 +1,91 @@
+// Signed ticket codes, the content of a ticket's QR code: "<ticket id>.<version>.<signature>".
+// The signature is a truncated HMAC-SHA256 with ticket_code_secret, so forged codes are rejected
+// without a database lookup. Transfers bump the version, which invalidates codes issued before.
+// The same secret derives the per-ticket tokens Wallet uses to fetch updated passes.
+
+use hmac::{ Hmac, Mac };
+use sha2::Sha256;
+
+use crate::config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Bytes of the HMAC kept in the code, to keep QR codes small.
+const SIGNATURE_LEN: usize = 16;
+
+fn hmac(message: &str) -> HmacSha256 {
+    let secret = &config::get().ticket_code_secret;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+fn mac(ticket_id: i64, version: i32) -> HmacSha256 {
+    hmac(&format!("{}.{}", ticket_id, version))
+}
+
+pub fn sign(ticket_id: i64, version: i32) -> String {
+    let signature = mac(ticket_id, version).finalize().into_bytes();
+    format!("{}.{}.{}", ticket_id, version, hex::encode(&signature[..SIGNATURE_LEN]))
+}
+
+// Returns ticket id and version of a code with a valid signature.
+pub fn parse(code: &str) -> Option<(i64, i32)> {
+    let mut parts = code.trim().splitn(3, '.');
+    let ticket_id: i64 = parts.next()?.parse().ok()?;
+    let version: i32 = parts.next()?.parse().ok()?;
+    let signature = hex::decode(parts.next()?).ok()?;
+
+    if signature.len() != SIGNATURE_LEN {
+        return None;
+    }
+    mac(ticket_id, version).verify_truncated_left(&signature).ok()?;
+
+    Some((ticket_id, version))
+}
+
+// Authentication token of a Wallet pass, which is issued per ticket and code version.
+pub fn pass_token(ticket_id: i64, version: i32) -> String {
+    hex::encode(hmac(&format!("pass.{}.{}", ticket_id, version)).finalize().into_bytes())
+}
+
+pub fn verify_pass_token(ticket_id: i64, version: i32, token: &str) -> bool {
+    let Ok(token) = hex::decode(token) else {
+        return false;
+    };
+
+    hmac(&format!("pass.{}.{}", ticket_id, version)).verify_slice(&token).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_signed_codes() {
+        config::load_for_tests();
+
+        let code = sign(42, 3);
+        assert!(code.starts_with("42.3."));
+        assert_eq!(parse(&code), Some((42, 3)));
+        // Codes are read from scanners, which may add whitespace.
+        assert_eq!(parse(&format!(" {}\n", code)), Some((42, 3)));
+    }
+
+    #[test]
+    fn rejects_forged_codes() {
+        config::load_for_tests();
+
+        let code = sign(42, 3);
+        let signature = code.rsplit('.').next().unwrap();
+        // Another ticket or version with the same signature.
+        assert_eq!(parse(&format!("43.3.{}", signature)), None);
+        assert_eq!(parse(&format!("42.4.{}", signature)), None);
+        // Truncated, extended or malformed signatures.
+        assert_eq!(parse(&code[..code.len() - 2]), None);
+        assert_eq!(parse(&format!("{}00", code)), None);
+        assert_eq!(parse(&format!("42.3.{}", "zz".repeat(SIGNATURE_LEN))), None);
+        assert_eq!(parse("42.3"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,466 @@
+// Server settings. Everything is read once at startup through Rocket's figment: Rocket.toml (the
+// profile is selected with ROCKET_PROFILE), the environment variables used before Rocket.toml
+// existed (SUPABASE_URI, KEY_PEPPER, SMTP_HOST, ...) and ROCKET_<SETTING> variables, in increasing
+// order of precedence. The settings are validated before the server starts, so a missing secret
+// or a malformed value stops the process with a message naming the setting instead of failing in
+// the first request that needs it.
+
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process;
+use std::sync::OnceLock;
+
+use lettre::message::Mailbox;
+use rocket::figment::providers::{ Env, Format, Serialized, Toml };
+use rocket::figment::{ Figment, Profile };
+use rocket::serde::{ Deserialize, Deserializer };
+
+use crate::keys;
+
+// Variables read before settings moved to Rocket.toml. Most map to the lowercased name.
+const LEGACY_VARIABLES: [&str; 32] = [
+    "SUPABASE_URI",
+    "DATABASE_REQUEST_ROLE",
+    "TICKET_CODE_SECRET",
+    "KEY_PEPPER",
+    "KEY_ROTATION_GRACE_PERIOD",
+    "SUPABASE_JWT_SECRET",
+    "SUPABASE_JWKS_FILE",
+    "SUPABASE_JWT_AUDIENCE",
+    "SUPABASE_JWT_ISSUER",
+    "USAGE_FLUSH_INTERVAL",
+    "TICKET_EXPIRY_INTERVAL",
+    "TICKET_EXPIRY_GRACE",
+    "TICKET_EXPIRY_BATCH",
+    "JOB_WORKERS",
+    "JOB_POLL_INTERVAL",
+    "MAIL_TRANSPORT",
+    "MAIL_FROM",
+    "MAIL_DIR",
+    "MAIL_TEMPLATE_DIR",
+    "SMTP_HOST",
+    "SMTP_PORT",
+    "SMTP_USERNAME",
+    "SMTP_PASSWORD",
+    "SMTP_TLS",
+    "PASS_TYPE_IDENTIFIER",
+    "PASS_TEAM_IDENTIFIER",
+    "PASS_CERTIFICATE",
+    "PASS_CERTIFICATE_PASSWORD",
+    "PASS_WWDR_CERTIFICATE",
+    "PASS_ORGANIZATION_NAME",
+    "PASS_WEB_SERVICE_URL",
+    "MANIFEST_SIGNING_KEY",
+];
+
+// Settings key of a legacy variable. RATE_LIMIT_<PLAN> and QUOTA_<PLAN> set the plan's limits.
+fn legacy_key(variable: &str) -> Option<String> {
+    if let Some(plan) = variable.strip_prefix("RATE_LIMIT_") {
+        return Some(format!("plans.{}.requests_per_minute", plan.to_lowercase()));
+    }
+    if let Some(plan) = variable.strip_prefix("QUOTA_") {
+        return Some(format!("plans.{}.monthly_quota", plan.to_lowercase()));
+    }
+
+    match variable {
+        "SUPABASE_URI" => Some("database_url".to_string()),
+        _ if LEGACY_VARIABLES.contains(&variable) => Some(variable.to_lowercase()),
+        _ => None,
+    }
+}
+
+fn default_pool_size() -> usize {
+    16
+}
+
+fn default_request_role() -> String {
+    "authenticated".to_string()
+}
+
+fn default_grace_period() -> i64 {
+    24 * 60 * 60
+}
+
+fn default_audience() -> String {
+    "authenticated".to_string()
+}
+
+fn default_flush_interval() -> u64 {
+    10
+}
+
+fn default_expiry_interval() -> u64 {
+    300
+}
+
+fn default_expiry_grace() -> i64 {
+    6 * 60 * 60
+}
+
+fn default_expiry_batch() -> i64 {
+    500
+}
+
+fn default_job_workers() -> usize {
+    2
+}
+
+fn default_poll_interval() -> u64 {
+    1
+}
+
+fn default_ready_timeout() -> u64 {
+    2
+}
+
+fn default_mail_from() -> String {
+    "TicketAPI <tickets@localhost>".to_string()
+}
+
+fn default_organization_name() -> String {
+    "TicketAPI".to_string()
+}
+
+// Settings read from the environment exactly as given. Env parses values, so a pepper of 00123
+// would read as 123 and a secret of 1e5 as 100000.
+const RAW_SETTINGS: [&str; 7] = [
+    "ticket_code_secret",
+    "key_pepper",
+    "supabase_jwt_secret",
+    "metrics_token",
+    "smtp_username",
+    "smtp_password",
+    "pass_certificate_password",
+];
+
+// Values of RAW_SETTINGS from the legacy variables and ROCKET_<SETTING> variables of the given
+// environment, the latter taking precedence as in figment().
+fn raw_settings(variables: impl IntoIterator<Item = (OsString, OsString)>) -> HashMap<String, String> {
+    let variables: Vec<(String, String)> = variables
+        .into_iter()
+        .filter_map(|(variable, value)| Some((variable.to_string_lossy().trim().to_ascii_uppercase(), value.into_string().ok()?)))
+        .collect();
+
+    let mut settings = HashMap::new();
+    for prefixed in [false, true] {
+        for (variable, value) in &variables {
+            let key = match variable.strip_prefix("ROCKET_") {
+                Some(setting) if prefixed => Some(setting.to_lowercase()),
+                None if !prefixed => legacy_key(variable),
+                _ => None,
+            };
+            if let Some(key) = key.filter(|key| RAW_SETTINGS.contains(&key.as_str())) {
+                settings.insert(key, value.clone());
+            }
+        }
+    }
+    settings
+}
+
+// Secrets in Rocket.toml may be written as integers. TOML integers have no leading zeros, so they
+// convert back to the same text; other numbers are rejected.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Text {
+    String(String),
+    Unsigned(u64),
+    Signed(i64),
+}
+
+impl Text {
+    fn into_string(self) -> String {
+        match self {
+            Text::String(value) => value,
+            Text::Unsigned(value) => value.to_string(),
+            Text::Signed(value) => value.to_string(),
+        }
+    }
+}
+
+fn text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Text::deserialize(deserializer).map(Text::into_string)
+}
+
+fn optional_text<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    Option::<Text>::deserialize(deserializer).map(|value| value.map(Text::into_string))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    File,
+    #[default]
+    Stdout,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Starttls,
+    Tls,
+    None,
+}
+
+// Requests per month, or "unlimited".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum QuotaSetting {
+    Requests(i64),
+    Unlimited(String),
+}
+
+// Limits of a plan. Limits that are not set keep the built-in plan's default, or the default
+// plan's for plans that are not built in.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PlanSettings {
+    pub requests_per_minute: Option<u32>,
+    pub monthly_quota: Option<QuotaSetting>,
+}
+
+#[derive(Deserialize)]
+pub struct Settings {
+    // Postgres connection string. Its sslmode parameter selects TLS, see tls.rs.
+    pub database_url: String,
+    // CA bundle (PEM) for sslmode=verify-full, instead of sslrootcert.
+    pub database_ca_file: Option<PathBuf>,
+    // Client certificate and key (PEM), instead of sslcert and sslkey.
+    pub database_client_certificate: Option<PathBuf>,
+    pub database_client_key: Option<PathBuf>,
+    // Most connections open at the same time; further requests wait for a free one.
+    #[serde(default = "default_pool_size")]
+    pub database_pool_size: usize,
+    // Role queries on behalf of a caller run as.
+    #[serde(default = "default_request_role")]
+    pub database_request_role: String,
+
+    #[serde(deserialize_with = "text")]
+    pub ticket_code_secret: String,
+    #[serde(deserialize_with = "text")]
+    pub key_pepper: String,
+    // Seconds a rotated key keeps working, unless the rotation request sets it.
+    #[serde(default = "default_grace_period")]
+    pub key_rotation_grace_period: i64,
+
+    #[serde(default, deserialize_with = "optional_text")]
+    pub supabase_jwt_secret: Option<String>,
+    pub supabase_jwks_file: Option<PathBuf>,
+    #[serde(default = "default_audience")]
+    pub supabase_jwt_audience: String,
+    pub supabase_jwt_issuer: Option<String>,
+
+    // Overrides of the built-in plans and additional plans, by name.
+    #[serde(default)]
+    pub plans: HashMap<String, PlanSettings>,
+
+    // Seconds between writes of the usage counters.
+    #[serde(default = "default_flush_interval")]
+    pub usage_flush_interval: u64,
+
+    // Seconds between two expiry runs; 0 disables ticket expiry.
+    #[serde(default = "default_expiry_interval")]
+    pub ticket_expiry_interval: u64,
+    // Seconds after the event date before a ticket expires.
+    #[serde(default = "default_expiry_grace")]
+    pub ticket_expiry_grace: i64,
+    // Tickets updated per statement.
+    #[serde(default = "default_expiry_batch")]
+    pub ticket_expiry_batch: i64,
+
+    // Seconds GET /ready waits for the database before reporting the instance as not ready.
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: u64,
+
+    // Bearer token GET /metrics requires; without one the metrics are public.
+    #[serde(default, deserialize_with = "optional_text")]
+    pub metrics_token: Option<String>,
+
+    // Job workers per instance; 0 disables them.
+    #[serde(default = "default_job_workers")]
+    pub job_workers: usize,
+    // Seconds an idle worker waits before looking for due jobs again.
+    #[serde(default = "default_poll_interval")]
+    pub job_poll_interval: u64,
+
+    #[serde(default)]
+    pub mail_transport: MailTransport,
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    pub mail_dir: Option<PathBuf>,
+    pub mail_template_dir: Option<PathBuf>,
+    pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub smtp_username: Option<String>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub smtp_password: Option<String>,
+    #[serde(default)]
+    pub smtp_tls: SmtpTls,
+
+    // Wallet passes are enabled by setting pass_type_identifier.
+    pub pass_type_identifier: Option<String>,
+    pub pass_team_identifier: Option<String>,
+    pub pass_certificate: Option<PathBuf>,
+    #[serde(default, deserialize_with = "optional_text")]
+    pub pass_certificate_password: Option<String>,
+    pub pass_wwdr_certificate: Option<PathBuf>,
+    #[serde(default = "default_organization_name")]
+    pub pass_organization_name: String,
+    pub pass_web_service_url: Option<String>,
+
+    // Offline scanning is enabled by setting manifest_signing_key.
+    pub manifest_signing_key: Option<PathBuf>,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// Settings loaded at startup.
+pub fn get() -> &'static Settings {
+    SETTINGS.get().expect("settings are loaded at startup")
+}
+
+// Settings for unit tests of code that reads them through get().
+#[cfg(test)]
+pub fn load_for_tests() -> &'static Settings {
+    SETTINGS.get_or_init(|| {
+        Figment::from(Serialized::defaults(HashMap::from([
+            ("database_url", "postgres://localhost/test"),
+            ("ticket_code_secret", "test secret"),
+            ("key_pepper", "test pepper"),
+        ])))
+            .extract()
+            .expect("test settings are valid")
+    })
+}
+
+// Rocket's own settings (address, port, limits, tls, secret_key, ...) and ours. Unlike Rocket's
+// default, the server listens on all interfaces unless address is set.
+pub fn figment() -> Figment {
+    Figment::from(rocket::Config::default())
+        .merge(Serialized::default("address", "0.0.0.0"))
+        .merge(Toml::file(Env::var_or("ROCKET_CONFIG", "Rocket.toml")).nested())
+        .merge(
+            Env::raw()
+                .filter_map(|variable| legacy_key(&variable.as_str().to_ascii_uppercase()).map(Into::into))
+                .ignore(&RAW_SETTINGS)
+                .global()
+        )
+        .merge(Env::prefixed("ROCKET_").ignore(&["PROFILE"]).ignore(&RAW_SETTINGS).global())
+        .merge(Serialized::globals(raw_settings(env::vars_os())))
+        .select(Profile::from_env_or("ROCKET_PROFILE", rocket::Config::DEFAULT_PROFILE))
+}
+
+// Prints a configuration error and stops the process.
+pub fn fail(message: String) -> ! {
+    eprintln!("Configuration error: {}", message);
+    process::exit(1);
+}
+
+// Reads and validates the settings. Stops the process with every problem found if they are
+// invalid.
+pub fn load(figment: &Figment) -> &'static Settings {
+    let mut errors = Vec::new();
+
+    if let Err(e) = figment.extract::<rocket::Config>() {
+        errors.extend(e.into_iter().map(|e| e.to_string()));
+    }
+
+    match figment.extract::<Settings>() {
+        Ok(settings) => {
+            errors.extend(settings.validate());
+            if errors.is_empty() {
+                return SETTINGS.get_or_init(|| settings);
+            }
+        }
+        Err(e) => errors.extend(e.into_iter().map(|e| e.to_string())),
+    }
+
+    for error in &errors {
+        eprintln!("Configuration error: {}", error);
+    }
+    process::exit(1);
+}
+
+fn is_role_name(role: &str) -> bool {
+    !role.is_empty() && role.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Settings {
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut check = |valid: bool, message: String| {
+            if !valid {
+                errors.push(message);
+            }
+        };
+
+        check(self.database_pool_size > 0, "database_pool_size must be at least 1".to_string());
+        check(is_role_name(&self.database_request_role), "database_request_role must be a plain role name".to_string());
+
+        check(!self.ticket_code_secret.is_empty(), "ticket_code_secret must not be empty".to_string());
+        check(!self.key_pepper.is_empty(), "key_pepper must not be empty".to_string());
+        check(
+            (0..=keys::MAX_GRACE_PERIOD).contains(&self.key_rotation_grace_period),
+            "key_rotation_grace_period must be between 0 and 30 days in seconds".to_string()
+        );
+
+        check(self.usage_flush_interval > 0, "usage_flush_interval must be at least 1 second".to_string());
+        check(self.ticket_expiry_grace >= 0, "ticket_expiry_grace must not be negative".to_string());
+        check(self.ticket_expiry_batch > 0, "ticket_expiry_batch must be at least 1".to_string());
+        check(self.job_poll_interval > 0, "job_poll_interval must be at least 1 second".to_string());
+        check(self.ready_timeout > 0, "ready_timeout must be at least 1 second".to_string());
+
+        check(self.mail_from.parse::<Mailbox>().is_ok(), "mail_from must be a valid address".to_string());
+        check(
+            self.mail_transport != MailTransport::Smtp || self.smtp_host.is_some(),
+            "smtp_host must be set for mail_transport = \"smtp\"".to_string()
+        );
+        check(
+            self.mail_transport != MailTransport::File || self.mail_dir.is_some(),
+            "mail_dir must be set for mail_transport = \"file\"".to_string()
+        );
+
+        if self.pass_type_identifier.is_some() {
+            check(self.pass_team_identifier.is_some(), "pass_team_identifier must be set for Wallet passes".to_string());
+            check(self.pass_certificate.is_some(), "pass_certificate must be set for Wallet passes".to_string());
+            check(self.pass_wwdr_certificate.is_some(), "pass_wwdr_certificate must be set for Wallet passes".to_string());
+        }
+
+        for (name, plan) in &self.plans {
+            check(plan.requests_per_minute != Some(0), format!("plans.{}.requests_per_minute must be at least 1", name));
+            let quota_valid = match &plan.monthly_quota {
+                Some(QuotaSetting::Requests(requests)) => *requests >= 0,
+                Some(QuotaSetting::Unlimited(value)) => value == "unlimited",
+                None => true,
+            };
+            check(quota_valid, format!("plans.{}.monthly_quota must be a number of requests or \"unlimited\"", name));
+        }
+
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_keep_their_text() {
+        let variables = [
+            ("KEY_PEPPER", "00123"),
+            ("ROCKET_TICKET_CODE_SECRET", "1e5"),
+            ("TICKET_CODE_SECRET", "legacy"),
+            ("ROCKET_PORT", "8000"),
+        ];
+        let settings = raw_settings(variables.map(|(variable, value)| (variable.into(), value.into())));
+        assert_eq!(settings.len(), 2);
+        let figment = Figment::from(Serialized::globals(settings));
+
+        assert_eq!(figment.extract_inner::<String>("key_pepper").unwrap(), "00123");
+        // ROCKET_<SETTING> takes precedence over the legacy variable.
+        assert_eq!(figment.extract_inner::<String>("ticket_code_secret").unwrap(), "1e5");
+    }
+}
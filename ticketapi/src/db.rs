@@ -0,0 +1,167 @@
+// Database connections. Queries made on behalf of a caller run in a transaction that switches to
+// database_request_role (default "authenticated") and sets the caller's JWT claims, so the row
+// level security policies apply to them as a second line of defense.
+//
+// Connections are pooled: at most database_pool_size are open at a time, idle ones are reused and
+// a request that finds none free waits for one. A transaction left open when its connection goes
+// back to the pool is rolled back before the connection's next query. Connections use TLS unless
+// the connection string sets sslmode=disable, see tls.rs.
+
+use std::ops::{ Deref, DerefMut };
+use std::sync::{ Mutex, OnceLock };
+use std::time::Instant;
+
+use rocket::serde::Serialize;
+use tokio::io::{ AsyncRead, AsyncWrite };
+use tokio::sync::{ Semaphore, SemaphorePermit };
+use tokio_postgres::{ Client, Connection as PostgresConnection, NoTls, Error, Transaction };
+use tokio_postgres_rustls::MakeRustlsConnect;
+use uuid::Uuid;
+
+use crate::config::{ self, Settings };
+use crate::{ metrics, tls };
+
+struct Pool {
+    config: tokio_postgres::Config,
+    tls: Option<MakeRustlsConnect>,
+    idle: Mutex<Vec<Client>>,
+    permits: Semaphore,
+    size: usize,
+}
+
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+// Sets up the pool. Connections are opened when they are first needed.
+pub fn init(settings: &Settings) -> Result<(), String> {
+    let target = tls::target(settings)?;
+
+    let pool = Pool {
+        config: target.config,
+        tls: target.tls,
+        idle: Mutex::new(Vec::new()),
+        permits: Semaphore::new(settings.database_pool_size),
+        size: settings.database_pool_size,
+    };
+    if POOL.set(pool).is_err() {
+        return Err("The connection pool is already set up".to_string());
+    }
+
+    Ok(())
+}
+
+fn pool() -> &'static Pool {
+    POOL.get().expect("the connection pool is set up at startup")
+}
+
+#[derive(Serialize)]
+pub struct PoolStatus {
+    pub size: usize,
+    // Connections handed out to requests and background tasks.
+    pub in_use: usize,
+    // Open connections waiting to be reused.
+    pub idle: usize,
+}
+
+impl PoolStatus {
+    // Whether a request would have to wait for a connection.
+    pub fn exhausted(&self) -> bool {
+        self.in_use >= self.size
+    }
+}
+
+pub fn pool_status() -> PoolStatus {
+    let pool = pool();
+
+    PoolStatus {
+        size: pool.size,
+        in_use: pool.size - pool.permits.available_permits(),
+        idle: pool.idle.lock().unwrap().len(),
+    }
+}
+
+// Pooled connection, returned to the pool when dropped.
+pub struct Connection {
+    client: Option<Client>,
+    _permit: SemaphorePermit<'static>,
+    checked_out: Instant,
+}
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("connection is open until dropped")
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("connection is open until dropped")
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics::observe_connection_hold(self.checked_out.elapsed());
+        if let Some(client) = self.client.take().filter(|client| !client.is_closed()) {
+            pool().idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+fn spawn<S, T>((client, connection): (Client, PostgresConnection<S, T>)) -> Client
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("connection error: {}", e);
+        }
+    });
+
+    client
+}
+
+async fn open(pool: &Pool) -> Result<Client, Error> {
+    match &pool.tls {
+        Some(tls) => Ok(spawn(pool.config.connect(tls.clone()).await?)),
+        None => Ok(spawn(pool.config.connect(NoTls).await?)),
+    }
+}
+
+pub async fn connect() -> Result<Connection, Error> {
+    let pool = pool();
+    let started = Instant::now();
+    let permit = pool.permits.acquire().await.expect("the pool is never closed");
+
+    let idle = loop {
+        match pool.idle.lock().unwrap().pop() {
+            Some(client) if client.is_closed() => continue,
+            idle => break idle,
+        }
+    };
+    let client = match idle {
+        Some(client) => client,
+        None => open(pool).await?,
+    };
+
+    metrics::observe_connection_wait(started.elapsed());
+
+    Ok(Connection { client: Some(client), _permit: permit, checked_out: Instant::now() })
+}
+
+// Starts a transaction in which auth.uid() is the given user.
+pub async fn begin_as(client: &mut Client, user_id: Uuid) -> Result<Transaction<'_>, Error> {
+    let role = &config::get().database_request_role;
+    let claims = serde_json::json!({ "sub": user_id, "role": role }).to_string();
+
+    let transaction = client.transaction().await?;
+    transaction.batch_execute(&format!("SET LOCAL ROLE \"{}\"", role)).await?;
+    transaction.execute(
+        "SELECT set_config('request.jwt.claims', $1, true), set_config('request.jwt.claim.sub', $2, true)",
+        &[&claims, &user_id.to_string()]
+    ).await?;
+
+    Ok(transaction)
+}
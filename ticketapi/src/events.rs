@@ -0,0 +1,59 @@
+// Per-event policies of an account. Events are identified by the event_name of their tickets;
+// events without a stored policy use the defaults.
+
+use rocket::serde::{ Deserialize, Serialize };
+use tokio_postgres::{ Error, GenericClient };
+use uuid::Uuid;
+
+use crate::{ db, Caller };
+
+#[derive(Deserialize, Serialize)]
+pub struct EventPolicy {
+    #[serde(skip_deserializing)]
+    pub event_name: String,
+    pub transfers_enabled: bool,
+}
+
+impl EventPolicy {
+    fn default_for(event_name: &str) -> EventPolicy {
+        EventPolicy { event_name: event_name.to_string(), transfers_enabled: true }
+    }
+}
+
+// Policy lookup inside an existing transaction.
+pub async fn policy_of(client: &impl GenericClient, owner_id: Uuid, event_name: &str) -> Result<EventPolicy, Error> {
+    let row = client.query_opt(
+        "SELECT transfers_enabled FROM event_policies WHERE owner_id = $1 AND event_name = $2",
+        &[&owner_id, &event_name]
+    ).await?;
+
+    Ok(match row {
+        Some(row) => EventPolicy { event_name: event_name.to_string(), transfers_enabled: row.get(0) },
+        None => EventPolicy::default_for(event_name),
+    })
+}
+
+pub async fn get_policy(caller: &Caller, event_name: &str) -> Result<EventPolicy, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let policy = policy_of(&transaction, caller.account_id, event_name).await?;
+
+    transaction.commit().await?;
+
+    Ok(policy)
+}
+
+pub async fn set_policy(caller: &Caller, event_name: &str, policy: &EventPolicy) -> Result<EventPolicy, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query =
+        "INSERT INTO event_policies (owner_id, event_name, transfers_enabled) VALUES ($1, $2, $3) ON CONFLICT (owner_id, event_name) DO UPDATE SET transfers_enabled = EXCLUDED.transfers_enabled, updated_at = NOW() RETURNING transfers_enabled";
+    let row = transaction
+        .query_one(query, &[&caller.account_id, &event_name, &policy.transfers_enabled]).await?;
+
+    transaction.commit().await?;
+
+    Ok(EventPolicy { event_name: event_name.to_string(), transfers_enabled: row.get(0) })
+}
@@ -0,0 +1,57 @@
+// Expiry of tickets for past events. A background task periodically moves "Active" tickets whose
+// valid_until, or event_date for tickets without one, lies more than a grace period in the past to
+// "Expired", in batches, and adds an "expired" entry to each ticket's history. Only active tickets
+// are touched, so runs can overlap or repeat (e.g. on several instances) without changing a ticket
+// twice. Expired tickets with Wallet passes get a push job, so devices void the passes.
+
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio_postgres::Error;
+
+use crate::{ config, db, jobs, passes };
+
+// Spawns the expiry task. Must be called from within the tokio runtime.
+pub fn start() {
+    let settings = config::get();
+    if settings.ticket_expiry_interval == 0 {
+        return;
+    }
+
+    tokio::spawn(
+        run(Duration::from_secs(settings.ticket_expiry_interval), settings.ticket_expiry_grace, settings.ticket_expiry_batch)
+    );
+}
+
+async fn run(interval: Duration, grace: i64, batch: i64) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        match expire(grace, batch).await {
+            Ok(0) => {}
+            Ok(expired) => eprintln!("ticket expiry: {} tickets expired", expired),
+            Err(e) => eprintln!("ticket expiry error: {}", e),
+        }
+    }
+}
+
+// Expires tickets batch by batch until none are left. Returns the number of expired tickets.
+async fn expire(grace: i64, batch: i64) -> Result<u64, Error> {
+    let client = db::connect().await?;
+    let cutoff = Utc::now() - chrono::Duration::seconds(grace);
+
+    // Rows locked by a concurrent run are skipped and left to it.
+    let query =
+        "WITH due AS (SELECT id FROM tickets WHERE status = 'Active' AND COALESCE(valid_until, event_date) < $1 ORDER BY id LIMIT $2 FOR UPDATE SKIP LOCKED), expired AS (UPDATE tickets SET status = 'Expired', updated_at = NOW() FROM due WHERE tickets.id = due.id RETURNING tickets.id, tickets.owner_id, tickets.event_date, tickets.valid_until), logged AS (INSERT INTO ticket_history (ticket_id, owner_id, action, details) SELECT id, owner_id, 'expired', jsonb_build_object('event_date', event_date, 'valid_until', valid_until, 'grace_period', $3::bigint) FROM expired), pushed AS (INSERT INTO jobs (owner_id, kind, payload, max_attempts) SELECT owner_id, $4, jsonb_build_object('ticket_id', id), $5 FROM expired WHERE EXISTS (SELECT 1 FROM pass_registrations r WHERE r.ticket_id = expired.id)) SELECT id FROM expired";
+
+    let mut total = 0;
+    loop {
+        let rows = client.query(query, &[&cutoff, &batch, &grace, &passes::PUSH_JOB, &jobs::DEFAULT_MAX_ATTEMPTS]).await?;
+        total += rows.len() as u64;
+
+        if (rows.len() as i64) < batch {
+            return Ok(total);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Probes for the orchestrator. GET /health only tells that the process serves requests. GET /ready
+// tells whether it can do its work: the database answers within ready_timeout, its schema has the
+// migrations this build needs and the connection pool has a free connection. An instance that is
+// not ready answers 503 and is taken out of the load balancer until it is.
+
+use std::time::{ Duration, Instant };
+
+use rocket::serde::Serialize;
+use tokio_postgres::Error;
+
+use crate::config;
+use crate::db::{ self, PoolStatus };
+
+// Latest migration in docs/setup/migrations the code relies on.
+pub const SCHEMA_VERSION: i32 = 27;
+
+#[derive(Serialize)]
+pub struct Health {
+    pub status: &'static str,
+    pub version: &'static str,
+}
+
+pub fn health() -> Health {
+    Health { status: "ok", version: env!("CARGO_PKG_VERSION") }
+}
+
+#[derive(Serialize)]
+pub struct DatabaseCheck {
+    // ok, timeout, error, or skipped while the pool is exhausted
+    pub status: &'static str,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct MigrationCheck {
+    pub required: i32,
+    // None if the database could not be asked.
+    pub applied: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub database: DatabaseCheck,
+    pub migrations: MigrationCheck,
+    pub pool: PoolStatus,
+}
+
+async fn applied_migration() -> Result<i32, Error> {
+    let client = db::connect().await?;
+    let row = client.query_one("SELECT COALESCE(MAX(version), 0) FROM public.schema_migrations", &[]).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn readiness() -> Readiness {
+    let pool = db::pool_status();
+
+    let (database, applied) = if pool.exhausted() {
+        (DatabaseCheck { status: "skipped", latency_ms: None }, None)
+    } else {
+        let started = Instant::now();
+        let timeout = Duration::from_secs(config::get().ready_timeout);
+        match tokio::time::timeout(timeout, applied_migration()).await {
+            Ok(Ok(version)) => {
+                let latency_ms = started.elapsed().as_millis() as u64;
+                (DatabaseCheck { status: "ok", latency_ms: Some(latency_ms) }, Some(version))
+            }
+            Ok(Err(e)) => {
+                eprintln!("readiness check failed: {}", e);
+                (DatabaseCheck { status: "error", latency_ms: None }, None)
+            }
+            Err(_) => (DatabaseCheck { status: "timeout", latency_ms: None }, None),
+        }
+    };
+
+    let ready = database.status == "ok" && applied.is_some_and(|version| version >= SCHEMA_VERSION);
+
+    Readiness {
+        ready,
+        database,
+        migrations: MigrationCheck { required: SCHEMA_VERSION, applied },
+        pool,
+    }
+}
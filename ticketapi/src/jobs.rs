@@ -0,0 +1,231 @@
+// Durable background jobs. Work that should not run inside a request handler is stored in the
+// jobs table, usually in the transaction that makes the change it belongs to, and performed by
+// workers inside the binary. Workers claim due jobs with FOR UPDATE SKIP LOCKED, so any number of
+// instances can share the queue. Failed jobs are retried with exponential backoff and marked
+// dead after their last attempt; jobs of a worker that died are picked up again after a timeout.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{ DateTime, Utc };
+use rocket::serde::Serialize;
+use serde_json::Value;
+use tokio_postgres::{ Error, GenericClient, Row };
+use uuid::Uuid;
+
+use crate::mail::{ self, Mailer };
+use crate::passes::{ self, Pusher };
+use crate::{ config, db, metrics, Caller };
+
+// Attempts before a job is dead-lettered.
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+// Delay before the first retry, doubled for every further attempt up to MAX_BACKOFF.
+const BASE_BACKOFF: i64 = 10;
+const MAX_BACKOFF: i64 = 60 * 60;
+// Seconds after which a running job is considered abandoned by its worker.
+const LOCK_TIMEOUT: i64 = 15 * 60;
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    pub id: i64,
+    pub kind: String,
+    // queued, running, succeeded or dead
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: String,
+    pub last_error: Option<String>,
+    pub result: Option<Value>,
+    pub created_at: String,
+    pub finished_at: Option<String>,
+}
+
+const JOB_COLUMNS: &str =
+    "id, kind, status, attempts, max_attempts, run_at, last_error, result, created_at, finished_at";
+
+impl JobStatus {
+    fn from_row(row: &Row) -> JobStatus {
+        let run_at: DateTime<Utc> = row.get(5);
+        let created_at: DateTime<Utc> = row.get(8);
+        let finished_at: Option<DateTime<Utc>> = row.get(9);
+
+        JobStatus {
+            id: row.get(0),
+            kind: row.get(1),
+            status: row.get(2),
+            attempts: row.get(3),
+            max_attempts: row.get(4),
+            run_at: run_at.to_rfc3339(),
+            last_error: row.get(6),
+            result: row.get(7),
+            created_at: created_at.to_rfc3339(),
+            finished_at: finished_at.map(|finished_at| finished_at.to_rfc3339()),
+        }
+    }
+}
+
+// Adds a job for an account. Runs in the caller's transaction, so the job only exists if the
+// change it belongs to is committed.
+pub async fn enqueue(client: &impl GenericClient, owner_id: Uuid, kind: &str, payload: &Value) -> Result<i64, Error> {
+    let row = client.query_one(
+        "INSERT INTO jobs (owner_id, kind, payload, max_attempts) VALUES ($1, $2, $3, $4) RETURNING id",
+        &[&owner_id, &kind, &payload, &DEFAULT_MAX_ATTEMPTS]
+    ).await?;
+
+    Ok(row.get(0))
+}
+
+// Status of a job of the caller's account.
+pub async fn get_job(caller: &Caller, job_id: i64) -> Result<Option<JobStatus>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query = format!("SELECT {} FROM jobs WHERE id = $1 AND owner_id = $2", JOB_COLUMNS);
+    let row = transaction.query_opt(&query, &[&job_id, &caller.account_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(row.as_ref().map(JobStatus::from_row))
+}
+
+// Job claimed by a worker.
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub payload: Value,
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+impl Job {
+    // Whether a failure of this attempt dead-letters the job.
+    pub fn is_last_attempt(&self) -> bool {
+        self.attempts >= self.max_attempts
+    }
+}
+
+// What workers need to perform jobs.
+struct Context {
+    mailer: Arc<dyn Mailer>,
+    pusher: Option<Pusher>,
+}
+
+// Spawns the workers. Must be called from within the tokio runtime.
+pub fn start(mailer: Arc<dyn Mailer>, pusher: Option<Pusher>) {
+    let settings = config::get();
+
+    let context = Arc::new(Context { mailer, pusher });
+    for _ in 0..settings.job_workers {
+        tokio::spawn(work(context.clone(), Duration::from_secs(settings.job_poll_interval)));
+    }
+}
+
+async fn work(context: Arc<Context>, interval: Duration) {
+    loop {
+        match claim().await {
+            Ok(Some(job)) => {
+                let outcome = perform(&context, &job).await;
+                if let Err(e) = finish(&job, outcome).await {
+                    eprintln!("job {} status error: {}", job.id, e);
+                }
+            }
+            Ok(None) => tokio::time::sleep(interval).await,
+            Err(e) => {
+                eprintln!("job queue error: {}", e);
+                tokio::time::sleep(interval).await;
+            }
+        }
+    }
+}
+
+async fn perform(context: &Context, job: &Job) -> Result<Option<Value>, String> {
+    match job.kind.as_str() {
+        mail::SEND_JOB => mail::perform_send(context.mailer.as_ref(), job).await,
+        passes::PUSH_JOB => passes::perform_push(context.pusher.as_ref(), job).await,
+        kind => Err(format!("unknown job kind {}", kind)),
+    }
+}
+
+// Takes the oldest due job, including jobs whose worker stopped responding.
+async fn claim() -> Result<Option<Job>, Error> {
+    let client = db::connect().await?;
+
+    let query =
+        "UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_at = NOW(), updated_at = NOW() WHERE id = (SELECT id FROM jobs WHERE (status = 'queued' AND run_at <= NOW()) OR (status = 'running' AND locked_at < NOW() - make_interval(secs => $1)) ORDER BY run_at LIMIT 1 FOR UPDATE SKIP LOCKED) RETURNING id, kind, payload, attempts, max_attempts";
+    let row = client.query_opt(query, &[&(LOCK_TIMEOUT as f64)]).await?;
+
+    Ok(
+        row.map(|row| Job {
+            id: row.get(0),
+            kind: row.get(1),
+            payload: row.get(2),
+            attempts: row.get(3),
+            max_attempts: row.get(4),
+        })
+    )
+}
+
+fn backoff(attempts: i32) -> i64 {
+    let exponent = (attempts - 1).clamp(0, 20) as u32;
+    (BASE_BACKOFF * 2_i64.pow(exponent)).min(MAX_BACKOFF)
+}
+
+async fn finish(job: &Job, outcome: Result<Option<Value>, String>) -> Result<(), Error> {
+    let client = db::connect().await?;
+
+    match outcome {
+        Ok(result) => {
+            metrics::job_finished(&job.kind, "succeeded");
+            client.execute(
+                "UPDATE jobs SET status = 'succeeded', result = $1, locked_at = NULL, updated_at = NOW(), finished_at = NOW() WHERE id = $2",
+                &[&result, &job.id]
+            ).await?;
+        }
+        Err(error) if job.is_last_attempt() => {
+            eprintln!("job {} ({}) dead after {} attempts: {}", job.id, job.kind, job.attempts, error);
+            metrics::job_finished(&job.kind, "dead");
+            client.execute(
+                "UPDATE jobs SET status = 'dead', last_error = $1, locked_at = NULL, updated_at = NOW(), finished_at = NOW() WHERE id = $2",
+                &[&error, &job.id]
+            ).await?;
+        }
+        Err(error) => {
+            metrics::job_finished(&job.kind, "retried");
+            client.execute(
+                "UPDATE jobs SET status = 'queued', last_error = $1, locked_at = NULL, run_at = NOW() + make_interval(secs => $2), updated_at = NOW() WHERE id = $3",
+                &[&error, &(backoff(job.attempts) as f64), &job.id]
+            ).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_per_attempt() {
+        assert_eq!(backoff(1), BASE_BACKOFF);
+        assert_eq!(backoff(2), BASE_BACKOFF * 2);
+        assert_eq!(backoff(3), BASE_BACKOFF * 4);
+        assert_eq!(backoff(5), BASE_BACKOFF * 16);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(i32::MAX), MAX_BACKOFF);
+        // Counts below one are treated as the first attempt.
+        assert_eq!(backoff(0), BASE_BACKOFF);
+    }
+
+    #[test]
+    fn last_attempt() {
+        let job = |attempts| Job { id: 1, kind: mail::SEND_JOB.to_string(), payload: Value::Null, attempts, max_attempts: 3 };
+
+        assert!(!job(2).is_last_attempt());
+        assert!(job(3).is_last_attempt());
+    }
+}
@@ -0,0 +1,112 @@
+// Verification of Supabase session tokens (Authorization: Bearer <JWT>), signed either with the
+// project's HS256 secret (supabase_jwt_secret) or a key from a JWKS file (supabase_jwks_file).
+
+use std::collections::HashMap;
+use std::fs;
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{ decode, decode_header, Algorithm, DecodingKey, Validation };
+use rocket::serde::Deserialize;
+use uuid::Uuid;
+
+use crate::config::Settings;
+
+#[derive(Debug, Deserialize)]
+pub struct Claims {
+    pub sub: Uuid,
+    #[serde(default)]
+    pub app_metadata: AppMetadata,
+}
+
+// Claims the Supabase project sets for its users and they cannot change.
+#[derive(Debug, Default, Deserialize)]
+pub struct AppMetadata {
+    // Narrows the scopes of the user's sessions, if set.
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenError {
+    Invalid,
+    Expired,
+    Unsupported,
+}
+
+pub struct JwtVerifier {
+    secret: Option<DecodingKey>,
+    jwks: HashMap<String, (Algorithm, DecodingKey)>,
+    audience: String,
+    issuer: Option<String>,
+}
+
+impl JwtVerifier {
+    pub fn from_config(settings: &Settings) -> Result<JwtVerifier, String> {
+        let secret = settings.supabase_jwt_secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let mut jwks = HashMap::new();
+        if let Some(file) = &settings.supabase_jwks_file {
+            let path = file.display();
+            let contents = fs::read_to_string(file).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let set: JwkSet = serde_json
+                ::from_str(&contents)
+                .map_err(|e| format!("{} is not a valid JWKS file: {}", path, e))?;
+
+            for jwk in &set.keys {
+                let (Some(kid), Some(algorithm)) = (&jwk.common.key_id, jwk.common.key_algorithm) else {
+                    return Err(format!("Every key in {} needs a kid and an alg", path));
+                };
+                let algorithm = algorithm
+                    .to_string()
+                    .parse()
+                    .map_err(|_| format!("Unsupported algorithm {} in {}", algorithm, path))?;
+                let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid key {} in {}: {}", kid, path, e))?;
+
+                jwks.insert(kid.clone(), (algorithm, key));
+            }
+        }
+
+        Ok(JwtVerifier {
+            secret,
+            jwks,
+            audience: settings.supabase_jwt_audience.clone(),
+            issuer: settings.supabase_jwt_issuer.clone(),
+        })
+    }
+
+    // Checks signature, expiry, audience and (if configured) issuer of a token.
+    pub fn verify(&self, token: &str) -> Result<Claims, TokenError> {
+        let header = decode_header(token).map_err(|_| TokenError::Invalid)?;
+
+        let (algorithm, key) = match (&header.kid, header.alg) {
+            (Some(kid), _) if !self.jwks.is_empty() => {
+                let (algorithm, key) = self.jwks.get(kid).ok_or(TokenError::Invalid)?;
+                (*algorithm, key)
+            }
+            (_, Algorithm::HS256) => (Algorithm::HS256, self.secret.as_ref().ok_or(TokenError::Unsupported)?),
+            _ => {
+                return Err(TokenError::Unsupported);
+            }
+        };
+
+        if header.alg != algorithm {
+            return Err(TokenError::Invalid);
+        }
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_required_spec_claims(&["exp", "sub", "aud"]);
+        validation.set_audience(&[&self.audience]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        decode::<Claims>(token, key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| match e.kind() {
+                ErrorKind::ExpiredSignature => TokenError::Expired,
+                _ => TokenError::Invalid,
+            })
+    }
+}
@@ -0,0 +1,448 @@
+// API key generation, hashing and management. Keys are stored as a salted HMAC-SHA256 digest (peppered
+// with key_pepper) and looked up by their first PREFIX_LEN characters, which are not secret.
+
+use chrono::{ DateTime, Duration, Utc };
+use hmac::{ Hmac, Mac };
+use rand::distributions::Alphanumeric;
+use rand::{ Rng, RngCore };
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::{ Header, Status };
+use rocket::serde::{ Deserialize, Serialize };
+use rocket::{ Request, Response };
+use sha2::Sha256;
+use tokio_postgres::{ Error, Row };
+use uuid::Uuid;
+
+use crate::{ config, db };
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const PREFIX_LEN: usize = 12;
+const KEY_PREFIX: &str = "tk_";
+const SECRET_LEN: usize = 40;
+const SALT_LEN: usize = 16;
+
+// Longest time a rotated key keeps working, in seconds.
+pub const MAX_GRACE_PERIOD: i64 = 30 * 24 * 60 * 60;
+
+pub struct HashedKey {
+    pub prefix: String,
+    pub salt: String,
+    pub hash: String,
+    pub preview: String,
+}
+
+#[derive(Deserialize)]
+pub struct KeyRequest {
+    pub name: String,
+    // Only used on creation; defaults to the scopes of the creating key.
+    pub scopes: Option<Vec<String>>,
+}
+
+// Key as shown to its owner. Never contains the secret.
+#[derive(Serialize)]
+pub struct KeySummary {
+    pub id: i64,
+    pub name: String,
+    pub preview: String,
+    pub plan: String,
+    pub scopes: Vec<String>,
+    pub active: bool,
+    pub created_at: String,
+    pub last_used: Option<String>,
+    pub total_uses: i32,
+    pub expires_at: Option<String>,
+    pub replaced_by: Option<i64>,
+    // Organization the key acts for, None for the owner's own account.
+    pub organization_id: Option<Uuid>,
+}
+
+// Response to key creation, the only time the secret is returned.
+#[derive(Serialize)]
+pub struct CreatedKey {
+    #[serde(flatten)]
+    pub summary: KeySummary,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+pub struct RotateRequest {
+    // Seconds the old key keeps working, defaults to KEY_ROTATION_GRACE_PERIOD.
+    pub grace_period: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RotatedKey {
+    pub key: CreatedKey,
+    pub previous: KeySummary,
+}
+
+// Why a rotation was refused.
+#[derive(Debug)]
+pub enum RotateError {
+    // No such key, or it is inactive or already expired.
+    NotFound,
+    // The key has scopes the caller lacks or acts for another account than the caller.
+    Forbidden,
+    // The key was already rotated and is in its grace period. Only the newest key of a rotation
+    // chain may be rotated.
+    AlreadyRotated,
+    DatabaseError,
+}
+
+impl From<Error> for RotateError {
+    fn from(_: Error) -> RotateError {
+        RotateError::DatabaseError
+    }
+}
+
+impl RotateError {
+    pub fn status(&self) -> Status {
+        match self {
+            RotateError::NotFound => Status::NotFound,
+            RotateError::Forbidden => Status::Forbidden,
+            RotateError::AlreadyRotated => Status::Conflict,
+            RotateError::DatabaseError => Status::InternalServerError,
+        }
+    }
+}
+
+const SUMMARY_COLUMNS: &str =
+    "id, name, preview, plan, active, created_at, last_used, total_uses, scopes, expires_at, replaced_by, organization_id";
+
+impl KeySummary {
+    fn from_row(row: &Row) -> KeySummary {
+        let created_at: DateTime<Utc> = row.get(5);
+        let last_used: Option<DateTime<Utc>> = row.get(6);
+        let expires_at: Option<DateTime<Utc>> = row.get(9);
+
+        KeySummary {
+            id: row.get(0),
+            name: row.get(1),
+            preview: row.get(2),
+            plan: row.get(3),
+            active: row.get(4),
+            created_at: created_at.to_rfc3339(),
+            last_used: last_used.map(|last_used| last_used.to_rfc3339()),
+            total_uses: row.get(7),
+            scopes: row.get(8),
+            expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+            replaced_by: row.get(10),
+            organization_id: row.get(11),
+        }
+    }
+}
+
+fn pepper() -> &'static [u8] {
+    config::get().key_pepper.as_bytes()
+}
+
+fn mac(salt: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(pepper()).expect("HMAC accepts keys of any length");
+    mac.update(salt.as_bytes());
+    mac
+}
+
+pub fn prefix(key: &str) -> &str {
+    match key.char_indices().nth(PREFIX_LEN) {
+        Some((end, _)) => &key[..end],
+        None => key,
+    }
+}
+
+pub fn preview(key: &str) -> String {
+    let tail: Vec<char> = key.chars().rev().take(4).collect();
+    format!("{}...{}", prefix(key), tail.iter().rev().collect::<String>())
+}
+
+pub fn hash(key: &str) -> HashedKey {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = hex::encode(salt);
+
+    let mut mac = mac(&salt);
+    mac.update(key.as_bytes());
+
+    HashedKey {
+        prefix: prefix(key).to_string(),
+        hash: hex::encode(mac.finalize().into_bytes()),
+        salt,
+        preview: preview(key),
+    }
+}
+
+// Compares the presented key against a stored hash in constant time.
+pub fn verify(key: &str, salt: &str, hash: &str) -> bool {
+    let Ok(expected) = hex::decode(hash) else {
+        return false;
+    };
+
+    let mut mac = mac(salt);
+    mac.update(key.as_bytes());
+    mac.verify_slice(&expected).is_ok()
+}
+
+// Creates a new random key. The plaintext is only ever returned to the caller.
+pub fn generate() -> (String, HashedKey) {
+    let secret: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SECRET_LEN)
+        .map(char::from)
+        .collect();
+    let key = format!("{}{}", KEY_PREFIX, secret);
+    let hashed = hash(&key);
+
+    (key, hashed)
+}
+
+// Hashes every key that is still stored in plaintext and clears the plaintext column.
+// Run once with `ticketapi hash-keys` after applying migration 005.
+pub async fn hash_existing_keys() -> Result<u64, Error> {
+    let mut client = db::connect().await?;
+
+    let transaction = client.transaction().await?;
+    let rows = transaction
+        .query("SELECT id, api_key FROM keys WHERE api_key IS NOT NULL FOR UPDATE", &[]).await?;
+
+    let update_query =
+        "UPDATE keys SET key_prefix = $1, key_salt = $2, key_hash = $3, api_key = NULL WHERE id = $4";
+    let mut hashed_count = 0;
+
+    for row in rows {
+        let id: i64 = row.get(0);
+        let api_key: String = row.get(1);
+        let hashed = hash(&api_key);
+
+        hashed_count += transaction.execute(
+            update_query,
+            &[&hashed.prefix, &hashed.salt, &hashed.hash, &id]
+        ).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(hashed_count)
+}
+
+pub async fn create_key(
+    user_id: Uuid,
+    organization_id: Option<Uuid>,
+    name: &str,
+    plan: &str,
+    scopes: &[String]
+) -> Result<CreatedKey, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let (key, hashed) = generate();
+    let query = format!(
+        "INSERT INTO keys (user_id, name, preview, plan, key_prefix, key_salt, key_hash, scopes, organization_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING {}",
+        SUMMARY_COLUMNS
+    );
+    let row = transaction.query_one(
+        &query,
+        &[
+            &user_id,
+            &name,
+            &hashed.preview,
+            &plan,
+            &hashed.prefix,
+            &hashed.salt,
+            &hashed.hash,
+            &scopes,
+            &organization_id,
+        ]
+    ).await?;
+
+    transaction.commit().await?;
+
+    Ok(CreatedKey { summary: KeySummary::from_row(&row), key })
+}
+
+pub async fn list_keys(user_id: Uuid) -> Result<Vec<KeySummary>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let query = format!("SELECT {} FROM keys WHERE user_id = $1 ORDER BY id", SUMMARY_COLUMNS);
+    let rows = transaction.query(&query, &[&user_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(rows.iter().map(KeySummary::from_row).collect())
+}
+
+pub async fn rename_key(user_id: Uuid, key_id: i64, name: &str) -> Result<Option<KeySummary>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let query = format!(
+        "UPDATE keys SET name = $1 WHERE id = $2 AND user_id = $3 RETURNING {}",
+        SUMMARY_COLUMNS
+    );
+    let row = transaction.query_opt(&query, &[&name, &key_id, &user_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(row.as_ref().map(KeySummary::from_row))
+}
+
+pub async fn deactivate_key(user_id: Uuid, key_id: i64) -> Result<Option<KeySummary>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let query = format!(
+        "UPDATE keys SET active = FALSE WHERE id = $1 AND user_id = $2 RETURNING {}",
+        SUMMARY_COLUMNS
+    );
+    let row = transaction.query_opt(&query, &[&key_id, &user_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(row.as_ref().map(KeySummary::from_row))
+}
+
+// Returns false if the user has no such key.
+pub async fn delete_key(user_id: Uuid, key_id: i64) -> Result<bool, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let deleted = transaction
+        .execute("DELETE FROM keys WHERE id = $1 AND user_id = $2", &[&key_id, &user_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(deleted > 0)
+}
+
+pub fn grace_period(requested: Option<i64>) -> Option<Duration> {
+    let seconds = requested.unwrap_or(config::get().key_rotation_grace_period);
+
+    (0..=MAX_GRACE_PERIOD).contains(&seconds).then(|| Duration::seconds(seconds))
+}
+
+// Issues a new secret for a key. The old secret keeps working until the grace period ends. The
+// new key continues the old one's rotation chain, so both share one rate limit bucket and quota,
+// and starts with its usage counters. Only keys for the caller's account with no scopes beyond
+// the caller's may be rotated, as with creation.
+pub async fn rotate_key(
+    user_id: Uuid,
+    organization_id: Option<Uuid>,
+    caller_scopes: &[String],
+    key_id: i64,
+    grace_period: Duration
+) -> Result<RotatedKey, RotateError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let select_query =
+        "SELECT name, plan, scopes, monthly_quota, organization_id, total_uses, period_uses, period_start, COALESCE(lineage_id, id) FROM keys WHERE id = $1 AND user_id = $2 AND active AND (expires_at IS NULL OR expires_at > NOW()) AND replaced_by IS NULL FOR UPDATE";
+    let Some(old) = transaction.query_opt(select_query, &[&key_id, &user_id]).await? else {
+        // A concurrent rotation of the same key ends up here too once it has committed.
+        let rotated = transaction.query_opt(
+            "SELECT 1 FROM keys WHERE id = $1 AND user_id = $2 AND active AND (expires_at IS NULL OR expires_at > NOW()) AND replaced_by IS NOT NULL",
+            &[&key_id, &user_id]
+        ).await?;
+        return Err(if rotated.is_some() { RotateError::AlreadyRotated } else { RotateError::NotFound });
+    };
+
+    let name: String = old.get(0);
+    let plan: String = old.get(1);
+    let scopes: Vec<String> = old.get(2);
+    let monthly_quota: Option<i64> = old.get(3);
+    let key_organization_id: Option<Uuid> = old.get(4);
+    let total_uses: i32 = old.get(5);
+    let period_uses: i32 = old.get(6);
+    let period_start: DateTime<Utc> = old.get(7);
+    let lineage_id: i64 = old.get(8);
+
+    if key_organization_id != organization_id || scopes.iter().any(|scope| !caller_scopes.contains(scope)) {
+        return Err(RotateError::Forbidden);
+    }
+
+    let (key, hashed) = generate();
+    let insert_query = format!(
+        "INSERT INTO keys (user_id, name, preview, plan, key_prefix, key_salt, key_hash, scopes, monthly_quota, organization_id, total_uses, period_uses, period_start, lineage_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14) RETURNING {}",
+        SUMMARY_COLUMNS
+    );
+    let new = transaction.query_one(
+        &insert_query,
+        &[
+            &user_id,
+            &name,
+            &hashed.preview,
+            &plan,
+            &hashed.prefix,
+            &hashed.salt,
+            &hashed.hash,
+            &scopes,
+            &monthly_quota,
+            &key_organization_id,
+            &total_uses,
+            &period_uses,
+            &period_start,
+            &lineage_id,
+        ]
+    ).await?;
+    let new = KeySummary::from_row(&new);
+
+    let expires_at = Utc::now() + grace_period;
+    let update_query = format!(
+        "UPDATE keys SET expires_at = $1, replaced_by = $2 WHERE id = $3 RETURNING {}",
+        SUMMARY_COLUMNS
+    );
+    let previous = transaction.query_one(&update_query, &[&expires_at, &new.id, &key_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(RotatedKey {
+        key: CreatedKey { summary: new, key },
+        previous: KeySummary::from_row(&previous),
+    })
+}
+
+// Expiry of the key used for the request, remembered by the Caller guard.
+#[derive(Debug, Clone, Copy)]
+struct KeyExpiry(DateTime<Utc>);
+
+pub fn remember_expiry(req: &Request<'_>, expires_at: Option<DateTime<Utc>>) {
+    if let Some(expires_at) = expires_at {
+        req.local_cache(|| Some(KeyExpiry(expires_at)));
+    }
+}
+
+// Warns clients that authenticate with a key that is being rotated out.
+pub struct KeyDeprecationHeaders;
+
+#[rocket::async_trait]
+impl Fairing for KeyDeprecationHeaders {
+    fn info(&self) -> Info {
+        Info { name: "Key deprecation headers", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(KeyExpiry(expires_at)) = req.local_cache(|| None::<KeyExpiry>) {
+            res.set_header(Header::new("Deprecation", "true"));
+            res.set_header(Header::new("Sunset", expires_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()));
+            res.set_header(
+                Header::new(
+                    "Warning",
+                    format!(
+                        "299 TicketAPI \"This API key is deprecated and stops working at {}. Switch to its replacement.\"",
+                        expires_at.to_rfc3339()
+                    )
+                )
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotating_a_rotated_key_conflicts() {
+        assert_eq!(RotateError::AlreadyRotated.status(), Status::Conflict);
+        assert_eq!(RotateError::NotFound.status(), Status::NotFound);
+    }
+}
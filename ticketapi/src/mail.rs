@@ -0,0 +1,448 @@
+// Ticket emails. Messages are rendered from templates, recorded in email_deliveries and sent
+// through the configured Mailer (mail_transport: smtp, file or stdout) by a background job, so a
+// slow mail server does not hold up requests and failed sends are retried.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{ DateTime, Utc };
+use lettre::message::header::ContentType;
+use lettre::message::{ self, Mailbox, MultiPart, SinglePart };
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{ AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor };
+use rand::Rng;
+use rocket::serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use tokio_postgres::{ Error, GenericClient, Row };
+use uuid::Uuid;
+
+use crate::config::{ MailTransport, Settings, SmtpTls };
+use crate::jobs::{ self, Job };
+use crate::tickets::{ self, Ticket };
+use crate::{ calendar, db, Caller };
+
+// Kind of the jobs that send an email.
+pub const SEND_JOB: &str = "send_email";
+
+#[derive(Deserialize, Serialize)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[rocket::async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+// Delivery through an SMTP relay, configured with smtp_host, smtp_port, smtp_username,
+// smtp_password and smtp_tls (starttls, tls or none).
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn from_config(settings: &Settings) -> Result<SmtpMailer, String> {
+        let host = settings.smtp_host.as_deref().ok_or("smtp_host must be set for mail_transport = \"smtp\"")?;
+        let mut builder = match settings.smtp_tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+            SmtpTls::Tls =>
+                AsyncSmtpTransport::<Tokio1Executor>::relay(host).map_err(|e| format!("smtp_host is not a valid host: {}", e))?,
+            SmtpTls::Starttls =>
+                AsyncSmtpTransport::<Tokio1Executor>
+                    ::starttls_relay(host)
+                    .map_err(|e| format!("smtp_host is not a valid host: {}", e))?,
+        };
+
+        if let Some(port) = settings.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&settings.smtp_username, &settings.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from: settings.mail_from.parse().map_err(|_| "mail_from must be a valid address".to_string())?,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let to: Mailbox = email.to.parse().map_err(|e| MailError(format!("invalid recipient: {}", e)))?;
+        let builder = Message::builder().from(self.from.clone()).to(to).subject(email.subject.clone());
+        let message = if email.attachments.is_empty() {
+            builder.header(ContentType::TEXT_PLAIN).body(email.body.clone())
+        } else {
+            let mut parts = MultiPart::mixed().singlepart(SinglePart::plain(email.body.clone()));
+            for attachment in &email.attachments {
+                let content_type = ContentType::parse(&attachment.content_type).map_err(|e| MailError(e.to_string()))?;
+                parts = parts.singlepart(
+                    message::Attachment::new(attachment.filename.clone()).body(attachment.data.clone(), content_type)
+                );
+            }
+            builder.multipart(parts)
+        }.map_err(|e| MailError(e.to_string()))?;
+
+        self.transport
+            .send(message).await
+            .map(|_| ())
+            .map_err(|e| MailError(e.to_string()))
+    }
+}
+
+// Writes messages to .eml files in a directory, or to stdout. For local development.
+pub struct FileMailer {
+    from: String,
+    dir: Option<PathBuf>,
+}
+
+impl FileMailer {
+    fn format(&self, email: &Email) -> String {
+        let headers = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n",
+            self.from,
+            email.to,
+            email.subject,
+            Utc::now().to_rfc2822()
+        );
+        if email.attachments.is_empty() {
+            return format!("{}Content-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n", headers, email.body);
+        }
+
+        let boundary = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let mut message = format!(
+            "{}Content-Type: multipart/mixed; boundary=\"{}\"\r\n\r\n--{}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+            headers,
+            boundary,
+            boundary,
+            email.body
+        );
+        for attachment in &email.attachments {
+            message.push_str(
+                &format!(
+                    "--{}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{}\"\r\n\r\n{}\r\n",
+                    boundary,
+                    attachment.content_type,
+                    attachment.filename,
+                    attachment.data
+                )
+            );
+        }
+        message.push_str(&format!("--{}--\r\n", boundary));
+        message
+    }
+}
+
+#[rocket::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = self.format(email);
+
+        match &self.dir {
+            Some(dir) => {
+                let name = format!("{}-{:08x}.eml", Utc::now().format("%Y%m%dT%H%M%S%.6f"), rand::thread_rng().gen::<u32>());
+                tokio::fs::write(dir.join(name), message).await.map_err(|e| MailError(e.to_string()))
+            }
+            None => {
+                println!("{}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Template {
+    Created,
+    // Sent to the recipient of a transfer that has to be accepted, with the token to accept it.
+    TransferOffer,
+    Transferred,
+    // Sent to the previous holder of a transferred ticket.
+    TransferredAway,
+    Cancelled,
+}
+
+impl Template {
+    const ALL: [Template; 5] = [
+        Template::Created,
+        Template::TransferOffer,
+        Template::Transferred,
+        Template::TransferredAway,
+        Template::Cancelled,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Template::Created => "created",
+            Template::TransferOffer => "transfer_offer",
+            Template::Transferred => "transferred",
+            Template::TransferredAway => "transferred_away",
+            Template::Cancelled => "cancelled",
+        }
+    }
+
+    // Built-in subject and body, replaced by <name>.txt in MAIL_TEMPLATE_DIR if present.
+    fn default_text(&self) -> &'static str {
+        match self {
+            Template::Created =>
+                "Subject: Your ticket for {{event_name}}\n\nHello {{holder_name}},\n\nyour ticket for {{event_name}} at {{event_location}} on {{event_date}} is confirmed.\n\nTicket code: {{code}}\n\n{{terms_and_conditions}}\n",
+            Template::TransferOffer =>
+                "Subject: {{from_name}} wants to transfer a ticket for {{event_name}} to you\n\nHello {{to_name}},\n\n{{from_name}} wants to transfer their ticket for {{event_name}} at {{event_location}} on {{event_date}} to you.\n\nTo accept it, send this token to POST /beta/1/transfers/accept before {{expires_at}}: {{token}}\n\nYour ticket is emailed to you once you accept.\n",
+            Template::Transferred =>
+                "Subject: A ticket for {{event_name}} has been transferred to you\n\nHello {{holder_name}},\n\na ticket for {{event_name}} at {{event_location}} on {{event_date}} has been transferred to you.\n\nTicket code: {{code}}\n\n{{terms_and_conditions}}\n",
+            Template::TransferredAway =>
+                "Subject: Your ticket for {{event_name}} has been transferred\n\nHello {{from_name}},\n\nyour ticket for {{event_name}} on {{event_date}} has been transferred to {{to_name}}. Your ticket code is no longer valid.\n",
+            Template::Cancelled =>
+                "Subject: Your ticket for {{event_name}} has been cancelled\n\nHello {{holder_name}},\n\nyour ticket for {{event_name}} on {{event_date}} has been cancelled and is no longer valid.\n",
+        }
+    }
+}
+
+// Subject line and body of a template.
+struct Text {
+    subject: String,
+    body: String,
+}
+
+impl Text {
+    fn parse(template: &str) -> Text {
+        let (header, body) = template.split_once("\n\n").unwrap_or(("", template));
+        let subject = header.strip_prefix("Subject:").unwrap_or(header).trim();
+
+        Text { subject: subject.to_string(), body: body.to_string() }
+    }
+
+    // Fills in the ticket's fields and any extra ones the template uses.
+    fn render(&self, ticket: &Ticket, extra: &[(&str, &str)]) -> (String, String) {
+        let fields = [
+            ("event_name", ticket.event_name.as_deref()),
+            ("event_location", ticket.event_location.as_deref()),
+            ("event_date", ticket.event_date.as_deref()),
+            ("holder_name", ticket.holder_name.as_deref()),
+            ("holder_email", ticket.holder_email.as_deref()),
+            ("terms_and_conditions", ticket.terms_and_conditions.as_deref()),
+            ("code", ticket.code.as_deref()),
+        ];
+
+        let fill = |text: &str| {
+            let text = fields.iter().fold(text.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{{{}}}}}", name), value.unwrap_or(""))
+            });
+            extra.iter().fold(text, |text, (name, value)| text.replace(&format!("{{{{{}}}}}", name), value))
+        };
+
+        (fill(&self.subject), fill(&self.body))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Delivery {
+    pub id: i64,
+    pub ticket_id: i64,
+    pub template: String,
+    pub recipient: String,
+    // pending, sent or failed
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+    // Job sending the email, see GET /jobs/<id>.
+    pub job_id: Option<i64>,
+}
+
+const DELIVERY_COLUMNS: &str = "id, ticket_id, template, recipient, status, error, created_at, sent_at, job_id";
+
+impl Delivery {
+    fn from_row(row: &Row) -> Delivery {
+        let created_at: DateTime<Utc> = row.get(6);
+        let sent_at: Option<DateTime<Utc>> = row.get(7);
+
+        Delivery {
+            id: row.get(0),
+            ticket_id: row.get(1),
+            template: row.get(2),
+            recipient: row.get(3),
+            status: row.get(4),
+            error: row.get(5),
+            created_at: created_at.to_rfc3339(),
+            sent_at: sent_at.map(|sent_at| sent_at.to_rfc3339()),
+            job_id: row.get(8),
+        }
+    }
+}
+
+pub struct Mail {
+    mailer: Arc<dyn Mailer>,
+    templates: HashMap<Template, Text>,
+}
+
+impl Mail {
+    pub fn from_config(settings: &Settings) -> Result<Mail, String> {
+        let from = settings.mail_from.clone();
+
+        let mailer: Arc<dyn Mailer> = match settings.mail_transport {
+            MailTransport::Smtp => Arc::new(SmtpMailer::from_config(settings)?),
+            MailTransport::File => {
+                let dir = settings.mail_dir.clone().ok_or("mail_dir must be set for mail_transport = \"file\"")?;
+                fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+                Arc::new(FileMailer { from, dir: Some(dir) })
+            }
+            MailTransport::Stdout => Arc::new(FileMailer { from, dir: None }),
+        };
+
+        let template_dir = &settings.mail_template_dir;
+        let templates = Template::ALL
+            .iter()
+            .map(|template| {
+                let text = template_dir
+                    .as_ref()
+                    .and_then(|dir| fs::read_to_string(dir.join(format!("{}.txt", template.name()))).ok())
+                    .unwrap_or_else(|| template.default_text().to_string());
+                (*template, Text::parse(&text))
+            })
+            .collect();
+
+        Ok(Mail { mailer, templates })
+    }
+
+    pub fn mailer(&self) -> Arc<dyn Mailer> {
+        self.mailer.clone()
+    }
+
+    // Records an email about a ticket to its holder and queues the job that sends it. Returns None
+    // if the account has no such ticket or the ticket has no holder email.
+    pub async fn notify(&self, caller: &Caller, ticket_id: i64, template: Template) -> Result<Option<Delivery>, Error> {
+        let Some(ticket) = tickets::get_ticket(caller, ticket_id).await? else {
+            return Ok(None);
+        };
+        let Some(recipient) = ticket.holder_email.clone().filter(|email| !email.is_empty()) else {
+            return Ok(None);
+        };
+
+        let mut client = db::connect().await?;
+        let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+        let delivery = self.queue(&transaction, caller.account_id, &ticket, template, &recipient, &[]).await?;
+
+        transaction.commit().await?;
+
+        Ok(Some(delivery))
+    }
+
+    // Records an email about an account's ticket and queues the job that sends it, as part of the
+    // given transaction. Extra fields are filled into the template besides the ticket's.
+    pub async fn queue(
+        &self,
+        client: &impl GenericClient,
+        owner_id: Uuid,
+        ticket: &Ticket,
+        template: Template,
+        recipient: &str,
+        extra: &[(&str, &str)]
+    ) -> Result<Delivery, Error> {
+        let ticket_id = ticket.id.expect("stored tickets have an id");
+        let (subject, body) = self.templates[&template].render(ticket, extra);
+        // The event for the holder's calendar, or its cancellation
+        let attachments = calendar::event(ticket, template == Template::Cancelled)
+            .filter(|_| !matches!(template, Template::TransferOffer | Template::TransferredAway))
+            .map(|event| Attachment {
+                filename: "event.ics".to_string(),
+                content_type: "text/calendar; charset=utf-8".to_string(),
+                data: event,
+            })
+            .into_iter()
+            .collect();
+        let email = Email { to: recipient.to_string(), subject, body, attachments };
+
+        let row = client.query_one(
+            "INSERT INTO email_deliveries (ticket_id, owner_id, template, recipient) VALUES ($1, $2, $3, $4) RETURNING id",
+            &[&ticket_id, &owner_id, &template.name(), &recipient]
+        ).await?;
+        let delivery_id: i64 = row.get(0);
+
+        let payload = json!({ "delivery_id": delivery_id, "email": email });
+        let job_id = jobs::enqueue(client, owner_id, SEND_JOB, &payload).await?;
+
+        let query = format!("UPDATE email_deliveries SET job_id = $1 WHERE id = $2 RETURNING {}", DELIVERY_COLUMNS);
+        let row = client.query_one(&query, &[&job_id, &delivery_id]).await?;
+
+        Ok(Delivery::from_row(&row))
+    }
+}
+
+#[derive(Deserialize)]
+struct SendPayload {
+    delivery_id: i64,
+    email: Email,
+}
+
+// Performs a send_email job. The delivery stays pending while the job is retried and fails with
+// the job's last attempt.
+pub async fn perform_send(mailer: &dyn Mailer, job: &Job) -> Result<Option<Value>, String> {
+    let payload: SendPayload = serde_json::from_value(job.payload.clone()).map_err(|e| e.to_string())?;
+
+    let result = mailer.send(&payload.email).await;
+    let status = match (&result, job.is_last_attempt()) {
+        (Ok(()), _) => "sent",
+        (Err(_), true) => "failed",
+        (Err(_), false) => "pending",
+    };
+    let error = result.as_ref().err().map(|e| e.to_string());
+    record_result(payload.delivery_id, status, error.as_deref()).await.map_err(|e| e.to_string())?;
+
+    result.map(|_| None).map_err(|e| e.to_string())
+}
+
+async fn record_result(delivery_id: i64, status: &str, error: Option<&str>) -> Result<(), Error> {
+    let client = db::connect().await?;
+
+    client.execute(
+        "UPDATE email_deliveries SET status = $1, error = $2, sent_at = CASE WHEN $1 = 'sent' THEN NOW() END WHERE id = $3",
+        &[&status, &error, &delivery_id]
+    ).await?;
+
+    Ok(())
+}
+
+// Emails sent about a ticket, newest first, including those of deleted tickets. Returns None if
+// the account has no such ticket and sent no emails about it.
+pub async fn list_deliveries(caller: &Caller, ticket_id: i64) -> Result<Option<Vec<Delivery>>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query = format!("SELECT {} FROM email_deliveries WHERE ticket_id = $1 AND owner_id = $2 ORDER BY id DESC", DELIVERY_COLUMNS);
+    let rows = transaction.query(&query, &[&ticket_id, &caller.account_id]).await?;
+    let exists = !rows.is_empty() ||
+        transaction
+            .query_opt("SELECT 1 FROM tickets WHERE id = $1 AND owner_id = $2", &[&ticket_id, &caller.account_id]).await?
+            .is_some();
+
+    transaction.commit().await?;
+
+    Ok(exists.then(|| rows.iter().map(Delivery::from_row).collect()))
+}
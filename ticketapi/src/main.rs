@@ -4,15 +4,106 @@
 
 #[macro_use]
 extern crate rocket;
-use rocket::http::Status;
+use rocket::http::{ Header, Status };
 use rocket::request::{ self, Request, FromRequest };
 use rocket::request::Outcome;
 use rocket::serde::{ json::Json, Serialize, Deserialize };
-use tokio_postgres::{ NoTls, Error };
+use rocket::data::{ Data, ToByteUnit };
+use rocket::State;
 use std::env;
 use dotenv::dotenv;
 
-struct ApiKey(String);
+mod branding;
+mod calendar;
+mod codes;
+mod config;
+mod db;
+mod events;
+mod expiry;
+mod health;
+mod jobs;
+mod jwt;
+mod keys;
+mod mail;
+mod metrics;
+mod orgs;
+mod passes;
+mod pdf;
+mod plans;
+mod quota;
+mod ratelimit;
+mod scans;
+mod scopes;
+mod tickets;
+mod tls;
+mod transfers;
+mod usage;
+
+use plans::Plans;
+use quota::{ Quota, QuotaHeaders, QuotaResponse };
+use ratelimit::{ BucketKey, IpRateLimit, RateLimitHeaders, RateLimiter };
+use jwt::{ JwtVerifier, TokenError };
+use orgs::{ Member, MemberRequest, Organization, OrganizationRequest, Role };
+use branding::{ Logo, TicketTemplate };
+use events::EventPolicy;
+use health::{ Health, Readiness };
+use jobs::JobStatus;
+use mail::{ Delivery, Mail, Template };
+use metrics::{ MetricsAccess, RequestMetrics };
+use scans::{ ManifestSigner, ScanLog, ScanReport, ScanRequest, ScannerKey };
+use passes::{ ApplePass, IfModifiedSince, Logs, PassSigner, Pusher, Registration, UpdatedPasses };
+use tickets::{ HistoryEntry, Ticket, Verification };
+use transfers::{ AcceptRequest, Transfer, TransferRequest };
+use usage::{ UsageRecorder, UsageReport, UsageTracking };
+use scopes::{
+    KeysManage,
+    MembersManage,
+    MembersRead,
+    Scoped,
+    TicketsDelete,
+    TicketsRead,
+    TicketsVerify,
+    TicketsWrite,
+    UsageRead,
+};
+use keys::{ CreatedKey, KeyDeprecationHeaders, KeyRequest, KeySummary, RotateRequest, RotatedKey };
+use chrono::{ DateTime, Duration, NaiveDate, Utc };
+use uuid::Uuid;
+
+// Authenticated caller, either an API key (x-api-key) or a Supabase session (bearer JWT).
+struct Caller {
+    // None for session callers.
+    key_id: Option<i64>,
+    // None for sessions of users without an active key.
+    metering: Option<Metering>,
+    user_id: Uuid,
+    // Account that owns the tickets created and managed by this caller: the user, or the
+    // organization the caller acts for.
+    account_id: Uuid,
+    // Role in the organization, None when acting for the user's own account.
+    role: Option<Role>,
+    plan: String,
+    scopes: Vec<String>,
+    expires_at: Option<DateTime<Utc>>,
+    // None for sessions of users without an active key.
+    quota: Option<Quota>,
+}
+
+// Keys a request is limited and counted by. Keys replaced by rotation share them with their
+// replacements.
+#[derive(Clone, Copy)]
+struct Metering {
+    // First key of the rotation chain, for the rate limit bucket.
+    lineage_id: i64,
+    // Newest key of the rotation chain, whose usage counts against the quota.
+    quota_key_id: i64,
+}
+
+impl Caller {
+    fn organization_id(&self) -> Option<Uuid> {
+        self.role.map(|_| self.account_id)
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 struct ErrorResponse {
@@ -22,136 +113,400 @@ struct ErrorResponse {
     suggestion: &'static str,
 }
 
-#[derive(Debug)]
-enum ApiKeyError {
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AuthError {
     BadCount,
     Missing,
     Invalid,
+    Inactive,
+    Expired,
+    InvalidToken,
+    TokenExpired,
+    InsufficientScope,
+    NotAMember,
+    RateLimited,
+    QuotaExceeded,
     DatabaseError,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Ticket {
-    id: Option<i64>,
-    event_name: Option<String>,
-    event_location: Option<String>,
-    event_date: Option<String>,
-    status: Option<String>,
-    holder_name: Option<String>,
-    holder_email: Option<String>,
-    notes: Option<String>,
-    terms_and_conditions: Option<String>,
-}
+impl AuthError {
+    fn status(&self) -> Status {
+        match self {
+            AuthError::BadCount => Status::BadRequest,
+            AuthError::Missing |
+            AuthError::Invalid |
+            AuthError::Inactive |
+            AuthError::Expired |
+            AuthError::InvalidToken |
+            AuthError::TokenExpired => Status::Unauthorized,
+            AuthError::InsufficientScope | AuthError::NotAMember => Status::Forbidden,
+            AuthError::RateLimited | AuthError::QuotaExceeded => Status::TooManyRequests,
+            AuthError::DatabaseError => Status::InternalServerError,
+        }
+    }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for ApiKey {
-    type Error = ApiKeyError;
+    fn response(&self) -> ErrorResponse {
+        let (error, message, suggestion) = match self {
+            AuthError::BadCount =>
+                (
+                    "Bad Request",
+                    "The request contains more than one credential.",
+                    "Send exactly one x-api-key header or one Authorization bearer token.",
+                ),
+            AuthError::Missing =>
+                (
+                    "Unauthorized",
+                    "The request does not contain an API key.",
+                    "Send your API key in the x-api-key header or a session token as Authorization: Bearer.",
+                ),
+            AuthError::Invalid =>
+                (
+                    "Unauthorized",
+                    "The API key is not valid.",
+                    "Check that the key in the x-api-key header is correct.",
+                ),
+            AuthError::Inactive =>
+                (
+                    "Unauthorized",
+                    "The API key has been deactivated.",
+                    "Use an active API key or create a new one.",
+                ),
+            AuthError::Expired =>
+                (
+                    "Unauthorized",
+                    "The API key has expired after being rotated.",
+                    "Use the key that replaced it.",
+                ),
+            AuthError::InvalidToken =>
+                (
+                    "Unauthorized",
+                    "The bearer token is not valid.",
+                    "Send a session token issued by Supabase for this project.",
+                ),
+            AuthError::TokenExpired =>
+                (
+                    "Unauthorized",
+                    "The bearer token has expired.",
+                    "Refresh the session and retry with the new token.",
+                ),
+            AuthError::InsufficientScope =>
+                (
+                    "Forbidden",
+                    "The API key does not have the scope required by this endpoint.",
+                    "Use a key with the required scope (see the API reference).",
+                ),
+            AuthError::NotAMember =>
+                (
+                    "Forbidden",
+                    "The caller is not a member of the organization it acts for.",
+                    "Check the X-Organization-Id header or ask an owner or admin of the organization to add you.",
+                ),
+            AuthError::RateLimited =>
+                (
+                    "Too Many Requests",
+                    "You have sent too many requests in a given amount of time.",
+                    "Wait for the number of seconds in the Retry-After header before retrying.",
+                ),
+            AuthError::QuotaExceeded =>
+                (
+                    "Quota Exceeded",
+                    "Your API key has used up its monthly request quota.",
+                    "Wait for the quota to reset (see X-Quota-Reset) or upgrade your plan.",
+                ),
+            AuthError::DatabaseError =>
+                (
+                    "Internal Server Error",
+                    "The credentials could not be verified.",
+                    "Try again later or contact support if the issue persists.",
+                ),
+        };
 
-    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let keys: Vec<_> = req.headers().get("x-api-key").collect();
+        ErrorResponse { status: self.status().code, error, message, suggestion }
+    }
 
-        match keys.len() {
-            0 => Outcome::Error((Status::BadRequest, ApiKeyError::Missing)),
-            1 =>
-                match is_api_key_valid(keys[0]).await {
-                    Ok(_) => Outcome::Success(ApiKey(keys[0].to_string())),
-                    Err(_) => Outcome::Error((Status::BadRequest, ApiKeyError::Invalid)),
-                }
-            _ => Outcome::Error((Status::BadRequest, ApiKeyError::BadCount)),
+    fn challenge(&self) -> &'static str {
+        match self {
+            AuthError::InvalidToken | AuthError::TokenExpired =>
+                "Bearer realm=\"TicketAPI\", error=\"invalid_token\"",
+            _ => "ApiKey realm=\"TicketAPI\", header=\"x-api-key\", Bearer realm=\"TicketAPI\"",
         }
     }
 }
 
-// Key verification.
-async fn is_api_key_valid(key: &str) -> Result<i64, ApiKeyError> {
-    let database_url = env::var("SUPABASE_URI").map_err(|_| ApiKeyError::DatabaseError)?;
-    let (client, connection) = tokio_postgres
-        ::connect(&database_url, NoTls).await
-        .map_err(|_| ApiKeyError::DatabaseError)?;
-
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+// Rendered ticket document, offered for download under a file name.
+#[derive(Responder)]
+#[response(content_type = "application/pdf")]
+struct PdfFile {
+    inner: Vec<u8>,
+    disposition: Header<'static>,
+}
 
-    let row = client
-        .query_one("SELECT id FROM keys WHERE api_key = $1", &[&key]).await
-        .map_err(|_| ApiKeyError::Invalid)?;
+// Event manifest for offline scanners, signed over the exact body.
+#[derive(Responder)]
+#[response(content_type = "json")]
+struct SignedManifest {
+    inner: Vec<u8>,
+    signature: Header<'static>,
+}
 
-    let key_id: i64 = row.get(0);
-    Ok(key_id)
+// Metrics in the Prometheus text exposition format.
+#[derive(Responder)]
+#[response(content_type = "text/plain; version=0.0.4")]
+struct MetricsText(String);
+
+// Ticket's event for import into calendars.
+#[derive(Responder)]
+#[response(content_type = "text/calendar")]
+struct CalendarFile {
+    inner: String,
+    disposition: Header<'static>,
 }
 
-// Usage data gathering.
-async fn update_usage(key_id: i64) -> Result<(), Error> {
-    let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+// Signed Apple Wallet pass with the time of its ticket's last change.
+#[derive(Responder)]
+#[response(content_type = "application/vnd.apple.pkpass")]
+struct PassFile {
+    inner: Vec<u8>,
+    last_modified: Header<'static>,
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+impl PassFile {
+    fn new(pass: Vec<u8>, updated_at: DateTime<Utc>) -> PassFile {
+        PassFile {
+            inner: pass,
+            last_modified: Header::new("Last-Modified", updated_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
         }
-    });
+    }
+}
 
-    let total_query = "UPDATE keys SET total_uses = total_uses + 1 WHERE id = $1".to_string();
-    let _ = client.query_one(&total_query, &[&key_id]).await?;
+#[derive(Responder)]
+enum WalletPass {
+    Updated(PassFile),
+    #[response(status = 304)] NotModified(()),
+}
 
-    Ok(())
+#[derive(Responder)]
+enum UpdatedSerials {
+    Updated(Json<UpdatedPasses>),
+    #[response(status = 204)] NoUpdates(()),
 }
 
-// Ticket creation.
-async fn insert_ticket(ticket: Json<Ticket>, key_id: i64) -> Result<i64, Error> {
-    let event_name = ticket.event_name.clone().unwrap_or_else(|| "".to_string());
-    let event_location = ticket.event_location.clone().unwrap_or_else(|| "".to_string());
-    let event_date = ticket.event_date.clone().unwrap_or_else(|| "".to_string());
-    let status = ticket.status.clone().unwrap_or_else(|| "".to_string());
-    let holder_name = ticket.holder_name.clone().unwrap_or_else(|| "".to_string());
-    let holder_email = ticket.holder_email.clone().unwrap_or_else(|| "".to_string());
-    let notes = ticket.notes.clone().unwrap_or_else(|| "".to_string());
-    let terms_and_conditions = ticket.terms_and_conditions
-        .clone()
-        .unwrap_or_else(|| "".to_string());
+// Error body with a WWW-Authenticate challenge, for 401 responses.
+#[derive(Responder)]
+struct Unauthorized {
+    inner: Json<ErrorResponse>,
+    challenge: Header<'static>,
+}
 
-    let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // Other Authorization schemes (e.g. Basic from a proxy) do not compete with an API key.
+        let keys: Vec<_> = req.headers().get("x-api-key").collect();
+        let tokens: Vec<_> = req.headers()
+            .get("authorization")
+            .filter(|value| keys.is_empty() || value.starts_with("Bearer "))
+            .collect();
+        let plans = req.rocket().state::<Plans>().expect("Plans must be managed");
+
+        let result = match (keys.len(), tokens.len()) {
+            (0, 0) => Err(AuthError::Missing),
+            (1, 0) => is_api_key_valid(keys[0], plans).await,
+            (0, 1) => is_session_valid(req, tokens[0]).await,
+            _ => Err(AuthError::BadCount),
+        };
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+        // Requests without usable credentials are limited by client IP.
+        match result {
+            Ok(caller) => {
+                let bucket = match caller.metering {
+                    Some(metering) => BucketKey::Key(metering.lineage_id),
+                    None => BucketKey::User(caller.user_id),
+                };
+                if ratelimit::limit(req, bucket, &caller.plan).exceeded() {
+                    return reject(req, AuthError::RateLimited);
+                }
+                // Sessions are recorded under the key they are metered against.
+                if let Some(metering) = caller.metering {
+                    usage::remember(req, caller.key_id.unwrap_or(metering.quota_key_id));
+                }
+                keys::remember_expiry(req, caller.expires_at);
+                if let Some(quota) = &caller.quota {
+                    quota::remember(req, quota);
+                    if quota.exceeded() {
+                        return reject(req, AuthError::QuotaExceeded);
+                    }
+                }
+                if let Some(metering) = caller.metering {
+                    usage::meter(req, caller.key_id, metering.quota_key_id);
+                }
+                Outcome::Success(caller)
+            }
+            Err(AuthError::DatabaseError) => reject(req, AuthError::DatabaseError),
+            Err(error) => {
+                if ratelimit::limit_ip(req).exceeded() {
+                    return reject(req, AuthError::RateLimited);
+                }
+                reject(req, error)
+            }
         }
-    });
+    }
+}
 
+// Remembers why authentication failed so the catchers can say so.
+fn reject<T>(req: &Request<'_>, error: AuthError) -> request::Outcome<T, AuthError> {
+    req.local_cache(|| Some(error));
+    Outcome::Error((error.status(), error))
+}
+
+// Key verification.
+async fn is_api_key_valid(key: &str, plans: &Plans) -> Result<Caller, AuthError> {
+    let client = db::connect().await.map_err(|_| AuthError::DatabaseError)?;
+
+    // Keys are looked up by their non-secret prefix and compared against the stored hash.
+    // Uses from an earlier month do not count against the current quota period. The quota of a
+    // rotated key is that of the newest key of its rotation chain.
     let query =
-        "INSERT INTO tickets (event_name, event_location, event_date, status, holder_name, holder_email, notes, terms_and_conditions, key_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id".to_string();
-    let row = client.query_one(
-        &query,
-        &[
-            &event_name,
-            &event_location,
-            &event_date,
-            &status,
-            &holder_name,
-            &holder_email,
-            &notes,
-            &terms_and_conditions,
-            &key_id,
-        ]
-    ).await?;
-
-    let generated_id: i64 = row.get(0);
-
-    Ok(generated_id)
+        "SELECT k.id, k.user_id, k.plan, k.monthly_quota, CASE WHEN q.period_start >= date_trunc('month', NOW()) THEN q.period_uses ELSE 0 END, date_trunc('month', NOW()), date_trunc('month', NOW()) + INTERVAL '1 month', k.key_salt, k.key_hash, k.active, k.scopes, k.expires_at, k.expires_at <= NOW(), k.organization_id, (SELECT m.role FROM organization_members m WHERE m.organization_id = k.organization_id AND m.user_id = k.user_id), COALESCE(k.lineage_id, k.id), q.id FROM keys k CROSS JOIN LATERAL (SELECT l.id, l.period_uses, l.period_start FROM keys l WHERE COALESCE(l.lineage_id, l.id) = COALESCE(k.lineage_id, k.id) ORDER BY l.id DESC LIMIT 1) q WHERE k.key_prefix = $1";
+    let rows = client
+        .query(query, &[&keys::prefix(key)]).await
+        .map_err(|_| AuthError::DatabaseError)?;
+
+    let row = rows
+        .iter()
+        .find(|row| keys::verify(key, row.get(7), row.get(8)))
+        .ok_or(AuthError::Invalid)?;
+
+    let active: bool = row.get(9);
+    if !active {
+        return Err(AuthError::Inactive);
+    }
+
+    let expired: Option<bool> = row.get(12);
+    if expired == Some(true) {
+        return Err(AuthError::Expired);
+    }
+
+    let plan: String = row.get(2);
+    let monthly_quota: Option<i64> = row.get(3);
+    let period_uses: i32 = row.get(4);
+
+    let user_id: Uuid = row.get(1);
+    let mut scopes: Vec<String> = row.get(10);
+
+    // Keys bound to an organization act with their user's current role in it.
+    let organization_id: Option<Uuid> = row.get(13);
+    let role = match organization_id {
+        Some(_) => {
+            let role: Option<&str> = row.get(14);
+            let role = role.and_then(Role::parse).ok_or(AuthError::NotAMember)?;
+            scopes.retain(|scope| role.scopes().contains(&scope.as_str()));
+            Some(role)
+        }
+        None => None,
+    };
+
+    Ok(Caller {
+        key_id: Some(row.get(0)),
+        metering: Some(Metering { lineage_id: row.get(15), quota_key_id: row.get(16) }),
+        user_id,
+        account_id: organization_id.unwrap_or(user_id),
+        role,
+        scopes,
+        expires_at: row.get(11),
+        quota: Some(Quota {
+            limit: monthly_quota.or(plans.get(&plan).monthly_quota),
+            used: period_uses as i64,
+            period_start: row.get(5),
+            resets_at: row.get(6),
+        }),
+        plan,
+    })
+}
+
+// Session verification. Sessions act for their user with the scopes of an owner, or for the
+// organization in X-Organization-Id with the scopes of the user's role, narrowed to the scopes in
+// the token's app_metadata if it has any. They are limited and metered like the user's newest
+// active key and get its plan; users without one get the default plan and no quota.
+async fn is_session_valid(req: &Request<'_>, authorization: &str) -> Result<Caller, AuthError> {
+    let verifier = req.rocket().state::<JwtVerifier>().expect("JwtVerifier must be managed");
+    let plans = req.rocket().state::<Plans>().expect("Plans must be managed");
+
+    let token = authorization.strip_prefix("Bearer ").ok_or(AuthError::InvalidToken)?;
+    let claims = verifier.verify(token.trim()).map_err(|e| match e {
+        TokenError::Expired => AuthError::TokenExpired,
+        TokenError::Invalid | TokenError::Unsupported => AuthError::InvalidToken,
+    })?;
+
+    let client = db::connect().await.map_err(|_| AuthError::DatabaseError)?;
+
+    let (account_id, role, role_scopes) = match req.headers().get_one("x-organization-id") {
+        Some(organization_id) => {
+            let organization_id = Uuid::parse_str(organization_id).map_err(|_| AuthError::NotAMember)?;
+            let role = orgs
+                ::role_of(&client, organization_id, claims.sub).await
+                .map_err(|_| AuthError::DatabaseError)?
+                .ok_or(AuthError::NotAMember)?;
+            (organization_id, Some(role), role.scopes())
+        }
+        None => (claims.sub, None, Role::Owner.scopes()),
+    };
+    let scopes = role_scopes
+        .iter()
+        .filter(|scope| claims.app_metadata.scopes.as_ref().is_none_or(|granted| granted.iter().any(|g| g == *scope)))
+        .map(|scope| scope.to_string())
+        .collect();
+
+    let query =
+        "SELECT k.plan, k.monthly_quota, CASE WHEN q.period_start >= date_trunc('month', NOW()) THEN q.period_uses ELSE 0 END, date_trunc('month', NOW()), date_trunc('month', NOW()) + INTERVAL '1 month', COALESCE(k.lineage_id, k.id), q.id FROM keys k CROSS JOIN LATERAL (SELECT l.id, l.period_uses, l.period_start FROM keys l WHERE COALESCE(l.lineage_id, l.id) = COALESCE(k.lineage_id, k.id) ORDER BY l.id DESC LIMIT 1) q WHERE k.user_id = $1 AND k.active AND (k.expires_at IS NULL OR k.expires_at > NOW()) ORDER BY k.id DESC LIMIT 1";
+    let row = client.query_opt(query, &[&claims.sub]).await.map_err(|_| AuthError::DatabaseError)?;
+
+    let Some(row) = row else {
+        return Ok(Caller {
+            key_id: None,
+            metering: None,
+            user_id: claims.sub,
+            account_id,
+            role,
+            plan: plans::DEFAULT.to_string(),
+            scopes,
+            expires_at: None,
+            quota: None,
+        });
+    };
+
+    let plan: String = row.get(0);
+    let monthly_quota: Option<i64> = row.get(1);
+    let period_uses: i32 = row.get(2);
+
+    Ok(Caller {
+        key_id: None,
+        metering: Some(Metering { lineage_id: row.get(5), quota_key_id: row.get(6) }),
+        user_id: claims.sub,
+        account_id,
+        role,
+        scopes,
+        expires_at: None,
+        quota: Some(Quota {
+            limit: monthly_quota.or(plans.get(&plan).monthly_quota),
+            used: period_uses as i64,
+            period_start: row.get(3),
+            resets_at: row.get(4),
+        }),
+        plan,
+    })
 }
 
 // Routing for ticket API
 #[post("/ticket", format = "application/json", data = "<ticket>")]
-async fn api_create_ticket(key: ApiKey, ticket: Json<Ticket>) -> String {
-    let key_id: i64 = is_api_key_valid(&key.0).await.unwrap();
-    let id: i64 = insert_ticket(ticket, key_id.clone()).await.unwrap();
-    let _ = update_usage(key_id).await;
+async fn api_create_ticket(caller: Scoped<TicketsWrite>, mail: &State<Mail>, ticket: Json<Ticket>) -> Result<String, Status> {
+    let id: i64 = tickets::insert_ticket(&caller, &ticket).await.map_err(|_| Status::InternalServerError)?;
+    metrics::ticket_created();
+    let _ = mail.notify(&caller, id, Template::Created).await;
 
-    format!("Ticket created successfully: {}", id)
+    Ok(format!("Ticket created successfully: {}", id))
     /*
     "id": i64 SEQUENTIAL NOT NULL,
     "event_name": varchar,
@@ -180,334 +535,607 @@ async fn api_create_ticket(key: ApiKey, ticket: Json<Ticket>) -> String {
     */
 }
 
-// Ticket update.
-async fn update_ticket(ticket_id: i64, key_id: i64, ticket: Json<Ticket>) -> Result<(), Error> {
-    let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
+#[put("/ticket/<ticket_id>", format = "application/json", data = "<ticket>")]
+async fn api_update_ticket(
+    ticket_id: i64,
+    caller: Scoped<TicketsWrite>,
+    mail: &State<Mail>,
+    ticket: Json<Ticket>
+) -> Result<String, Status> {
+    let change = tickets::update_ticket(&caller, ticket_id, &ticket).await.map_err(|e| e.status())?;
+    if change.cancelled() {
+        let _ = mail.notify(&caller, ticket_id, Template::Cancelled).await;
+    }
 
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+    Ok(format!("UPDATE TICKET {ticket_id}"))
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+#[get("/ticket/<ticket_id>/verify")]
+async fn api_verify_ticket(ticket_id: i64, caller: Scoped<TicketsVerify>) -> Result<Json<Verification>, Status> {
+    match tickets::verify_ticket(&caller, ticket_id).await {
+        Ok(Some(verification)) => {
+            metrics::ticket_verified(verification.valid);
+            Ok(Json(verification))
         }
-    });
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-    let query = "SELECT key_id FROM tickets WHERE id = $1".to_string();
-    let row = client.query_one(&query, &[&ticket_id]).await?;
+#[get("/verify/<code>")]
+async fn api_verify_code(code: &str, caller: Scoped<TicketsVerify>) -> Result<Json<Verification>, Status> {
+    match tickets::verify_code(&caller, code).await {
+        Ok(Some(verification)) => {
+            metrics::ticket_verified(verification.valid);
+            Ok(Json(verification))
+        }
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-    let stored_key_id: i64 = row.get(0);
+// Scan at a door: records the entry or exit if the ticket and its entry rules allow it.
+#[post("/verify/<code>", format = "application/json", data = "<request>")]
+async fn api_scan_code(code: &str, caller: Scoped<TicketsVerify>, request: Json<ScanRequest>) -> Result<Json<Verification>, Status> {
+    match scans::record_scan(&caller, code, &request).await {
+        Ok(Some(verification)) => {
+            metrics::ticket_verified(verification.valid);
+            Ok(Json(verification))
+        }
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-    if stored_key_id == key_id {
-        async fn update_event_name(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[get("/ticket/<ticket_id>/history")]
+async fn api_get_ticket_history(ticket_id: i64, caller: Scoped<TicketsRead>) -> Result<Json<Vec<HistoryEntry>>, Status> {
+    match tickets::get_history(&caller, ticket_id).await {
+        Ok(Some(history)) => Ok(Json(history)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
+// Ticket transfers to a new holder.
+#[post("/ticket/<ticket_id>/transfer", format = "application/json", data = "<request>")]
+async fn api_transfer_ticket(
+    ticket_id: i64,
+    caller: Scoped<TicketsWrite>,
+    mail: &State<Mail>,
+    request: Json<TransferRequest>
+) -> Result<(Status, Json<Transfer>), Status> {
+    if request.to_name.trim().is_empty() || !request.to_email.contains('@') {
+        return Err(Status::BadRequest);
+    }
 
-            let event_name = ticket.event_name.clone().unwrap_or_else(|| "".to_string());
-            if !event_name.is_empty() {
-                let update_event_name_query =
-                    "UPDATE tickets SET event_name = $1 WHERE id = $2".to_string();
-                let _ = client.query(&update_event_name_query, &[&event_name, &ticket_id]).await?;
-            }
+    transfers::initiate(&caller, mail, ticket_id, &request).await
+        .map(|transfer| (Status::Created, Json(transfer)))
+        .map_err(|e| e.status())
+}
+
+// Acceptance by the recipient, who has no API key and proves they received the offer with the
+// token emailed to them.
+#[post("/transfers/accept", format = "application/json", data = "<request>")]
+async fn api_accept_transfer(_limit: IpRateLimit, mail: &State<Mail>, request: Json<AcceptRequest>) -> Result<Json<Transfer>, Status> {
+    transfers::accept(mail, request.token.trim()).await
+        .map(Json)
+        .map_err(|e| e.status())
+}
+
+#[post("/transfers/<transfer_id>/cancel")]
+async fn api_cancel_transfer(transfer_id: i64, caller: Scoped<TicketsWrite>) -> Result<Json<Transfer>, Status> {
+    transfers::cancel(&caller, transfer_id).await
+        .map(Json)
+        .map_err(|e| e.status())
+}
 
-            Ok(())
+#[get("/ticket/<ticket_id>/pdf")]
+async fn api_get_ticket_pdf(ticket_id: i64, caller: Scoped<TicketsRead>) -> Result<PdfFile, Status> {
+    let ticket = match tickets::get_ticket(&caller, ticket_id).await {
+        Ok(Some(ticket)) => ticket,
+        Ok(None) => {
+            return Err(Status::NotFound);
+        }
+        Err(_) => {
+            return Err(Status::InternalServerError);
         }
+    };
+    let (template, logo) = branding::get_template(&caller).await.map_err(|_| Status::InternalServerError)?;
 
-        async fn update_event_location(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+    Ok(PdfFile {
+        inner: pdf::render_ticket(&ticket, &template, logo.as_ref()),
+        disposition: Header::new("Content-Disposition", format!("inline; filename=\"ticket-{}.pdf\"", ticket_id)),
+    })
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
-
-            let event_location = ticket.event_location.clone().unwrap_or_else(|| "".to_string());
-            if !event_location.is_empty() {
-                let update_event_location_query =
-                    "UPDATE tickets SET event_location = $1 WHERE id = $2".to_string();
-                let _ = client.query(
-                    &update_event_location_query,
-                    &[&event_location, &ticket_id]
-                ).await?;
-            }
+#[get("/ticket/<ticket_id>/calendar.ics")]
+async fn api_get_ticket_calendar(ticket_id: i64, caller: Scoped<TicketsRead>) -> Result<CalendarFile, Status> {
+    let ticket = match tickets::get_ticket(&caller, ticket_id).await {
+        Ok(Some(ticket)) => ticket,
+        Ok(None) => {
+            return Err(Status::NotFound);
+        }
+        Err(_) => {
+            return Err(Status::InternalServerError);
+        }
+    };
+    let cancelled = ticket.status.as_deref() == Some("Cancelled");
+
+    match calendar::event(&ticket, cancelled) {
+        Some(event) =>
+            Ok(CalendarFile {
+                inner: event,
+                disposition: Header::new("Content-Disposition", format!("attachment; filename=\"ticket-{}.ics\"", ticket_id)),
+            }),
+        None => Err(Status::NotFound),
+    }
+}
 
-            Ok(())
+#[get("/ticket/<ticket_id>/pass.pkpass")]
+async fn api_get_ticket_pass(
+    ticket_id: i64,
+    caller: Scoped<TicketsRead>,
+    signer: &State<Option<PassSigner>>
+) -> Result<PassFile, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    // Checks access to the ticket, the pass itself is built from the stored ticket.
+    match tickets::get_ticket(&caller, ticket_id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            return Err(Status::NotFound);
+        }
+        Err(_) => {
+            return Err(Status::InternalServerError);
         }
+    }
+    let Some(stored) = tickets::find_ticket(ticket_id).await.map_err(|_| Status::InternalServerError)? else {
+        return Err(Status::NotFound);
+    };
 
-        async fn update_event_date(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+    match passes::pass_for(signer, ticket_id, stored.code_version).await? {
+        Some((pass, updated_at)) => Ok(PassFile::new(pass, updated_at)),
+        None => Err(Status::NotFound),
+    }
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
+// Apple Wallet web service, called by devices holding a pass. Passes authenticate with their
+// ApplePass token; only the list of updated passes is unauthenticated, as the protocol defines.
+#[post("/v1/devices/<device_id>/registrations/<pass_type>/<serial>", format = "application/json", data = "<registration>")]
+async fn wallet_register(
+    device_id: &str,
+    pass_type: &str,
+    serial: &str,
+    token: ApplePass,
+    registration: Json<Registration>,
+    signer: &State<Option<PassSigner>>
+) -> Result<Status, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    if pass_type != signer.type_identifier {
+        return Err(Status::NotFound);
+    }
+    let (ticket_id, _) = token.authorize(serial)?;
 
-            let event_date = ticket.event_date.clone().unwrap_or_else(|| "".to_string());
-            if !event_date.is_empty() {
-                let update_event_date_query =
-                    "UPDATE tickets SET event_date = $1 WHERE id = $2".to_string();
-                let _ = client.query(&update_event_date_query, &[&event_date, &ticket_id]).await?;
-            }
+    match passes::register(device_id, pass_type, serial, ticket_id, &registration.push_token).await {
+        Ok(true) => Ok(Status::Created),
+        Ok(false) => Ok(Status::Ok),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            Ok(())
-        }
+#[delete("/v1/devices/<device_id>/registrations/<pass_type>/<serial>")]
+async fn wallet_unregister(
+    device_id: &str,
+    pass_type: &str,
+    serial: &str,
+    token: ApplePass,
+    signer: &State<Option<PassSigner>>
+) -> Result<Status, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    if pass_type != signer.type_identifier {
+        return Err(Status::NotFound);
+    }
+    token.authorize(serial)?;
 
-        async fn update_status(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+    passes::unregister(device_id, pass_type, serial).await
+        .map(|_| Status::Ok)
+        .map_err(|_| Status::InternalServerError)
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
+#[allow(non_snake_case)]
+#[get("/v1/devices/<device_id>/registrations/<pass_type>?<passesUpdatedSince>")]
+async fn wallet_updated_passes(
+    device_id: &str,
+    pass_type: &str,
+    passesUpdatedSince: Option<i64>,
+    signer: &State<Option<PassSigner>>
+) -> Result<UpdatedSerials, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    if pass_type != signer.type_identifier {
+        return Err(Status::NotFound);
+    }
 
-            let status = ticket.status.clone().unwrap_or_else(|| "".to_string());
-            if !status.is_empty() {
-                let update_status_query =
-                    "UPDATE tickets SET status = $1 WHERE id = $2".to_string();
-                let _ = client.query(&update_status_query, &[&status, &ticket_id]).await?;
-            }
+    match passes::updated_since(device_id, pass_type, passesUpdatedSince).await {
+        Ok(Some(updated)) => Ok(UpdatedSerials::Updated(Json(updated))),
+        Ok(None) => Ok(UpdatedSerials::NoUpdates(())),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            Ok(())
+#[get("/v1/passes/<pass_type>/<serial>")]
+async fn wallet_get_pass(
+    pass_type: &str,
+    serial: &str,
+    token: ApplePass,
+    since: IfModifiedSince,
+    signer: &State<Option<PassSigner>>
+) -> Result<WalletPass, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    if pass_type != signer.type_identifier {
+        return Err(Status::NotFound);
+    }
+    let (ticket_id, version) = token.authorize(serial)?;
+
+    match passes::pass_for(signer, ticket_id, version).await? {
+        Some((_, updated_at)) if since.0.is_some_and(|since| updated_at.timestamp() <= since.timestamp()) => {
+            Ok(WalletPass::NotModified(()))
         }
+        Some((pass, updated_at)) => Ok(WalletPass::Updated(PassFile::new(pass, updated_at))),
+        None => Err(Status::NotFound),
+    }
+}
 
-        async fn update_holder_name(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[post("/v1/log", format = "application/json", data = "<logs>")]
+fn wallet_log(_limit: IpRateLimit, logs: Json<Logs>) -> Status {
+    logs.write();
+    Status::Ok
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
+// Look of rendered tickets for the caller's account.
+#[get("/ticket-template")]
+async fn api_get_ticket_template(caller: Scoped<TicketsRead>) -> Result<Json<TicketTemplate>, Status> {
+    branding::get_template(&caller).await
+        .map(|(template, _)| Json(template))
+        .map_err(|_| Status::InternalServerError)
+}
 
-            let holder_name = ticket.holder_name.clone().unwrap_or_else(|| "".to_string());
-            if !holder_name.is_empty() {
-                let update_holder_name_query =
-                    "UPDATE tickets SET holder_name = $1 WHERE id = $2".to_string();
-                let _ = client.query(&update_holder_name_query, &[&holder_name, &ticket_id]).await?;
-            }
+#[put("/ticket-template", format = "application/json", data = "<template>")]
+async fn api_set_ticket_template(caller: Scoped<TicketsWrite>, template: Json<TicketTemplate>) -> Result<Json<TicketTemplate>, Status> {
+    if branding::parse_color(&template.primary_color).is_none() || branding::parse_color(&template.text_color).is_none() {
+        return Err(Status::BadRequest);
+    }
 
-            Ok(())
-        }
+    branding::set_colors(&caller, &template).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-        async fn update_holder_email(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[put("/ticket-template/logo", format = "image/jpeg", data = "<data>")]
+async fn api_set_ticket_logo(caller: Scoped<TicketsWrite>, data: Data<'_>) -> Result<Json<TicketTemplate>, Status> {
+    let data = data
+        .open(branding::MAX_LOGO_SIZE.bytes())
+        .into_bytes().await
+        .map_err(|_| Status::BadRequest)?;
+    if !data.is_complete() {
+        return Err(Status::PayloadTooLarge);
+    }
+    let logo = Logo::from_jpeg(data.into_inner()).ok_or(Status::BadRequest)?;
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
-
-            let holder_email = ticket.holder_email.clone().unwrap_or_else(|| "".to_string());
-            if !holder_email.is_empty() {
-                let update_holder_email_query =
-                    "UPDATE tickets SET holder_email = $1 WHERE id = $2".to_string();
-                let _ = client.query(
-                    &update_holder_email_query,
-                    &[&holder_email, &ticket_id]
-                ).await?;
-            }
+    branding::set_logo(&caller, Some(&logo)).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-            Ok(())
-        }
+#[delete("/ticket-template/logo")]
+async fn api_delete_ticket_logo(caller: Scoped<TicketsWrite>) -> Result<Json<TicketTemplate>, Status> {
+    branding::set_logo(&caller, None).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-        async fn update_notes(ticket: &Json<Ticket>, ticket_id: i64) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+// Ticket emails to the holder.
+#[post("/ticket/<ticket_id>/email")]
+async fn api_resend_ticket_email(
+    ticket_id: i64,
+    caller: Scoped<TicketsWrite>,
+    mail: &State<Mail>
+) -> Result<(Status, Json<Delivery>), Status> {
+    match mail.notify(&caller, ticket_id, Template::Created).await {
+        Ok(Some(delivery)) => Ok((Status::Accepted, Json(delivery))),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
+#[get("/ticket/<ticket_id>/emails")]
+async fn api_list_ticket_emails(ticket_id: i64, caller: Scoped<TicketsRead>) -> Result<Json<Vec<Delivery>>, Status> {
+    match mail::list_deliveries(&caller, ticket_id).await {
+        Ok(Some(deliveries)) => Ok(Json(deliveries)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            let notes = ticket.notes.clone().unwrap_or_else(|| "".to_string());
-            if !notes.is_empty() {
-                let update_notes_query = "UPDATE tickets SET notes = $1 WHERE id = $2".to_string();
-                let _ = client.query(&update_notes_query, &[&notes, &ticket_id]).await?;
-            }
+// Background jobs of the caller's account, for polling the work queued by 202 responses.
+#[get("/jobs/<job_id>")]
+async fn api_get_job(job_id: i64, caller: Scoped<TicketsRead>) -> Result<Json<JobStatus>, Status> {
+    match jobs::get_job(&caller, job_id).await {
+        Ok(Some(job)) => Ok(Json(job)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-            Ok(())
-        }
+// Per-event policies.
+#[get("/events/<event_name>/policy")]
+async fn api_get_event_policy(event_name: &str, caller: Scoped<TicketsRead>) -> Result<Json<EventPolicy>, Status> {
+    events::get_policy(&caller, event_name).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-        async fn update_terms_and_conditions(
-            ticket: &Json<Ticket>,
-            ticket_id: i64
-        ) -> Result<(), Error> {
-            let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-            let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[put("/events/<event_name>/policy", format = "application/json", data = "<policy>")]
+async fn api_set_event_policy(
+    event_name: &str,
+    caller: Scoped<TicketsWrite>,
+    policy: Json<EventPolicy>
+) -> Result<Json<EventPolicy>, Status> {
+    events::set_policy(&caller, event_name, &policy).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = connection.await {
-                    eprintln!("connection error: {}", e);
-                }
-            });
-
-            let terms_and_conditions = ticket.terms_and_conditions
-                .clone()
-                .unwrap_or_else(|| "".to_string());
-            if !terms_and_conditions.is_empty() {
-                let update_terms_and_conditions_query =
-                    "UPDATE tickets SET terms_and_conditions = $1 WHERE id = $2".to_string();
-                let _ = client.query(
-                    &update_terms_and_conditions_query,
-                    &[&terms_and_conditions, &ticket_id]
-                ).await?;
-            }
+// Offline scanning: verification key, manifest download and scan log upload.
+#[get("/scanner/key")]
+async fn api_get_scanner_key(_caller: Scoped<TicketsVerify>, signer: &State<Option<ManifestSigner>>) -> Result<Json<ScannerKey>, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
 
-            Ok(())
-        }
+    Ok(Json(ScannerKey { algorithm: "Ed25519", public_key: signer.public_key() }))
+}
 
-        let update_date_query = "UPDATE tickets SET updated_at = NOW() WHERE id = $1".to_string();
-        let _ = client.query(&update_date_query, &[&ticket_id]).await?;
-        let _ = update_event_name(&ticket, ticket_id).await?;
-        let _ = update_event_location(&ticket, ticket_id).await?;
-        let _ = update_event_date(&ticket, ticket_id).await?;
-        let _ = update_status(&ticket, ticket_id).await?;
-        let _ = update_holder_name(&ticket, ticket_id).await?;
-        let _ = update_holder_email(&ticket, ticket_id).await?;
-        let _ = update_notes(&ticket, ticket_id).await?;
-        let _ = update_terms_and_conditions(&ticket, ticket_id).await?;
+#[get("/events/<event_name>/manifest")]
+async fn api_get_event_manifest(
+    event_name: &str,
+    caller: Scoped<TicketsVerify>,
+    signer: &State<Option<ManifestSigner>>
+) -> Result<SignedManifest, Status> {
+    let signer = signer.as_ref().ok_or(Status::NotImplemented)?;
+    let manifest = scans::get_manifest(&caller, event_name).await.map_err(|_| Status::InternalServerError)?;
+    let body = serde_json::to_vec(&manifest).map_err(|_| Status::InternalServerError)?;
+
+    Ok(SignedManifest {
+        signature: Header::new("X-Manifest-Signature", signer.sign(&body)),
+        inner: body,
+    })
+}
 
-        Ok(())
-    } else {
-        Ok(())
+#[post("/events/<event_name>/scans", format = "application/json", data = "<log>")]
+async fn api_sync_scans(event_name: &str, caller: Scoped<TicketsVerify>, log: Json<ScanLog>) -> Result<Json<ScanReport>, Status> {
+    if log.scanner_id.is_empty() || log.scans.len() > scans::MAX_SCANS {
+        return Err(Status::BadRequest);
     }
+
+    scans::sync(&caller, event_name, &log).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
 }
 
-#[put("/ticket/<ticket_id>", format = "application/json", data = "<ticket>")]
-async fn api_update_ticket(ticket_id: i64, key: ApiKey, ticket: Json<Ticket>) -> String {
-    let key_id: i64 = is_api_key_valid(&key.0).await.unwrap();
-    let _ = update_usage(key_id).await;
+#[get("/ticket/<ticket_id>")]
+async fn api_get_ticket(ticket_id: i64, caller: Scoped<TicketsRead>) -> Result<Json<Ticket>, Status> {
+    let returnable_ticket = tickets::get_ticket(&caller, ticket_id).await;
 
-    let _ = update_ticket(ticket_id, key_id, ticket).await.unwrap();
+    match returnable_ticket {
+        Ok(Some(ticket)) => Ok(Json(ticket)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-    format!("UPDATE TICKET {ticket_id}")
+#[delete("/ticket/<ticket_id>")]
+async fn api_delete_ticket(ticket_id: i64, caller: Scoped<TicketsDelete>, mail: &State<Mail>) -> Result<String, Status> {
+    match tickets::delete_ticket(&caller, mail, ticket_id).await {
+        Ok(true) => Ok(format!("Successfully deleted ticket {:?}", ticket_id)),
+        Ok(false) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
 }
 
-// TODO: TICKET VERIFICATION
-async fn get_ticket(ticket_id: i64, key_id: i64) -> Result<Json<Ticket>, Error> {
-    let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[get("/quota")]
+fn api_get_quota(caller: Scoped<UsageRead>) -> Result<Json<QuotaResponse>, Status> {
+    // Sessions of users without an active key have no quota.
+    match &caller.quota {
+        Some(quota) => Ok(Json(QuotaResponse::new(&caller.plan, quota))),
+        None => Err(Status::NotFound),
+    }
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
-
-    let query = "SELECT key_id FROM tickets WHERE id = $1".to_string();
-    let row = client.query_one(&query, &[&ticket_id]).await?;
-
-    let stored_id: i64 = row.get(0);
-
-    if key_id == stored_id {
-        let select_query =
-            "SELECT status, event_name, event_location, event_date, holder_name, holder_email, notes, terms_and_conditions FROM tickets WHERE id = $1".to_string();
-        let row = client.query_one(&select_query, &[&ticket_id]).await?;
-
-        let status: String = row.get(0);
-        let event_name: String = row.get(1);
-        let event_location: String = row.get(2);
-        let event_date: String = row.get(3);
-        let holder_name: String = row.get(4);
-        let holder_email: String = row.get(5);
-        let notes: String = row.get(6);
-        let terms_and_conditions: String = row.get(7);
-
-        let ticket = Ticket {
-            id: Some(ticket_id),
-            event_name: Some(event_name),
-            event_location: Some(event_location),
-            event_date: Some(event_date),
-            status: Some(status),
-            holder_name: Some(holder_name),
-            holder_email: Some(holder_email),
-            notes: Some(notes),
-            terms_and_conditions: Some(terms_and_conditions),
-        };
+#[get("/usage?<from>&<to>&<interval>&<key_id>")]
+async fn api_get_usage(
+    caller: Scoped<UsageRead>,
+    from: Option<&str>,
+    to: Option<&str>,
+    interval: Option<&str>,
+    key_id: Option<i64>
+) -> Result<Json<UsageReport>, Status> {
+    let parse = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| Status::BadRequest);
+
+    let to = match to {
+        Some(to) => parse(to)?,
+        None => Utc::now().date_naive(),
+    };
+    let from = match from {
+        Some(from) => parse(from)?,
+        None => to - Duration::days(30),
+    };
+    let interval = interval.unwrap_or("day");
+
+    if from > to || !["day", "week", "month"].contains(&interval) {
+        return Err(Status::BadRequest);
+    }
 
-        Ok(Json(ticket))
-    } else {
-        let ticket = Ticket {
-            id: None,
-            event_name: None,
-            event_location: None,
-            event_date: None,
-            status: None,
-            holder_name: None,
-            holder_email: None,
-            notes: None,
-            terms_and_conditions: None,
-        };
-        Ok(Json(ticket))
+    usage::get_usage(caller.user_id, key_id, from, to, interval).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+// API key management for the owner of the calling key.
+#[post("/keys", format = "application/json", data = "<request>")]
+async fn api_create_key(caller: Scoped<KeysManage>, request: Json<KeyRequest>) -> Result<(Status, Json<CreatedKey>), Status> {
+    // New keys get the plan and organization of the caller that created them and at most its scopes.
+    let scopes = request.scopes.clone().unwrap_or_else(|| caller.scopes.clone());
+    if scopes.iter().any(|scope| !scopes::is_known(scope) || !caller.scopes.contains(scope)) {
+        return Err(Status::BadRequest);
     }
+
+    keys::create_key(caller.user_id, caller.organization_id(), &request.name, &caller.plan, &scopes).await
+        .map(|created| (Status::Created, Json(created)))
+        .map_err(|_| Status::InternalServerError)
 }
 
-#[get("/ticket/<ticket_id>")]
-async fn api_get_ticket(ticket_id: i64, key: ApiKey) -> Json<Ticket> {
-    let key_id: i64 = is_api_key_valid(&key.0).await.unwrap();
-    let returnable_ticket = get_ticket(ticket_id, key_id).await.unwrap();
-    let _ = update_usage(key_id).await;
+#[get("/keys")]
+async fn api_list_keys(caller: Scoped<KeysManage>) -> Result<Json<Vec<KeySummary>>, Status> {
+    keys::list_keys(caller.user_id).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-    returnable_ticket
+#[patch("/keys/<key_id>", format = "application/json", data = "<request>")]
+async fn api_rename_key(key_id: i64, caller: Scoped<KeysManage>, request: Json<KeyRequest>) -> Result<Json<KeySummary>, Status> {
+    match keys::rename_key(caller.user_id, key_id, &request.name).await {
+        Ok(Some(summary)) => Ok(Json(summary)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
 }
 
-// Ticket deletion.
-async fn delete_ticket(ticket_id: i64, key_id: i64) -> Result<(), Error> {
-    let database_url = env::var("SUPABASE_URI").expect("SUPABASE_URI must be set");
-    let (client, connection) = tokio_postgres::connect(&database_url, NoTls).await?;
+#[post("/keys/<key_id>/deactivate")]
+async fn api_deactivate_key(key_id: i64, caller: Scoped<KeysManage>) -> Result<Json<KeySummary>, Status> {
+    match keys::deactivate_key(caller.user_id, key_id).await {
+        Ok(Some(summary)) => Ok(Json(summary)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
 
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
+#[post("/keys/<key_id>/rotate", data = "<request>")]
+async fn api_rotate_key(
+    key_id: i64,
+    caller: Scoped<KeysManage>,
+    request: Option<Json<RotateRequest>>
+) -> Result<(Status, Json<RotatedKey>), Status> {
+    let grace_period = keys
+        ::grace_period(request.and_then(|request| request.grace_period))
+        .ok_or(Status::BadRequest)?;
+
+    keys::rotate_key(caller.user_id, caller.organization_id(), &caller.scopes, key_id, grace_period).await
+        .map(|rotated| (Status::Created, Json(rotated)))
+        .map_err(|e| e.status())
+}
+
+#[delete("/keys/<key_id>")]
+async fn api_delete_key(key_id: i64, caller: Scoped<KeysManage>) -> Status {
+    match keys::delete_key(caller.user_id, key_id).await {
+        Ok(true) => Status::NoContent,
+        Ok(false) => Status::NotFound,
+        Err(_) => Status::InternalServerError,
+    }
+}
+
+// Organizations of the calling user.
+#[post("/organizations", format = "application/json", data = "<request>")]
+async fn api_create_organization(caller: Scoped<MembersManage>, request: Json<OrganizationRequest>) -> Result<(Status, Json<Organization>), Status> {
+    if request.name.trim().is_empty() {
+        return Err(Status::BadRequest);
+    }
 
-    let query = "SELECT key_id FROM tickets WHERE id = $1".to_string();
-    let row = client.query_one(&query, &[&ticket_id]).await?;
+    orgs::create_organization(caller.user_id, request.name.trim()).await
+        .map(|organization| (Status::Created, Json(organization)))
+        .map_err(|_| Status::InternalServerError)
+}
 
-    let stored_key_id: i64 = row.get(0);
+#[get("/organizations")]
+async fn api_list_organizations(caller: Scoped<MembersRead>) -> Result<Json<Vec<Organization>>, Status> {
+    orgs::list_organizations(caller.user_id).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
 
-    if stored_key_id == key_id {
-        let delete_query = "DELETE from tickets WHERE id = $1".to_string();
-        let _ = client.query_one(&delete_query, &[&ticket_id]).await?;
-        Ok(())
-    } else {
-        Ok(())
+// Members are managed by owners and admins acting for the organization, with members:manage.
+fn member_manager(caller: &Caller, organization_id: Uuid) -> Result<Role, Status> {
+    match caller.role {
+        Some(role) if caller.account_id == organization_id && role.manages_members() => Ok(role),
+        Some(_) if caller.account_id == organization_id => Err(Status::Forbidden),
+        _ => Err(Status::NotFound),
     }
 }
 
-#[delete("/ticket/<ticket_id>")]
-async fn api_delete_ticket(ticket_id: i64, key: ApiKey) -> String {
-    let key_id: i64 = is_api_key_valid(&key.0).await.unwrap();
-    let _ = update_usage(key_id).await;
+#[get("/organizations/<organization_id>/members")]
+async fn api_list_members(organization_id: Uuid, caller: Scoped<MembersRead>) -> Result<Json<Vec<Member>>, Status> {
+    if caller.account_id != organization_id {
+        return Err(Status::NotFound);
+    }
 
-    let _ = delete_ticket(ticket_id, key_id).await;
-    format!("Successfully deleted ticket {:?}", ticket_id)
+    orgs::list_members(caller.user_id, organization_id).await
+        .map(Json)
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[put("/organizations/<organization_id>/members", format = "application/json", data = "<request>")]
+async fn api_set_member(organization_id: Uuid, caller: Scoped<MembersManage>, request: Json<MemberRequest>) -> Result<Json<Member>, Status> {
+    let role = member_manager(&caller, organization_id)?;
+
+    orgs::set_member(caller.user_id, role, organization_id, &request).await
+        .map(Json)
+        .map_err(|e| e.status())
+}
+
+#[delete("/organizations/<organization_id>/members/<user_id>")]
+async fn api_remove_member(organization_id: Uuid, user_id: Uuid, caller: Scoped<MembersManage>) -> Status {
+    let role = match member_manager(&caller, organization_id) {
+        Ok(role) => role,
+        Err(status) => {
+            return status;
+        }
+    };
+
+    match orgs::remove_member(caller.user_id, role, organization_id, user_id).await {
+        Ok(()) => Status::NoContent,
+        Err(e) => e.status(),
+    }
 }
 
 #[get("/")]
-fn default_response() -> String {
+fn default_response(_limit: IpRateLimit) -> String {
     "Welcome to TicketAPI.".to_string()
 }
 
+// Liveness probe. Not rate limited, so frequent probes never fail.
+#[get("/health")]
+fn health_probe() -> Json<Health> {
+    Json(health::health())
+}
+
+// Readiness probe: 503 while the database, its migrations or the connection pool are not ready.
+#[get("/ready")]
+async fn readiness_probe() -> (Status, Json<Readiness>) {
+    let readiness = health::readiness().await;
+    let status = if readiness.ready { Status::Ok } else { Status::ServiceUnavailable };
+
+    (status, Json(readiness))
+}
+
+// Prometheus metrics, see metrics.rs.
+#[get("/metrics")]
+fn metrics_endpoint(_access: MetricsAccess) -> MetricsText {
+    MetricsText(metrics::render())
+}
+
 // HTTP Error Handlers and Catchers
 #[catch(400)]
-fn catch_err_400() -> Json<ErrorResponse> {
+fn catch_err_400(req: &Request<'_>) -> Json<ErrorResponse> {
+    if let Some(error) = req.local_cache(|| None::<AuthError>) {
+        return Json(error.response());
+    }
+
     Json(ErrorResponse {
         status: 400,
         error: "Bad Request",
@@ -517,17 +1145,32 @@ fn catch_err_400() -> Json<ErrorResponse> {
 }
 
 #[catch(401)]
-fn catch_err_401() -> Json<ErrorResponse> {
-    Json(ErrorResponse {
-        status: 401,
-        error: "Unauthorized",
-        message: "You must authenticate yourself to get the requested response.",
-        suggestion: "Provide valid authentication credentials.",
-    })
+fn catch_err_401(req: &Request<'_>) -> Unauthorized {
+    let error = req.local_cache(|| None::<AuthError>);
+    let challenge = error.unwrap_or(AuthError::Missing).challenge();
+    let body = match error {
+        Some(error) => error.response(),
+        None =>
+            ErrorResponse {
+                status: 401,
+                error: "Unauthorized",
+                message: "You must authenticate yourself to get the requested response.",
+                suggestion: "Provide valid authentication credentials.",
+            },
+    };
+
+    Unauthorized {
+        inner: Json(body),
+        challenge: Header::new("WWW-Authenticate", challenge),
+    }
 }
 
 #[catch(403)]
-fn catch_err_403() -> Json<ErrorResponse> {
+fn catch_err_403(req: &Request<'_>) -> Json<ErrorResponse> {
+    if let Some(error) = req.local_cache(|| None::<AuthError>) {
+        return Json(error.response());
+    }
+
     Json(ErrorResponse {
         status: 403,
         error: "Forbidden",
@@ -566,8 +1209,32 @@ fn catch_err_408() -> Json<ErrorResponse> {
     })
 }
 
+#[catch(409)]
+fn catch_err_409() -> Json<ErrorResponse> {
+    Json(ErrorResponse {
+        status: 409,
+        error: "Conflict",
+        message: "The request conflicts with the current state of the resource.",
+        suggestion: "Resolve the conflict and try again.",
+    })
+}
+
+#[catch(410)]
+fn catch_err_410() -> Json<ErrorResponse> {
+    Json(ErrorResponse {
+        status: 410,
+        error: "Gone",
+        message: "The requested resource is no longer available.",
+        suggestion: "Request a new one and try again.",
+    })
+}
+
 #[catch(429)]
-fn catch_err_429() -> Json<ErrorResponse> {
+fn catch_err_429(req: &Request<'_>) -> Json<ErrorResponse> {
+    if let Some(error) = req.local_cache(|| None::<AuthError>) {
+        return Json(error.response());
+    }
+
     Json(ErrorResponse {
         status: 429,
         error: "Too Many Requests",
@@ -577,7 +1244,11 @@ fn catch_err_429() -> Json<ErrorResponse> {
 }
 
 #[catch(500)]
-fn catch_err_500() -> Json<ErrorResponse> {
+fn catch_err_500(req: &Request<'_>) -> Json<ErrorResponse> {
+    if let Some(error) = req.local_cache(|| None::<AuthError>) {
+        return Json(error.response());
+    }
+
     Json(ErrorResponse {
         status: 500,
         error: "Internal Server Error",
@@ -619,17 +1290,42 @@ fn catch_err_503() -> Json<ErrorResponse> {
 #[tokio::main]
 async fn main() {
     dotenv().ok();
-    let secret_key = env::var("ROCKET_SECRET_KEY").expect("ROCKET_SECRET_KEY must be set");
+
+    let figment = config::figment();
+    let settings = config::load(&figment);
+    db::init(settings).unwrap_or_else(|e| config::fail(e));
+
+    if env::args().nth(1).as_deref() == Some("hash-keys") {
+        match keys::hash_existing_keys().await {
+            Ok(count) => println!("Hashed {} API keys.", count),
+            Err(e) => eprintln!("Failed to hash API keys: {}", e),
+        }
+        return;
+    }
+
+    let mail = Mail::from_config(settings).unwrap_or_else(|e| config::fail(e));
+    let jwt_verifier = JwtVerifier::from_config(settings).unwrap_or_else(|e| config::fail(e));
+    let pass_signer = PassSigner::from_config(settings).unwrap_or_else(|e| config::fail(e));
+    let manifest_signer = ManifestSigner::from_config(settings).unwrap_or_else(|e| config::fail(e));
+    let pusher = pass_signer.as_ref().map(Pusher::new).transpose().unwrap_or_else(|e| config::fail(e));
+
+    expiry::start();
+    jobs::start(mail.mailer(), pusher);
 
     let _ = rocket
-        ::build()
-        .configure(
-            rocket::Config
-                ::figment()
-                .merge(("port", 8000))
-                .merge(("address", "0.0.0.0"))
-                .merge(("secret_key", secret_key))
-        )
+        ::custom(figment)
+        .manage(Plans::from_config(&settings.plans))
+        .manage(RateLimiter::start())
+        .manage(jwt_verifier)
+        .manage(UsageRecorder::start())
+        .manage(mail)
+        .manage(pass_signer)
+        .manage(manifest_signer)
+        .attach(RateLimitHeaders)
+        .attach(QuotaHeaders)
+        .attach(UsageTracking)
+        .attach(KeyDeprecationHeaders)
+        .attach(RequestMetrics)
         .register(
             "/",
             catchers![
@@ -639,6 +1335,8 @@ async fn main() {
                 catch_err_404,
                 catch_err_405,
                 catch_err_408,
+                catch_err_409,
+                catch_err_410,
                 catch_err_429,
                 catch_err_500,
                 catch_err_501,
@@ -648,8 +1346,49 @@ async fn main() {
         )
         .mount(
             "/beta/1/",
-            routes![api_create_ticket, api_get_ticket, api_delete_ticket, api_update_ticket]
+            routes![
+                api_create_ticket,
+                api_get_ticket,
+                api_delete_ticket,
+                api_update_ticket,
+                api_verify_ticket,
+                api_verify_code,
+                api_scan_code,
+                api_get_ticket_history,
+                api_transfer_ticket,
+                api_accept_transfer,
+                api_cancel_transfer,
+                api_get_ticket_pdf,
+                api_get_ticket_pass,
+                api_get_ticket_calendar,
+                api_get_ticket_template,
+                api_set_ticket_template,
+                api_set_ticket_logo,
+                api_delete_ticket_logo,
+                api_resend_ticket_email,
+                api_list_ticket_emails,
+                api_get_job,
+                api_get_event_policy,
+                api_set_event_policy,
+                api_get_event_manifest,
+                api_sync_scans,
+                api_get_scanner_key,
+                api_get_quota,
+                api_get_usage,
+                api_create_key,
+                api_list_keys,
+                api_rename_key,
+                api_deactivate_key,
+                api_rotate_key,
+                api_delete_key,
+                api_create_organization,
+                api_list_organizations,
+                api_list_members,
+                api_set_member,
+                api_remove_member
+            ]
         )
-        .mount("/", routes![default_response])
+        .mount("/beta/1/wallet/", routes![wallet_register, wallet_unregister, wallet_updated_passes, wallet_get_pass, wallet_log])
+        .mount("/", routes![default_response, health_probe, readiness_probe, metrics_endpoint])
         .launch().await;
 }
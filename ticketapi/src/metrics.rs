@@ -0,0 +1,277 @@
+// Prometheus metrics, served in the text exposition format at GET /metrics. Values are kept in
+// memory per instance and start from zero on restart; Prometheus scrapes every instance and
+// aggregates. If metrics_token is set, scrapes must send "Authorization: Bearer <metrics_token>".
+//
+// Database latency is measured per pooled connection: the time a request or task waited for a
+// connection and the time it held it. The hold time covers the queries of that unit of work and
+// anything else done while holding the connection, e.g. the pushes of a Wallet push job.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::{ Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::Status;
+use rocket::request::{ self, FromRequest, Outcome };
+use rocket::{ Data, Request, Response };
+use sha2::{ Digest, Sha256 };
+
+use crate::{ config, db };
+
+// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    // Observations per bucket, not cumulative; the last entry counts those above every bound.
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    // Appends the series of the histogram. Labels are given without braces, e.g. `route="/"`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let labels = if labels.is_empty() { String::new() } else { format!("{{{}}}", labels) };
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct RequestLabels {
+    route: String,
+    method: String,
+    status: u16,
+}
+
+#[derive(Default)]
+struct Metrics {
+    requests: Mutex<HashMap<RequestLabels, Histogram>>,
+    connection_wait: Mutex<Histogram>,
+    connection_hold: Mutex<Histogram>,
+    // Finished job attempts by kind and outcome.
+    jobs: Mutex<HashMap<(String, &'static str), u64>>,
+    tickets_created: AtomicU64,
+    tickets_verified_valid: AtomicU64,
+    tickets_verified_invalid: AtomicU64,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+pub fn observe_connection_wait(duration: Duration) {
+    metrics().connection_wait.lock().unwrap().observe(duration);
+}
+
+pub fn observe_connection_hold(duration: Duration) {
+    metrics().connection_hold.lock().unwrap().observe(duration);
+}
+
+// Counts a finished job attempt; outcome is succeeded, retried or dead.
+pub fn job_finished(kind: &str, outcome: &'static str) {
+    *metrics().jobs.lock().unwrap().entry((kind.to_string(), outcome)).or_insert(0) += 1;
+}
+
+pub fn ticket_created() {
+    metrics().tickets_created.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticket_verified(valid: bool) {
+    let counter = if valid { &metrics().tickets_verified_valid } else { &metrics().tickets_verified_invalid };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// Escapes a label value.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub fn render() -> String {
+    let metrics = metrics();
+    let mut out = String::new();
+
+    let mut requests: Vec<(String, u64)> = Vec::new();
+    let mut durations = String::new();
+    {
+        let histograms = metrics.requests.lock().unwrap();
+        let mut keys: Vec<&RequestLabels> = histograms.keys().collect();
+        keys.sort();
+        for key in keys {
+            let labels = format!("route=\"{}\",method=\"{}\",status=\"{}\"", label(&key.route), key.method, key.status);
+            histograms[key].render(&mut durations, "ticketapi_http_request_duration_seconds", &labels);
+            requests.push((labels, histograms[key].count));
+        }
+    }
+
+    out.push_str("# HELP ticketapi_http_requests_total Requests answered, by route, method and status.\n");
+    out.push_str("# TYPE ticketapi_http_requests_total counter\n");
+    for (labels, count) in &requests {
+        let _ = writeln!(out, "ticketapi_http_requests_total{{{}}} {}", labels, count);
+    }
+    out.push_str("# HELP ticketapi_http_request_duration_seconds Time to answer a request, by route, method and status.\n");
+    out.push_str("# TYPE ticketapi_http_request_duration_seconds histogram\n");
+    out.push_str(&durations);
+
+    out.push_str("# HELP ticketapi_db_connection_wait_seconds Time spent waiting for a pooled database connection.\n");
+    out.push_str("# TYPE ticketapi_db_connection_wait_seconds histogram\n");
+    metrics.connection_wait.lock().unwrap().render(&mut out, "ticketapi_db_connection_wait_seconds", "");
+    out.push_str("# HELP ticketapi_db_connection_hold_seconds Time a request or task held a pooled database connection.\n");
+    out.push_str("# TYPE ticketapi_db_connection_hold_seconds histogram\n");
+    metrics.connection_hold.lock().unwrap().render(&mut out, "ticketapi_db_connection_hold_seconds", "");
+
+    let pool = db::pool_status();
+    out.push_str("# HELP ticketapi_db_pool_size Most database connections open at a time.\n");
+    out.push_str("# TYPE ticketapi_db_pool_size gauge\n");
+    let _ = writeln!(out, "ticketapi_db_pool_size {}", pool.size);
+    out.push_str("# HELP ticketapi_db_pool_connections Database connections of the pool, by state.\n");
+    out.push_str("# TYPE ticketapi_db_pool_connections gauge\n");
+    let _ = writeln!(out, "ticketapi_db_pool_connections{{state=\"in_use\"}} {}", pool.in_use);
+    let _ = writeln!(out, "ticketapi_db_pool_connections{{state=\"idle\"}} {}", pool.idle);
+
+    out.push_str("# HELP ticketapi_tickets_created_total Tickets created.\n");
+    out.push_str("# TYPE ticketapi_tickets_created_total counter\n");
+    let _ = writeln!(out, "ticketapi_tickets_created_total {}", metrics.tickets_created.load(Ordering::Relaxed));
+    out.push_str("# HELP ticketapi_tickets_verified_total Ticket verifications and door scans, by result.\n");
+    out.push_str("# TYPE ticketapi_tickets_verified_total counter\n");
+    let valid = metrics.tickets_verified_valid.load(Ordering::Relaxed);
+    let invalid = metrics.tickets_verified_invalid.load(Ordering::Relaxed);
+    let _ = writeln!(out, "ticketapi_tickets_verified_total{{result=\"valid\"}} {}", valid);
+    let _ = writeln!(out, "ticketapi_tickets_verified_total{{result=\"invalid\"}} {}", invalid);
+
+    out.push_str("# HELP ticketapi_jobs_total Finished job attempts, by kind and outcome (succeeded, retried or dead).\n");
+    out.push_str("# TYPE ticketapi_jobs_total counter\n");
+    render_jobs(&mut out, &metrics.jobs.lock().unwrap());
+
+    out
+}
+
+fn render_jobs(out: &mut String, jobs: &HashMap<(String, &'static str), u64>) {
+    let mut keys: Vec<&(String, &str)> = jobs.keys().collect();
+    keys.sort();
+    for key in keys {
+        let _ = writeln!(out, "ticketapi_jobs_total{{kind=\"{}\",outcome=\"{}\"}} {}", label(&key.0), key.1, jobs[key]);
+    }
+}
+
+// Access to GET /metrics: anyone if metrics_token is not set, otherwise scrapers sending it as a
+// bearer token.
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let Some(expected) = &config::get().metrics_token else {
+            return Outcome::Success(MetricsAccess);
+        };
+
+        let given = req.headers().get_one("authorization").and_then(|value| value.strip_prefix("Bearer "));
+        match given {
+            Some(token) if openssl::memcmp::eq(&Sha256::digest(token.trim()), &Sha256::digest(expected)) =>
+                Outcome::Success(MetricsAccess),
+            _ => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+// Start of a request, for its duration.
+struct RequestStart(Instant);
+
+// Records the count and duration of every response.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info { name: "Request metrics", kind: Kind::Request | Kind::Response }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _data: &mut Data<'_>) {
+        req.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let started = req.local_cache(|| RequestStart(Instant::now())).0;
+
+        // Requests no route matched are counted together, so scans of random paths do not add
+        // series.
+        let labels = RequestLabels {
+            route: req.route().map(|route| route.uri.to_string()).unwrap_or_else(|| "unmatched".to_string()),
+            method: req.method().as_str().to_string(),
+            status: res.status().code,
+        };
+
+        metrics().requests.lock().unwrap().entry(labels).or_default().observe(started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(200));
+        histogram.observe(Duration::from_secs(30));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "http_request_duration_seconds", "route=\"/ticket\",method=\"GET\"");
+        let lines: Vec<&str> = out.lines().collect();
+
+        assert_eq!(lines.len(), BUCKETS.len() + 3);
+        assert_eq!(lines[0], "http_request_duration_seconds_bucket{route=\"/ticket\",method=\"GET\",le=\"0.001\"} 0");
+        // 5 ms falls into the bucket with that bound.
+        assert_eq!(lines[1], "http_request_duration_seconds_bucket{route=\"/ticket\",method=\"GET\",le=\"0.005\"} 2");
+        assert_eq!(lines[6], "http_request_duration_seconds_bucket{route=\"/ticket\",method=\"GET\",le=\"0.25\"} 3");
+        assert_eq!(lines[11], "http_request_duration_seconds_bucket{route=\"/ticket\",method=\"GET\",le=\"10\"} 3");
+        assert_eq!(lines[12], "http_request_duration_seconds_bucket{route=\"/ticket\",method=\"GET\",le=\"+Inf\"} 4");
+        assert_eq!(lines[13], "http_request_duration_seconds_sum{route=\"/ticket\",method=\"GET\"} 30.208");
+        assert_eq!(lines[14], "http_request_duration_seconds_count{route=\"/ticket\",method=\"GET\"} 4");
+    }
+
+    #[test]
+    fn renders_without_labels() {
+        let mut out = String::new();
+        Histogram::default().render(&mut out, "db_connection_wait_seconds", "");
+
+        assert!(out.starts_with("db_connection_wait_seconds_bucket{le=\"0.001\"} 0\n"));
+        assert!(out.ends_with("db_connection_wait_seconds_sum 0\ndb_connection_wait_seconds_count 0\n"));
+    }
+
+    #[test]
+    fn counts_jobs_by_kind_and_outcome() {
+        job_finished("test_job", "retried");
+        job_finished("test_job", "retried");
+        job_finished("test_job", "succeeded");
+
+        let mut out = String::new();
+        render_jobs(&mut out, &metrics().jobs.lock().unwrap());
+        assert!(out.contains("ticketapi_jobs_total{kind=\"test_job\",outcome=\"retried\"} 2\n"));
+        assert!(out.contains("ticketapi_jobs_total{kind=\"test_job\",outcome=\"succeeded\"} 1\n"));
+    }
+}
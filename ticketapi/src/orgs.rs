@@ -0,0 +1,289 @@
+// Organizations and their members. A caller acting for an organization (a key bound to it, or a
+// session with X-Organization-Id) manages the organization's tickets with the scopes of its role.
+
+use chrono::{ DateTime, Utc };
+use rocket::http::Status;
+use rocket::serde::{ Deserialize, Serialize };
+use tokio_postgres::{ Client, Error, GenericClient, Row };
+use uuid::Uuid;
+
+use crate::db;
+use crate::scopes::{ self, KeysManage, MembersRead, Scope, TicketsDelete, TicketsRead, TicketsVerify, TicketsWrite, UsageRead };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Owner,
+    Admin,
+    Editor,
+    Viewer,
+    DoorStaff,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "owner" => Some(Role::Owner),
+            "admin" => Some(Role::Admin),
+            "editor" => Some(Role::Editor),
+            "viewer" => Some(Role::Viewer),
+            "door-staff" => Some(Role::DoorStaff),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Admin => "admin",
+            Role::Editor => "editor",
+            Role::Viewer => "viewer",
+            Role::DoorStaff => "door-staff",
+        }
+    }
+
+    // Scopes a member with this role may use in the organization.
+    pub fn scopes(&self) -> &'static [&'static str] {
+        match self {
+            Role::Owner | Role::Admin => &scopes::ALL,
+            Role::Editor =>
+                &[
+                    TicketsRead::NAME,
+                    TicketsWrite::NAME,
+                    TicketsDelete::NAME,
+                    TicketsVerify::NAME,
+                    UsageRead::NAME,
+                    KeysManage::NAME,
+                    MembersRead::NAME,
+                ],
+            Role::Viewer => &[TicketsRead::NAME, UsageRead::NAME, MembersRead::NAME],
+            Role::DoorStaff => &[TicketsVerify::NAME],
+        }
+    }
+
+    pub fn manages_members(&self) -> bool {
+        matches!(self, Role::Owner | Role::Admin)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct OrganizationRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct MemberRequest {
+    pub user_id: Uuid,
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct Organization {
+    pub id: Uuid,
+    pub name: String,
+    // Role of the caller in the organization.
+    pub role: Role,
+    pub created_at: String,
+}
+
+#[derive(Serialize)]
+pub struct Member {
+    pub user_id: Uuid,
+    pub role: Role,
+    pub created_at: String,
+}
+
+impl Member {
+    fn from_row(row: &Row) -> Member {
+        let created_at: DateTime<Utc> = row.get(2);
+
+        Member {
+            user_id: row.get(0),
+            role: Role::parse(row.get(1)).expect("organization_members.role is checked by the database"),
+            created_at: created_at.to_rfc3339(),
+        }
+    }
+}
+
+// Why a membership change was refused.
+#[derive(Debug)]
+pub enum MemberError {
+    NotFound,
+    // Only owners may add, change or remove owners.
+    Forbidden,
+    // Every organization keeps at least one owner.
+    LastOwner,
+    DatabaseError,
+}
+
+impl From<Error> for MemberError {
+    fn from(_: Error) -> MemberError {
+        MemberError::DatabaseError
+    }
+}
+
+impl MemberError {
+    pub fn status(&self) -> Status {
+        match self {
+            MemberError::NotFound => Status::NotFound,
+            MemberError::Forbidden => Status::Forbidden,
+            MemberError::LastOwner => Status::Conflict,
+            MemberError::DatabaseError => Status::InternalServerError,
+        }
+    }
+}
+
+// Role of a user in an organization, None if they are not a member. Used during authentication,
+// before the caller is known.
+pub async fn role_of(client: &Client, organization_id: Uuid, user_id: Uuid) -> Result<Option<Role>, Error> {
+    let row = client.query_opt(
+        "SELECT role FROM organization_members WHERE organization_id = $1 AND user_id = $2",
+        &[&organization_id, &user_id]
+    ).await?;
+
+    Ok(row.and_then(|row| Role::parse(row.get(0))))
+}
+
+pub async fn create_organization(user_id: Uuid, name: &str) -> Result<Organization, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let row = transaction
+        .query_one("SELECT id, name, created_at FROM create_organization($1)", &[&name]).await?;
+
+    transaction.commit().await?;
+
+    let created_at: DateTime<Utc> = row.get(2);
+    Ok(Organization {
+        id: row.get(0),
+        name: row.get(1),
+        role: Role::Owner,
+        created_at: created_at.to_rfc3339(),
+    })
+}
+
+pub async fn list_organizations(user_id: Uuid) -> Result<Vec<Organization>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let query =
+        "SELECT o.id, o.name, m.role, o.created_at FROM organizations o JOIN organization_members m ON m.organization_id = o.id WHERE m.user_id = $1 ORDER BY o.created_at";
+    let rows = transaction.query(query, &[&user_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(
+        rows
+            .iter()
+            .filter_map(|row| {
+                let created_at: DateTime<Utc> = row.get(3);
+                Some(Organization {
+                    id: row.get(0),
+                    name: row.get(1),
+                    role: Role::parse(row.get(2))?,
+                    created_at: created_at.to_rfc3339(),
+                })
+            })
+            .collect()
+    )
+}
+
+pub async fn list_members(user_id: Uuid, organization_id: Uuid) -> Result<Vec<Member>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let rows = transaction.query(
+        "SELECT user_id, role, created_at FROM organization_members WHERE organization_id = $1 ORDER BY created_at",
+        &[&organization_id]
+    ).await?;
+
+    transaction.commit().await?;
+
+    Ok(rows.iter().map(Member::from_row).collect())
+}
+
+// Locks the organization's memberships and returns the current role of a user and the number of owners.
+async fn current_role(
+    client: &impl GenericClient,
+    organization_id: Uuid,
+    user_id: Uuid
+) -> Result<(Option<Role>, i64), Error> {
+    let rows = client.query(
+        "SELECT user_id, role FROM organization_members WHERE organization_id = $1 FOR UPDATE",
+        &[&organization_id]
+    ).await?;
+
+    let role = rows
+        .iter()
+        .find(|row| row.get::<_, Uuid>(0) == user_id)
+        .and_then(|row| Role::parse(row.get(1)));
+    let owners = rows
+        .iter()
+        .filter(|row| row.get::<_, &str>(1) == Role::Owner.as_str())
+        .count() as i64;
+
+    Ok((role, owners))
+}
+
+// Adds a member or changes their role, on behalf of a member with the given role.
+pub async fn set_member(
+    user_id: Uuid,
+    caller_role: Role,
+    organization_id: Uuid,
+    member: &MemberRequest
+) -> Result<Member, MemberError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let (role, owners) = current_role(&transaction, organization_id, member.user_id).await?;
+    if caller_role != Role::Owner && (member.role == Role::Owner || role == Some(Role::Owner)) {
+        return Err(MemberError::Forbidden);
+    }
+    if role == Some(Role::Owner) && member.role != Role::Owner && owners <= 1 {
+        return Err(MemberError::LastOwner);
+    }
+
+    let query =
+        "INSERT INTO organization_members (organization_id, user_id, role) VALUES ($1, $2, $3) ON CONFLICT (organization_id, user_id) DO UPDATE SET role = EXCLUDED.role RETURNING user_id, role, created_at";
+    let row = transaction
+        .query_one(query, &[&organization_id, &member.user_id, &member.role.as_str()]).await?;
+
+    transaction.commit().await?;
+
+    Ok(Member::from_row(&row))
+}
+
+// Removes a member on behalf of a member with the given role. Keys of the removed member that are
+// bound to the organization stop working.
+pub async fn remove_member(
+    user_id: Uuid,
+    caller_role: Role,
+    organization_id: Uuid,
+    member_id: Uuid
+) -> Result<(), MemberError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let (role, owners) = current_role(&transaction, organization_id, member_id).await?;
+    match role {
+        None => {
+            return Err(MemberError::NotFound);
+        }
+        Some(Role::Owner) if caller_role != Role::Owner => {
+            return Err(MemberError::Forbidden);
+        }
+        Some(Role::Owner) if owners <= 1 => {
+            return Err(MemberError::LastOwner);
+        }
+        Some(_) => {}
+    }
+
+    transaction.execute(
+        "DELETE FROM organization_members WHERE organization_id = $1 AND user_id = $2",
+        &[&organization_id, &member_id]
+    ).await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
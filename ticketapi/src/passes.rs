@@ -0,0 +1,650 @@
+// Apple Wallet passes (.pkpass) for tickets. A pass is a zip of pass.json, icons, a manifest of
+// their SHA-1 hashes and a detached PKCS#7 signature of the manifest made with the pass type
+// certificate. Passes carry a web service URL, so Wallet fetches the current version of a pass
+// when its ticket changes. The serial number includes the code version: a transfer issues a new
+// pass to the new holder and the previous holder's pass turns into a voided one without barcode.
+// Wallet only asks for updates after a push notification, so every change of a ticket queues a
+// job that notifies the devices registered for its passes through APNs.
+
+use std::fmt;
+use std::fs;
+use std::io::{ Cursor, Write };
+use std::time::Duration;
+
+use chrono::{ DateTime, Utc };
+use openssl::error::ErrorStack;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkcs7::{ Pkcs7, Pkcs7Flags };
+use openssl::pkey::{ PKey, Private };
+use openssl::stack::Stack;
+use openssl::x509::X509;
+use rocket::http::Status;
+use rocket::request::{ self, FromRequest, Outcome, Request };
+use rocket::serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha1::{ Digest, Sha1 };
+use tokio_postgres::{ Error, GenericClient };
+use uuid::Uuid;
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::{ CompressionMethod, ZipWriter };
+
+use crate::branding::{ self, TicketTemplate };
+use crate::config::{ self, Settings };
+use crate::jobs::{ self, Job };
+use crate::tickets::{ self, StoredTicket };
+use crate::{ codes, db };
+
+const ICONS: [(&str, &[u8]); 3] = [
+    ("icon.png", include_bytes!("../assets/pass/icon.png")),
+    ("icon@2x.png", include_bytes!("../assets/pass/icon@2x.png")),
+    ("icon@3x.png", include_bytes!("../assets/pass/icon@3x.png")),
+];
+
+pub const PUSH_JOB: &str = "push_pass_update";
+
+// Apple's push service for Wallet passes. Pass updates are always pushed to production.
+const APNS_URL: &str = "https://api.push.apple.com/3/device/";
+const PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct PassError(String);
+
+impl fmt::Display for PassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<ErrorStack> for PassError {
+    fn from(e: ErrorStack) -> PassError {
+        PassError(e.to_string())
+    }
+}
+
+impl From<ZipError> for PassError {
+    fn from(e: ZipError) -> PassError {
+        PassError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for PassError {
+    fn from(e: std::io::Error) -> PassError {
+        PassError(e.to_string())
+    }
+}
+
+// Serial number of the pass for a ticket and code version.
+pub fn serial(ticket_id: i64, version: i32) -> String {
+    format!("{}.{}", ticket_id, version)
+}
+
+pub fn parse_serial(serial: &str) -> Option<(i64, i32)> {
+    let (ticket_id, version) = serial.split_once('.')?;
+    Some((ticket_id.parse().ok()?, version.parse().ok()?))
+}
+
+fn rgb((r, g, b): (f32, f32, f32)) -> String {
+    format!("rgb({}, {}, {})", (r * 255.0).round() as u8, (g * 255.0).round() as u8, (b * 255.0).round() as u8)
+}
+
+// Signs passes with the certificate of a pass type. Configured with pass_type_identifier,
+// pass_team_identifier, pass_certificate (PKCS#12 file with certificate and key),
+// pass_certificate_password, pass_wwdr_certificate (Apple WWDR intermediate, PEM) and optionally
+// pass_organization_name and pass_web_service_url (this API's /beta/1/wallet URL).
+pub struct PassSigner {
+    pub type_identifier: String,
+    team_identifier: String,
+    organization_name: String,
+    web_service_url: Option<String>,
+    certificate: X509,
+    key: PKey<Private>,
+    chain: Stack<X509>,
+}
+
+impl PassSigner {
+    // None unless pass_type_identifier is set.
+    pub fn from_config(settings: &Settings) -> Result<Option<PassSigner>, String> {
+        let Some(type_identifier) = settings.pass_type_identifier.clone() else {
+            return Ok(None);
+        };
+        let team_identifier = settings.pass_team_identifier.clone().ok_or("pass_team_identifier must be set")?;
+
+        let file = settings.pass_certificate.as_ref().ok_or("pass_certificate must be set")?;
+        let path = file.display();
+        let der = fs::read(file).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let password = settings.pass_certificate_password.as_deref().unwrap_or_default();
+        let parsed = Pkcs12::from_der(&der)
+            .and_then(|pkcs12| pkcs12.parse2(password))
+            .map_err(|e| format!("{} is not a valid PKCS#12 file: {}", path, e))?;
+        let (Some(certificate), Some(key)) = (parsed.cert, parsed.pkey) else {
+            return Err(format!("{} must contain a certificate and its private key", path));
+        };
+
+        let wwdr_file = settings.pass_wwdr_certificate.as_ref().ok_or("pass_wwdr_certificate must be set")?;
+        let wwdr_path = wwdr_file.display();
+        let pem = fs::read(wwdr_file).map_err(|e| format!("Cannot read {}: {}", wwdr_path, e))?;
+        let wwdr = X509::from_pem(&pem).map_err(|e| format!("{} is not a PEM certificate: {}", wwdr_path, e))?;
+        let mut chain = Stack::new().expect("Cannot allocate certificate stack");
+        chain.push(wwdr).expect("Cannot add WWDR certificate");
+
+        Ok(
+            Some(PassSigner {
+                type_identifier,
+                team_identifier,
+                organization_name: settings.pass_organization_name.clone(),
+                web_service_url: settings.pass_web_service_url.clone(),
+                certificate,
+                key,
+                chain,
+            })
+        )
+    }
+
+    // pass.json of a ticket. Passes of an earlier code version are voided and have no barcode.
+    fn pass_json(&self, stored: &StoredTicket, version: i32, template: &TicketTemplate) -> Value {
+        let ticket = &stored.ticket;
+        let ticket_id = ticket.id.unwrap_or_default();
+        let current = version == stored.code_version;
+        let event_name = ticket.event_name.clone().unwrap_or_default();
+        let background = branding::parse_color(&template.primary_color).unwrap_or((0.12, 0.16, 0.22));
+        // Text in white or black, whichever is readable on the background, as on PDF tickets.
+        let (foreground, label) = if branding::luminance(background) < 0.5 {
+            ((1.0, 1.0, 1.0), (0.82, 0.84, 0.86))
+        } else {
+            ((0.0, 0.0, 0.0), (0.29, 0.33, 0.39))
+        };
+
+        let mut secondary_fields = vec![
+            json!({
+                "key": "location",
+                "label": "LOCATION",
+                "value": ticket.event_location.clone().unwrap_or_default(),
+                "changeMessage": "The event moved to %@.",
+            })
+        ];
+        if let Some(event_date) = &ticket.event_date {
+            secondary_fields.push(
+                json!({
+                    "key": "date",
+                    "label": "DATE",
+                    "value": event_date,
+                    "dateStyle": "PKDateStyleMedium",
+                    "timeStyle": "PKDateStyleShort",
+                    "changeMessage": "The event was rescheduled to %@.",
+                })
+            );
+        }
+
+        let mut pass =
+            json!({
+            "formatVersion": 1,
+            "passTypeIdentifier": self.type_identifier,
+            "teamIdentifier": self.team_identifier,
+            "organizationName": self.organization_name,
+            "serialNumber": serial(ticket_id, version),
+            "description": format!("Ticket for {}", event_name),
+            "logoText": self.organization_name,
+            "foregroundColor": rgb(foreground),
+            "backgroundColor": rgb(background),
+            "labelColor": rgb(label),
+            "voided": !current || ticket.status.as_deref() != Some("Active"),
+            "eventTicket": {
+                "primaryFields": [{ "key": "event", "label": "EVENT", "value": event_name }],
+                "secondaryFields": secondary_fields,
+                "auxiliaryFields": [{
+                    "key": "holder",
+                    "label": "HOLDER",
+                    "value": ticket.holder_name.clone().unwrap_or_default(),
+                }],
+                "backFields": [
+                    { "key": "ticket", "label": "TICKET", "value": format!("#{}", ticket_id) },
+                    {
+                        "key": "terms",
+                        "label": "TERMS AND CONDITIONS",
+                        "value": ticket.terms_and_conditions.clone().unwrap_or_default(),
+                    },
+                ],
+            },
+        });
+
+        if let Some(event_date) = &ticket.event_date {
+            pass["relevantDate"] = json!(event_date);
+        }
+        if let Some(url) = &self.web_service_url {
+            pass["webServiceURL"] = json!(url);
+            pass["authenticationToken"] = json!(codes::pass_token(ticket_id, version));
+        }
+        if let (true, Some(code)) = (current, &ticket.code) {
+            let barcode =
+                json!({
+                "format": "PKBarcodeFormatQR",
+                "message": code,
+                "messageEncoding": "iso-8859-1",
+                "altText": format!("#{}", ticket_id),
+            });
+            pass["barcode"] = barcode.clone();
+            pass["barcodes"] = json!([barcode]);
+        }
+
+        pass
+    }
+
+    // Builds the signed .pkpass bundle of a ticket's pass in the given code version.
+    pub fn build(&self, stored: &StoredTicket, version: i32, template: &TicketTemplate) -> Result<Vec<u8>, PassError> {
+        let pass = serde_json::to_vec_pretty(&self.pass_json(stored, version, template)).map_err(|e| PassError(e.to_string()))?;
+
+        let mut files: Vec<(&str, &[u8])> = vec![("pass.json", &pass)];
+        files.extend(ICONS);
+
+        let manifest: serde_json::Map<String, Value> = files
+            .iter()
+            .map(|(name, data)| (name.to_string(), json!(hex::encode(Sha1::digest(data)))))
+            .collect();
+        let manifest = serde_json::to_vec(&manifest).map_err(|e| PassError(e.to_string()))?;
+
+        let signature = Pkcs7::sign(
+            &self.certificate,
+            &self.key,
+            &self.chain,
+            &manifest,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY
+        )?.to_der()?;
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        for (name, data) in files.iter().chain([("manifest.json", manifest.as_slice()), ("signature", &signature)].iter()) {
+            zip.start_file(*name, options)?;
+            zip.write_all(data)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+// Builds the pass of a ticket for a serial number, with the ticket's last change. Returns None if
+// there is no such ticket or code version.
+pub async fn pass_for(signer: &PassSigner, ticket_id: i64, version: i32) -> Result<Option<(Vec<u8>, DateTime<Utc>)>, Status> {
+    let stored = match tickets::find_ticket(ticket_id).await {
+        Ok(Some(stored)) if version <= stored.code_version => stored,
+        Ok(_) => {
+            return Ok(None);
+        }
+        Err(_) => {
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let client = db::connect().await.map_err(|_| Status::InternalServerError)?;
+    let (template, _) = branding::template_of(&*client, stored.owner_id).await.map_err(|_| Status::InternalServerError)?;
+
+    match signer.build(&stored, version, &template) {
+        Ok(pass) => Ok(Some((pass, stored.updated_at))),
+        Err(e) => {
+            eprintln!("pass signing error: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+// Token from "Authorization: ApplePass <token>", sent by Wallet.
+pub struct ApplePass(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApplePass {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.headers().get_one("authorization").and_then(|value| value.strip_prefix("ApplePass ")) {
+            Some(token) => Outcome::Success(ApplePass(token.trim().to_string())),
+            None => Outcome::Error((Status::Unauthorized, ())),
+        }
+    }
+}
+
+impl ApplePass {
+    // Checks the token against a serial number and returns its ticket id and code version.
+    pub fn authorize(&self, serial: &str) -> Result<(i64, i32), Status> {
+        let (ticket_id, version) = parse_serial(serial).ok_or(Status::NotFound)?;
+        if !codes::verify_pass_token(ticket_id, version, &self.0) {
+            return Err(Status::Unauthorized);
+        }
+        Ok((ticket_id, version))
+    }
+}
+
+// Time of the pass the device has, from If-Modified-Since.
+pub struct IfModifiedSince(pub Option<DateTime<Utc>>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfModifiedSince {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let since = req
+            .headers()
+            .get_one("if-modified-since")
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+            .map(|since| since.with_timezone(&Utc));
+        Outcome::Success(IfModifiedSince(since))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Registration {
+    #[serde(rename = "pushToken")]
+    pub push_token: String,
+}
+
+#[derive(Serialize)]
+pub struct UpdatedPasses {
+    #[serde(rename = "serialNumbers")]
+    pub serial_numbers: Vec<String>,
+    #[serde(rename = "lastUpdated")]
+    pub last_updated: String,
+}
+
+#[derive(Deserialize)]
+pub struct Logs {
+    pub logs: Vec<String>,
+}
+
+// Most messages of one request, and characters of one message, that are logged.
+const MAX_LOG_MESSAGES: usize = 10;
+const MAX_LOG_LENGTH: usize = 500;
+
+impl Logs {
+    // Writes the problems Wallet reports to the server log. Devices do not authenticate, so the
+    // messages are capped and stripped of control characters, which could forge log lines.
+    pub fn write(&self) {
+        for message in self.logs.iter().take(MAX_LOG_MESSAGES) {
+            log::warn!("wallet: {}", sanitize(message));
+        }
+        if self.logs.len() > MAX_LOG_MESSAGES {
+            log::warn!("wallet: {} further messages dropped", self.logs.len() - MAX_LOG_MESSAGES);
+        }
+    }
+}
+
+fn sanitize(message: &str) -> String {
+    message.chars().filter(|c| !c.is_control()).take(MAX_LOG_LENGTH).collect()
+}
+
+// Registers a device for updates of a pass. Returns true if the registration is new.
+pub async fn register(device_id: &str, pass_type: &str, serial: &str, ticket_id: i64, push_token: &str) -> Result<bool, Error> {
+    let client = db::connect().await?;
+
+    let query =
+        "INSERT INTO pass_registrations (device_id, pass_type_id, serial_number, ticket_id, push_token) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (device_id, pass_type_id, serial_number) DO UPDATE SET push_token = EXCLUDED.push_token RETURNING xmax = 0";
+    let row = client.query_one(query, &[&device_id, &pass_type, &serial, &ticket_id, &push_token]).await?;
+
+    Ok(row.get(0))
+}
+
+pub async fn unregister(device_id: &str, pass_type: &str, serial: &str) -> Result<(), Error> {
+    let client = db::connect().await?;
+
+    client.execute(
+        "DELETE FROM pass_registrations WHERE device_id = $1 AND pass_type_id = $2 AND serial_number = $3",
+        &[&device_id, &pass_type, &serial]
+    ).await?;
+
+    Ok(())
+}
+
+// Passes registered on a device whose ticket changed after the given tag. Tags are microseconds
+// since the epoch of the latest change.
+pub async fn updated_since(device_id: &str, pass_type: &str, since: Option<i64>) -> Result<Option<UpdatedPasses>, Error> {
+    let client = db::connect().await?;
+
+    let query =
+        "SELECT r.serial_number, (EXTRACT(EPOCH FROM COALESCE(t.updated_at, t.created_at)) * 1000000)::bigint AS tag FROM pass_registrations r JOIN tickets t ON t.id = r.ticket_id WHERE r.device_id = $1 AND r.pass_type_id = $2 AND ($3::bigint IS NULL OR (EXTRACT(EPOCH FROM COALESCE(t.updated_at, t.created_at)) * 1000000)::bigint > $3)";
+    let rows = client.query(query, &[&device_id, &pass_type, &since]).await?;
+
+    let Some(last_updated) = rows.iter().map(|row| row.get::<_, i64>(1)).max() else {
+        return Ok(None);
+    };
+
+    Ok(
+        Some(UpdatedPasses {
+            serial_numbers: rows.iter().map(|row| row.get(0)).collect(),
+            last_updated: last_updated.to_string(),
+        })
+    )
+}
+
+// Notifies devices through APNs that a pass changed. Authenticates with the pass type certificate,
+// which is also the push certificate, and sends an empty payload on the pass type's topic.
+pub struct Pusher {
+    client: reqwest::Client,
+    topic: String,
+}
+
+impl Pusher {
+    pub fn new(signer: &PassSigner) -> Result<Pusher, String> {
+        let error = |e: &dyn fmt::Display| format!("Cannot set up Wallet push notifications: {}", e);
+
+        let mut pem = signer.key.private_key_to_pem_pkcs8().map_err(|e| error(&e))?;
+        pem.extend(signer.certificate.to_pem().map_err(|e| error(&e))?);
+        for certificate in &signer.chain {
+            pem.extend(certificate.to_pem().map_err(|e| error(&e))?);
+        }
+        let identity = reqwest::Identity::from_pem(&pem).map_err(|e| error(&e))?;
+        let client = reqwest::Client
+            ::builder()
+            .identity(identity)
+            .http2_prior_knowledge()
+            .timeout(PUSH_TIMEOUT)
+            .build()
+            .map_err(|e| error(&e))?;
+
+        Ok(Pusher { client, topic: signer.type_identifier.clone() })
+    }
+
+    // Returns false if APNs no longer knows the device, whose registrations are then stale.
+    async fn push(&self, push_token: &str) -> Result<bool, String> {
+        let response = self.client
+            .post(format!("{}{}", APNS_URL, push_token))
+            .header("apns-topic", &self.topic)
+            .body("{}")
+            .send().await
+            .map_err(|e| e.to_string())?;
+
+        match response.status().as_u16() {
+            200 => Ok(true),
+            // 410 Unregistered; 400 for tokens that were never valid, e.g. BadDeviceToken.
+            410 | 400 => Ok(false),
+            status => {
+                let body = response.text().await.unwrap_or_default();
+                Err(format!("APNs answered {}: {}", status, body))
+            }
+        }
+    }
+}
+
+// Queues a push to the devices holding passes of a ticket. Runs in the transaction that changes the
+// ticket, so devices are only notified of committed changes. Does nothing without Wallet passes.
+pub async fn queue_push(client: &impl GenericClient, owner_id: Uuid, ticket_id: i64) -> Result<(), Error> {
+    if config::get().pass_type_identifier.is_none() {
+        return Ok(());
+    }
+
+    jobs::enqueue(client, owner_id, PUSH_JOB, &json!({ "ticket_id": ticket_id })).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct PushPayload {
+    ticket_id: i64,
+}
+
+// Performs a push_pass_update job. Registrations of devices APNs answers as unregistered are
+// removed; other failures retry the job, pushing again to every device, which only makes Wallet
+// ask for updates once more.
+pub async fn perform_push(pusher: Option<&Pusher>, job: &Job) -> Result<Option<Value>, String> {
+    let payload: PushPayload = serde_json::from_value(job.payload.clone()).map_err(|e| e.to_string())?;
+    let pusher = pusher.ok_or("Wallet passes are not configured")?;
+
+    let client = db::connect().await.map_err(|e| e.to_string())?;
+    let rows = client
+        .query(
+            "SELECT DISTINCT push_token FROM pass_registrations WHERE ticket_id = $1 AND pass_type_id = $2",
+            &[&payload.ticket_id, &pusher.topic]
+        ).await
+        .map_err(|e| e.to_string())?;
+
+    let mut pushed = 0;
+    let mut gone = Vec::new();
+    let mut errors = Vec::new();
+    for row in &rows {
+        let push_token: String = row.get(0);
+        match pusher.push(&push_token).await {
+            Ok(true) => {
+                pushed += 1;
+            }
+            Ok(false) => {
+                gone.push(push_token);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if !gone.is_empty() {
+        client
+            .execute("DELETE FROM pass_registrations WHERE push_token = ANY($1) AND pass_type_id = $2", &[&gone, &pusher.topic]).await
+            .map_err(|e| e.to_string())?;
+    }
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error);
+    }
+
+    Ok(Some(json!({ "pushed": pushed })))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::X509NameBuilder;
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::tickets::Ticket;
+
+    fn self_signed() -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "Pass Type ID: pass.com.example.test").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    fn signer() -> PassSigner {
+        let (certificate, key) = self_signed();
+        PassSigner {
+            type_identifier: "pass.com.example.test".to_string(),
+            team_identifier: "TEAM123456".to_string(),
+            organization_name: "Example".to_string(),
+            web_service_url: None,
+            certificate,
+            key,
+            chain: Stack::new().unwrap(),
+        }
+    }
+
+    fn stored_ticket(code_version: i32) -> StoredTicket {
+        let mut ticket: Ticket = serde_json
+            ::from_value(
+                json!({
+                "id": 7,
+                "event_name": "Concert",
+                "event_location": "Hall",
+                "event_date": "2026-10-01T19:00:00+00:00",
+                "status": "Active",
+                "holder_name": "Alex",
+            })
+            )
+            .unwrap();
+        ticket.code = Some("7.2.signature".to_string());
+
+        StoredTicket { ticket, owner_id: Uuid::nil(), code_version, updated_at: Utc::now() }
+    }
+
+    fn unzip(pass: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let mut archive = ZipArchive::new(Cursor::new(pass)).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut file = archive.by_index(i).unwrap();
+                let mut data = Vec::new();
+                file.read_to_end(&mut data).unwrap();
+                (file.name().to_string(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builds_signed_pass() {
+        let signer = signer();
+        let template = TicketTemplate { primary_color: "#1f2937".to_string(), text_color: "#111827".to_string(), has_logo: false };
+
+        let files = unzip(signer.build(&stored_ticket(2), 2, &template).unwrap());
+        let file = |name: &str| &files.iter().find(|(file, _)| file == name).unwrap().1;
+
+        // Every file but the manifest and signature is listed with its SHA-1.
+        let manifest: serde_json::Map<String, Value> = serde_json::from_slice(file("manifest.json")).unwrap();
+        assert_eq!(manifest.len(), files.len() - 2);
+        for (name, hash) in &manifest {
+            assert_eq!(hash.as_str().unwrap(), hex::encode(Sha1::digest(file(name))));
+        }
+
+        let pass: Value = serde_json::from_slice(file("pass.json")).unwrap();
+        assert_eq!(pass["serialNumber"], "7.2");
+        assert_eq!(pass["voided"], false);
+        assert_eq!(pass["barcode"]["message"], "7.2.signature");
+
+        let signature = Pkcs7::from_der(file("signature")).unwrap();
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(signer.certificate.clone()).unwrap();
+        let store = store.build();
+        let no_certs = Stack::new().unwrap();
+        signature.verify(&no_certs, &store, Some(file("manifest.json")), None, Pkcs7Flags::BINARY).unwrap();
+
+        // A changed manifest no longer matches the signature.
+        let mut tampered = file("manifest.json").clone();
+        tampered[2] ^= 1;
+        assert!(signature.verify(&no_certs, &store, Some(&tampered), None, Pkcs7Flags::BINARY).is_err());
+    }
+
+    #[test]
+    fn log_messages_are_sanitized() {
+        assert_eq!(sanitize("bad pass\n[ERROR] forged line\x1b[31m"), "bad pass[ERROR] forged line[31m");
+        assert_eq!(sanitize(&"x".repeat(2 * MAX_LOG_LENGTH)).len(), MAX_LOG_LENGTH);
+    }
+
+    #[test]
+    fn earlier_code_version_is_voided() {
+        let signer = signer();
+        let template = TicketTemplate { primary_color: "#ffffff".to_string(), text_color: "#111827".to_string(), has_logo: false };
+
+        let pass = signer.pass_json(&stored_ticket(2), 1, &template);
+        assert_eq!(pass["serialNumber"], "7.1");
+        assert_eq!(pass["voided"], true);
+        assert!(pass.get("barcode").is_none());
+        assert_eq!(pass["foregroundColor"], "rgb(0, 0, 0)");
+    }
+}
@@ -0,0 +1,331 @@
+// Printable PDF tickets. The document is a single A4 page written directly (standard Helvetica
+// fonts, WinAnsi text, the QR code drawn as filled squares and the logo embedded as a JPEG), which
+// keeps it small and free of font or renderer dependencies.
+
+use std::fmt::Write;
+
+use chrono::DateTime;
+use qrcode::{ Color, EcLevel, QrCode };
+
+use crate::branding::{ self, Logo, TicketTemplate };
+use crate::tickets::Ticket;
+
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 48.0;
+const HEADER_HEIGHT: f32 = 110.0;
+const QR_SIZE: f32 = 190.0;
+// Box the logo is scaled into, keeping its aspect ratio.
+const LOGO_MAX_HEIGHT: f32 = 60.0;
+const LOGO_MAX_WIDTH: f32 = 180.0;
+// Modules of white space around the QR code.
+const QR_QUIET_ZONE: usize = 4;
+
+// Builds the document object by object and writes the cross-reference table at the end.
+struct PdfWriter {
+    objects: Vec<Vec<u8>>,
+}
+
+impl PdfWriter {
+    // Adds an object and returns its number.
+    fn add(&mut self, object: Vec<u8>) -> usize {
+        self.objects.push(object);
+        self.objects.len()
+    }
+
+    fn stream(dictionary: &str, data: &[u8]) -> Vec<u8> {
+        let mut object = format!("<< {} /Length {} >>\nstream\n", dictionary, data.len()).into_bytes();
+        object.extend_from_slice(data);
+        object.extend_from_slice(b"\nendstream");
+        object
+    }
+
+    fn finish(self, root: usize) -> Vec<u8> {
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::with_capacity(self.objects.len());
+
+        for (i, object) in self.objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            pdf.extend_from_slice(object);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+
+        let xref = pdf.len();
+        let mut trailer = format!("xref\n0 {}\n0000000000 65535 f \n", self.objects.len() + 1);
+        for offset in offsets {
+            let _ = writeln!(trailer, "{:010} 00000 n ", offset);
+        }
+        let _ = write!(
+            trailer,
+            "trailer\n<< /Size {} /Root {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.objects.len() + 1,
+            root,
+            xref
+        );
+        pdf.extend_from_slice(trailer.as_bytes());
+
+        pdf
+    }
+}
+
+// PDF string literal in WinAnsiEncoding. Characters it cannot represent become '?'.
+fn text(value: &str) -> Vec<u8> {
+    let mut encoded = vec![b'('];
+    for c in value.chars() {
+        match c {
+            '(' | ')' | '\\' => encoded.extend_from_slice(&[b'\\', c as u8]),
+            '\n' | '\r' | '\t' => encoded.push(b' '),
+            c if (0x20..0x7f).contains(&(c as u32)) || (0xa0..=0xff).contains(&(c as u32)) => encoded.push(c as u8),
+            '€' => encoded.push(0x80),
+            '…' => encoded.push(0x85),
+            '‘' => encoded.push(0x91),
+            '’' => encoded.push(0x92),
+            '“' => encoded.push(0x93),
+            '”' => encoded.push(0x94),
+            '•' => encoded.push(0x95),
+            '–' => encoded.push(0x96),
+            '—' => encoded.push(0x97),
+            _ => encoded.push(b'?'),
+        }
+    }
+    encoded.push(b')');
+    encoded
+}
+
+// Splits text into lines of at most `width` points, estimating Helvetica at half its size per
+// character. Words longer than a line are broken.
+fn wrap(value: &str, size: f32, width: f32) -> Vec<String> {
+    let max_chars = ((width / (size * 0.5)) as usize).max(1);
+    let mut lines = Vec::new();
+
+    for paragraph in value.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            let chars: Vec<char> = word.chars().collect();
+            for piece in chars.chunks(max_chars) {
+                if !line.is_empty() && line.chars().count() + 1 + piece.len() > max_chars {
+                    lines.push(std::mem::take(&mut line));
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.extend(piece);
+            }
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+// Width and height of the logo in the header.
+fn logo_size(logo: &Logo) -> (f32, f32) {
+    let scale = (LOGO_MAX_HEIGHT / logo.height as f32).min(LOGO_MAX_WIDTH / logo.width as f32);
+    (logo.width as f32 * scale, logo.height as f32 * scale)
+}
+
+// Drawing operations of the page's content stream.
+struct Content(Vec<u8>);
+
+impl Content {
+    fn op(&mut self, op: &str) {
+        self.0.extend_from_slice(op.as_bytes());
+        self.0.push(b'\n');
+    }
+
+    fn fill_color(&mut self, (r, g, b): (f32, f32, f32)) {
+        self.op(&format!("{:.3} {:.3} {:.3} rg", r, g, b));
+    }
+
+    fn text(&mut self, font: &str, size: f32, x: f32, y: f32, value: &str) {
+        self.op(&format!("BT /{} {} Tf {:.2} {:.2} Td", font, size, x, y));
+        self.0.extend_from_slice(&text(value));
+        self.op(" Tj ET");
+    }
+}
+
+fn format_date(event_date: Option<&str>) -> String {
+    match event_date.map(DateTime::parse_from_rfc3339) {
+        Some(Ok(date)) => date.format("%A, %d %B %Y, %H:%M (UTC%:z)").to_string(),
+        Some(Err(_)) => event_date.unwrap_or_default().to_string(),
+        None => "To be announced".to_string(),
+    }
+}
+
+pub fn render_ticket(ticket: &Ticket, template: &TicketTemplate, logo: Option<&Logo>) -> Vec<u8> {
+    let primary = branding::parse_color(&template.primary_color).unwrap_or((0.12, 0.16, 0.22));
+    let text_color = branding::parse_color(&template.text_color).unwrap_or((0.07, 0.09, 0.15));
+    // Header text in white or black, whichever is readable on the primary color.
+    let header_text = if branding::luminance(primary) < 0.5 { (1.0, 1.0, 1.0) } else { (0.0, 0.0, 0.0) };
+
+    let mut content = Content(Vec::new());
+
+    // Header band with logo and event name
+    content.fill_color(primary);
+    content.op(&format!("0 {:.2} {:.2} {:.2} re f", PAGE_HEIGHT - HEADER_HEIGHT, PAGE_WIDTH, HEADER_HEIGHT));
+
+    let mut title_x = MARGIN;
+    if let Some(logo) = logo {
+        let (width, height) = logo_size(logo);
+        let y = PAGE_HEIGHT - HEADER_HEIGHT + (HEADER_HEIGHT - height) / 2.0;
+        content.op(&format!("q {:.2} 0 0 {:.2} {:.2} {:.2} cm /Logo Do Q", width, height, MARGIN, y));
+        title_x += width + 20.0;
+    }
+
+    content.fill_color(header_text);
+    let event_name = ticket.event_name.as_deref().unwrap_or("Ticket");
+    let title_lines = wrap(event_name, 24.0, PAGE_WIDTH - title_x - MARGIN);
+    for (i, line) in title_lines.iter().take(2).enumerate() {
+        content.text("F2", 24.0, title_x, PAGE_HEIGHT - 50.0 - i as f32 * 28.0, line);
+    }
+
+    // Details on the left, QR code on the right
+    let mut y = PAGE_HEIGHT - HEADER_HEIGHT - 50.0;
+    let details_width = PAGE_WIDTH - 2.0 * MARGIN - QR_SIZE - 30.0;
+    let details = [
+        ("Date", format_date(ticket.event_date.as_deref())),
+        ("Location", ticket.event_location.clone().unwrap_or_default()),
+        ("Holder", ticket.holder_name.clone().unwrap_or_default()),
+        ("Ticket", format!("#{}", ticket.id.unwrap_or_default())),
+    ];
+    for (label, value) in details {
+        content.fill_color((0.42, 0.45, 0.5));
+        content.text("F1", 10.0, MARGIN, y, &label.to_uppercase());
+        y -= 18.0;
+        content.fill_color(text_color);
+        for line in wrap(&value, 14.0, details_width) {
+            content.text("F2", 14.0, MARGIN, y, &line);
+            y -= 18.0;
+        }
+        y -= 14.0;
+    }
+
+    let qr_top = PAGE_HEIGHT - HEADER_HEIGHT - 30.0;
+    if let Some(code) = &ticket.code {
+        if let Ok(qr) = QrCode::with_error_correction_level(code.as_bytes(), EcLevel::M) {
+            let modules = qr.width();
+            let module = QR_SIZE / (modules + 2 * QR_QUIET_ZONE) as f32;
+            let left = PAGE_WIDTH - MARGIN - QR_SIZE + QR_QUIET_ZONE as f32 * module;
+            let top = qr_top - QR_QUIET_ZONE as f32 * module;
+
+            content.fill_color((0.0, 0.0, 0.0));
+            for (i, color) in qr.to_colors().iter().enumerate() {
+                if *color == Color::Dark {
+                    let x = left + (i % modules) as f32 * module;
+                    let y = top - (i / modules + 1) as f32 * module;
+                    content.op(&format!("{:.3} {:.3} {:.3} {:.3} re", x, y, module, module));
+                }
+            }
+            content.op("f");
+
+            content.fill_color((0.42, 0.45, 0.5));
+            content.text("F1", 7.0, PAGE_WIDTH - MARGIN - QR_SIZE, qr_top - QR_SIZE - 12.0, code);
+        }
+    }
+
+    // Terms and conditions below both columns
+    y = y.min(qr_top - QR_SIZE - 40.0);
+    if let Some(terms) = ticket.terms_and_conditions.as_deref().filter(|terms| !terms.trim().is_empty()) {
+        content.op(&format!("0.85 0.85 0.85 RG 0.5 w {:.2} {:.2} m {:.2} {:.2} l S", MARGIN, y, PAGE_WIDTH - MARGIN, y));
+        y -= 24.0;
+        content.fill_color((0.42, 0.45, 0.5));
+        content.text("F1", 10.0, MARGIN, y, "TERMS AND CONDITIONS");
+        y -= 16.0;
+        content.fill_color(text_color);
+        for line in wrap(terms, 9.0, PAGE_WIDTH - 2.0 * MARGIN) {
+            if y < MARGIN {
+                break;
+            }
+            content.text("F1", 9.0, MARGIN, y, &line);
+            y -= 12.0;
+        }
+    }
+
+    let mut pdf = PdfWriter { objects: Vec::new() };
+    let regular = pdf.add(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec());
+    let bold = pdf.add(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec());
+    let image = logo.map(|logo| {
+        let color_space = match logo.components {
+            1 => "/DeviceGray",
+            4 => "/DeviceCMYK",
+            _ => "/DeviceRGB",
+        };
+        pdf.add(
+            PdfWriter::stream(
+                &format!(
+                    "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter /DCTDecode",
+                    logo.width,
+                    logo.height,
+                    color_space
+                ),
+                &logo.data
+            )
+        )
+    });
+    let content = pdf.add(PdfWriter::stream("", &content.0));
+
+    let x_objects = image.map(|image| format!(" /XObject << /Logo {} 0 R >>", image)).unwrap_or_default();
+    // Page tree and catalog reference the objects that follow them.
+    let pages = pdf.objects.len() + 2;
+    let page = pdf.add(
+        format!(
+            "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 {} 0 R /F2 {} 0 R >>{} >> /Contents {} 0 R >>",
+            pages,
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            regular,
+            bold,
+            x_objects,
+            content
+        ).into_bytes()
+    );
+    pdf.add(format!("<< /Type /Pages /Kids [{} 0 R] /Count 1 >>", page).into_bytes());
+    let catalog = pdf.add(format!("<< /Type /Catalog /Pages {} 0 R >>", pages).into_bytes());
+
+    pdf.finish(catalog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(text("Rock (live) \\ 20€"), b"(Rock \\(live\\) \\\\ 20\x80)".to_vec());
+        assert_eq!(text("Line\nbreak\ttab"), b"(Line break tab)".to_vec());
+        assert_eq!(text("Größe – 東京"), b"(Gr\xf6\xdfe \x96 ??)".to_vec());
+    }
+
+    #[test]
+    fn wraps_at_word_boundaries() {
+        // 10 characters per line at size 10 and width 50.
+        assert_eq!(wrap("one two three four", 10.0, 50.0), ["one two", "three four"]);
+        assert_eq!(wrap("first\n\nthird", 10.0, 50.0), ["first", "", "third"]);
+    }
+
+    #[test]
+    fn breaks_long_words() {
+        assert_eq!(wrap("see https://example.com/terms-and-conditions", 10.0, 50.0), [
+            "see",
+            "https://ex",
+            "ample.com/",
+            "terms-and-",
+            "conditions",
+        ]);
+        assert!(wrap(&"ü".repeat(25), 10.0, 50.0).iter().all(|line| line.chars().count() <= 10));
+    }
+
+    #[test]
+    fn fits_logo_into_header() {
+        let size = |width, height| {
+            let (width, height) = logo_size(&Logo { data: Vec::new(), width, height, components: 3 });
+            ((width * 10.0).round() / 10.0, (height * 10.0).round() / 10.0)
+        };
+
+        assert_eq!(size(200, 100), (120.0, 60.0));
+        // Very wide and very tall logos stay inside the box.
+        assert_eq!(size(2000, 100), (180.0, 9.0));
+        assert_eq!(size(100, 4000), (1.5, 60.0));
+    }
+}
@@ -0,0 +1,58 @@
+// Plan definitions for API keys.
+
+use std::collections::HashMap;
+
+use crate::config::{ PlanSettings, QuotaSetting };
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub requests_per_minute: u32,
+    // Requests per calendar month, None for unlimited.
+    pub monthly_quota: Option<i64>,
+}
+
+// Name of the plan applied to requests without an API key (limited by IP).
+pub const ANONYMOUS: &str = "anonymous";
+
+// Plan used when a key references a plan we do not know about.
+pub const DEFAULT: &str = "free";
+
+// Built-in plans. Their limits can be overridden in the plans setting, which can also add plans.
+const BUILT_IN: [(&str, u32, Option<i64>); 4] = [
+    (ANONYMOUS, 30, None),
+    ("free", 60, Some(10_000)),
+    ("pro", 600, Some(1_000_000)),
+    ("enterprise", 6000, None),
+];
+
+pub struct Plans(HashMap<String, Plan>);
+
+impl Plans {
+    pub fn from_config(settings: &HashMap<String, PlanSettings>) -> Plans {
+        let mut plans: HashMap<String, Plan> = BUILT_IN
+            .iter()
+            .map(|(name, requests_per_minute, monthly_quota)| {
+                (name.to_string(), Plan { requests_per_minute: *requests_per_minute, monthly_quota: *monthly_quota })
+            })
+            .collect();
+
+        for (name, limits) in settings {
+            let mut plan = plans.get(name).unwrap_or(&plans[DEFAULT]).clone();
+            if let Some(requests_per_minute) = limits.requests_per_minute {
+                plan.requests_per_minute = requests_per_minute;
+            }
+            match &limits.monthly_quota {
+                Some(QuotaSetting::Requests(requests)) => plan.monthly_quota = Some(*requests),
+                Some(QuotaSetting::Unlimited(_)) => plan.monthly_quota = None,
+                None => {}
+            }
+            plans.insert(name.clone(), plan);
+        }
+
+        Plans(plans)
+    }
+
+    pub fn get(&self, name: &str) -> &Plan {
+        self.0.get(name).unwrap_or_else(|| &self.0[DEFAULT])
+    }
+}
@@ -0,0 +1,74 @@
+// Monthly request quotas per API key.
+
+use chrono::{ DateTime, Utc };
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::Header;
+use rocket::serde::Serialize;
+use rocket::{ Request, Response };
+
+// Consumption of a key in the current quota period (calendar month, UTC).
+#[derive(Debug, Clone)]
+pub struct Quota {
+    pub limit: Option<i64>,
+    pub used: i64,
+    pub period_start: DateTime<Utc>,
+    pub resets_at: DateTime<Utc>,
+}
+
+impl Quota {
+    pub fn exceeded(&self) -> bool {
+        matches!(self.limit, Some(limit) if self.used >= limit)
+    }
+
+    pub fn remaining(&self) -> Option<i64> {
+        self.limit.map(|limit| (limit - self.used).max(0))
+    }
+}
+
+#[derive(Serialize)]
+pub struct QuotaResponse {
+    pub plan: String,
+    pub limit: Option<i64>,
+    pub used: i64,
+    pub remaining: Option<i64>,
+    pub period_start: String,
+    pub resets_at: String,
+}
+
+impl QuotaResponse {
+    pub fn new(plan: &str, quota: &Quota) -> QuotaResponse {
+        QuotaResponse {
+            plan: plan.to_string(),
+            limit: quota.limit,
+            used: quota.used,
+            remaining: quota.remaining(),
+            period_start: quota.period_start.to_rfc3339(),
+            resets_at: quota.resets_at.to_rfc3339(),
+        }
+    }
+}
+
+// Remembers the quota of the authenticated key for the response headers.
+pub fn remember(req: &Request<'_>, quota: &Quota) {
+    req.local_cache(|| Some(quota.clone()));
+}
+
+// Adds X-Quota-* headers to responses for authenticated requests.
+pub struct QuotaHeaders;
+
+#[rocket::async_trait]
+impl Fairing for QuotaHeaders {
+    fn info(&self) -> Info {
+        Info { name: "Quota headers", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if let Some(quota) = req.local_cache(|| None::<Quota>) {
+            if let (Some(limit), Some(remaining)) = (quota.limit, quota.remaining()) {
+                res.set_header(Header::new("X-Quota-Limit", limit.to_string()));
+                res.set_header(Header::new("X-Quota-Remaining", remaining.to_string()));
+            }
+            res.set_header(Header::new("X-Quota-Reset", quota.resets_at.to_rfc3339()));
+        }
+    }
+}
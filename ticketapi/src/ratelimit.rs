@@ -0,0 +1,285 @@
+// Token-bucket rate limiting, keyed by API key (or session user) or by client IP for
+// unauthenticated requests.
+
+use std::collections::HashMap;
+use std::net::{ IpAddr, Ipv4Addr };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::http::{ Header, Status };
+use rocket::request::{ self, FromRequest, Outcome, Request };
+use rocket::Response;
+
+use uuid::Uuid;
+
+use crate::plans::{ self, Plan, Plans };
+
+// Buckets that have refilled completely are dropped this often; a new bucket starts full, so
+// dropping one changes nothing.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BucketKey {
+    Key(i64),
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+// A bucket keeps the limits it was last checked with, so pruning can tell whether it is full
+// without knowing the caller's plan.
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    refill_per_second: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(plan: &Plan, now: Instant) -> Bucket {
+        let capacity = plan.requests_per_minute as f64;
+        Bucket { tokens: capacity, capacity, refill_per_second: capacity / 60.0, updated: now }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.refill_per_second).min(self.capacity)
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.capacity
+    }
+
+    // Refills the bucket up to now, with the limits of the plan the caller has now.
+    fn refill(&mut self, plan: &Plan, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.capacity = plan.requests_per_minute as f64;
+        self.refill_per_second = self.capacity / 60.0;
+        self.tokens = self.tokens.min(self.capacity);
+        self.updated = now;
+    }
+}
+
+// Result of a rate limit check, used for the X-RateLimit-* headers.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset: u64,
+    pub retry_after: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn exceeded(&self) -> bool {
+        self.retry_after.is_some()
+    }
+}
+
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<BucketKey, Bucket>>>,
+}
+
+impl RateLimiter {
+    // Spawns the pruning task. Must be called from within the tokio runtime.
+    pub fn start() -> RateLimiter {
+        let limiter = RateLimiter::new();
+        tokio::spawn(prune(Arc::clone(&limiter.buckets)));
+        limiter
+    }
+
+    fn new() -> RateLimiter {
+        RateLimiter { buckets: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // Takes a token from the bucket if one is available.
+    pub fn check(&self, key: BucketKey, plan: &Plan) -> RateLimit {
+        self.apply(key, plan, true, Instant::now())
+    }
+
+    // Reports the state of the bucket without taking a token.
+    pub fn peek(&self, key: BucketKey, plan: &Plan) -> RateLimit {
+        self.apply(key, plan, false, Instant::now())
+    }
+
+    fn apply(&self, key: BucketKey, plan: &Plan, consume: bool, now: Instant) -> RateLimit {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.entry(key).or_insert_with(|| Bucket::new(plan, now));
+        bucket.refill(plan, now);
+
+        let retry_after = if bucket.tokens >= 1.0 {
+            if consume {
+                bucket.tokens -= 1.0;
+            }
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / bucket.refill_per_second).ceil() as u64)
+        };
+
+        RateLimit {
+            limit: plan.requests_per_minute,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((bucket.capacity - bucket.tokens) / bucket.refill_per_second).ceil() as u64,
+            retry_after,
+        }
+    }
+}
+
+async fn prune(buckets: Arc<Mutex<HashMap<BucketKey, Bucket>>>) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        buckets.lock().unwrap().retain(|_, bucket| !bucket.is_full(now));
+    }
+}
+
+fn client_ip(req: &Request<'_>) -> IpAddr {
+    req.client_ip().unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
+
+// Checks the bucket of an authenticated caller and remembers the result for the response headers.
+pub fn limit(req: &Request<'_>, bucket: BucketKey, plan: &str) -> RateLimit {
+    let limiter = req.rocket().state::<RateLimiter>().expect("RateLimiter must be managed");
+    let plans = req.rocket().state::<Plans>().expect("Plans must be managed");
+
+    let limit = limiter.check(bucket, plans.get(plan));
+    req.local_cache(|| Some(limit)).unwrap_or(limit)
+}
+
+// Checks the bucket of the client IP and remembers the result for the response headers.
+pub fn limit_ip(req: &Request<'_>) -> RateLimit {
+    let limiter = req.rocket().state::<RateLimiter>().expect("RateLimiter must be managed");
+    let plans = req.rocket().state::<Plans>().expect("Plans must be managed");
+
+    let limit = limiter.check(BucketKey::Ip(client_ip(req)), plans.get(plans::ANONYMOUS));
+    req.local_cache(|| Some(limit)).unwrap_or(limit)
+}
+
+// Guard for routes that do not require an API key; limits them by client IP.
+pub struct IpRateLimit;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IpRateLimit {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if limit_ip(req).exceeded() {
+            Outcome::Error((Status::TooManyRequests, ()))
+        } else {
+            Outcome::Success(IpRateLimit)
+        }
+    }
+}
+
+// Adds X-RateLimit-* (and Retry-After when limited) headers to every response.
+pub struct RateLimitHeaders;
+
+#[rocket::async_trait]
+impl Fairing for RateLimitHeaders {
+    fn info(&self) -> Info {
+        Info { name: "Rate limit headers", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let limit = match req.local_cache(|| None::<RateLimit>) {
+            Some(limit) => *limit,
+            None => {
+                let limiter = req.rocket().state::<RateLimiter>().expect("RateLimiter must be managed");
+                let plans = req.rocket().state::<Plans>().expect("Plans must be managed");
+                limiter.peek(BucketKey::Ip(client_ip(req)), plans.get(plans::ANONYMOUS))
+            }
+        };
+
+        res.set_header(Header::new("X-RateLimit-Limit", limit.limit.to_string()));
+        res.set_header(Header::new("X-RateLimit-Remaining", limit.remaining.to_string()));
+        res.set_header(Header::new("X-RateLimit-Reset", limit.reset.to_string()));
+
+        if let Some(retry_after) = limit.retry_after {
+            if res.status() == Status::TooManyRequests {
+                res.set_header(Header::new("Retry-After", retry_after.to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(requests_per_minute: u32) -> Plan {
+        Plan { requests_per_minute, monthly_quota: None }
+    }
+
+    #[test]
+    fn drains_and_refills() {
+        let limiter = RateLimiter::new();
+        let plan = plan(60);
+        let start = Instant::now();
+
+        for remaining in (0..60).rev() {
+            let limit = limiter.apply(BucketKey::Key(1), &plan, true, start);
+            assert!(!limit.exceeded());
+            assert_eq!(limit.remaining, remaining);
+        }
+
+        let limit = limiter.apply(BucketKey::Key(1), &plan, true, start);
+        assert!(limit.exceeded());
+        assert_eq!(limit.retry_after, Some(1));
+        assert_eq!(limit.reset, 60);
+
+        // One token per second at 60 requests per minute.
+        let limit = limiter.apply(BucketKey::Key(1), &plan, false, start + Duration::from_secs(10));
+        assert_eq!(limit.remaining, 10);
+        assert_eq!(limit.reset, 50);
+    }
+
+    #[test]
+    fn peek_takes_no_token() {
+        let limiter = RateLimiter::new();
+        let plan = plan(5);
+        let now = Instant::now();
+
+        limiter.apply(BucketKey::Key(1), &plan, false, now);
+        let limit = limiter.apply(BucketKey::Key(1), &plan, false, now);
+        assert_eq!(limit.remaining, 5);
+    }
+
+    #[test]
+    fn buckets_are_separate() {
+        let limiter = RateLimiter::new();
+        let plan = plan(1);
+        let now = Instant::now();
+
+        assert!(!limiter.apply(BucketKey::Key(1), &plan, true, now).exceeded());
+        assert!(limiter.apply(BucketKey::Key(1), &plan, true, now).exceeded());
+        assert!(!limiter.apply(BucketKey::Key(2), &plan, true, now).exceeded());
+    }
+
+    #[test]
+    fn full_is_judged_by_the_bucket_limits() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        limiter.apply(BucketKey::Key(1), &plan(600), true, now);
+        limiter.apply(BucketKey::Key(2), &plan(60), false, now);
+
+        let buckets = limiter.buckets.lock().unwrap();
+        // 599 of 600 tokens is more than a free plan holds, but the bucket is not full.
+        assert!(!buckets[&BucketKey::Key(1)].is_full(now));
+        assert!(buckets[&BucketKey::Key(1)].is_full(now + Duration::from_millis(100)));
+        assert!(buckets[&BucketKey::Key(2)].is_full(now));
+    }
+
+    #[test]
+    fn plan_change_caps_tokens() {
+        let limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        limiter.apply(BucketKey::Key(1), &plan(600), false, now);
+        let limit = limiter.apply(BucketKey::Key(1), &plan(60), false, now);
+        assert_eq!(limit.limit, 60);
+        assert_eq!(limit.remaining, 60);
+    }
+}
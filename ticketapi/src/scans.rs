@@ -0,0 +1,695 @@
+// Door scans. Every entry and exit scan is recorded in ticket_scans, and entries are checked
+// against the ticket's entry rules: total entries, entries per day and whether the holder has to
+// be scanned out before entering again.
+//
+// Offline scanners download a signed manifest of an event's valid codes before doors open, admit
+// tickets against it while the venue network is down and upload their scan logs later. Uploaded
+// scans are applied in timestamp order; entries the rules do not allow, for example the same
+// ticket admitted at two gates, are reported as conflicts. Scans recorded after an uploaded scan
+// are checked again, so an entry recorded online becomes a conflict once an earlier offline
+// entry of the ticket is uploaded.
+
+use std::collections::BTreeSet;
+use std::fs;
+
+use chrono::{ DateTime, Utc };
+use openssl::pkey::{ Id, PKey, Private };
+use openssl::sign::Signer;
+use rocket::serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use tokio_postgres::{ Error, GenericClient };
+
+use crate::config::Settings;
+use crate::tickets::Verification;
+use crate::{ codes, db, Caller };
+
+// Bytes of the SHA-256 of a code kept in the manifest.
+const CODE_HASH_LEN: usize = 8;
+
+// Most scans accepted in one upload.
+pub const MAX_SCANS: usize = 5_000;
+
+// High 16 bits of the advisory lock keys of tickets, so they cannot collide with advisory locks
+// taken for anything else. The ticket id fills the low 48 bits.
+const TICKET_LOCK_NAMESPACE: i64 = 0x5343 << 48;
+
+// Signs manifests with the Ed25519 key in manifest_signing_key (PEM file). Scanners verify them
+// with the public key from GET /scanner/key.
+pub struct ManifestSigner {
+    key: PKey<Private>,
+}
+
+impl ManifestSigner {
+    // None unless manifest_signing_key is set.
+    pub fn from_config(settings: &Settings) -> Result<Option<ManifestSigner>, String> {
+        let Some(file) = &settings.manifest_signing_key else {
+            return Ok(None);
+        };
+        let path = file.display();
+        let pem = fs::read(file).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        let key = PKey::private_key_from_pem(&pem).map_err(|e| format!("{} is not a PEM private key: {}", path, e))?;
+        if key.id() != Id::ED25519 {
+            return Err(format!("{} must be an Ed25519 key", path));
+        }
+
+        Ok(Some(ManifestSigner { key }))
+    }
+
+    pub fn public_key(&self) -> String {
+        let pem = self.key.public_key_to_pem().expect("Ed25519 public keys can be encoded");
+        String::from_utf8_lossy(&pem).into_owned()
+    }
+
+    // Hex encoded signature of the data.
+    pub fn sign(&self, data: &[u8]) -> String {
+        let mut signer = Signer::new_without_digest(&self.key).expect("Ed25519 keys can sign");
+        hex::encode(signer.sign_oneshot_to_vec(data).expect("Ed25519 signing does not fail"))
+    }
+}
+
+#[derive(Serialize)]
+pub struct ScannerKey {
+    pub algorithm: &'static str,
+    pub public_key: String,
+}
+
+// Valid codes of an event. Codes are listed as the hex encoded first bytes of their SHA-256, so a
+// lost scanner does not give away working codes.
+#[derive(Serialize)]
+pub struct Manifest {
+    pub event_name: String,
+    pub generated_at: String,
+    pub code_hash_length: usize,
+    pub codes: Vec<String>,
+}
+
+pub fn code_hash(code: &str) -> String {
+    hex::encode(&Sha256::digest(code.as_bytes())[..CODE_HASH_LEN])
+}
+
+pub async fn get_manifest(caller: &Caller, event_name: &str) -> Result<Manifest, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let rows = transaction.query(
+        "SELECT id, code_version FROM tickets WHERE owner_id = $1 AND event_name = $2 AND status = 'Active'",
+        &[&caller.account_id, &event_name]
+    ).await?;
+
+    transaction.commit().await?;
+
+    let mut codes: Vec<String> = rows
+        .iter()
+        .map(|row| code_hash(&codes::sign(row.get(0), row.get(1))))
+        .collect();
+    codes.sort();
+
+    Ok(Manifest {
+        event_name: event_name.to_string(),
+        generated_at: Utc::now().to_rfc3339(),
+        code_hash_length: CODE_HASH_LEN,
+        codes,
+    })
+}
+
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Entry,
+    Exit,
+}
+
+// Entries and exits of a ticket up to the time of a scan.
+pub struct Attendance {
+    pub entries: i64,
+    entries_today: i64,
+    inside: bool,
+    last_admission: Option<Admission>,
+}
+
+struct Admission {
+    gate: String,
+    scanned_at: DateTime<Utc>,
+    scanner_id: String,
+}
+
+async fn attendance(client: &impl GenericClient, ticket_id: i64, at: DateTime<Utc>) -> Result<Attendance, Error> {
+    let counts = client.query_one(
+        "SELECT COUNT(*) FILTER (WHERE result = 'admitted'), COUNT(*) FILTER (WHERE result = 'admitted' AND scanned_at >= date_trunc('day', $2, 'UTC')), MAX(scanned_at) FILTER (WHERE result = 'exited') FROM ticket_scans WHERE ticket_id = $1 AND scanned_at <= $2",
+        &[&ticket_id, &at]
+    ).await?;
+    let last_exit: Option<DateTime<Utc>> = counts.get(2);
+
+    let last_admission = client
+        .query_opt(
+            "SELECT gate, scanned_at, scanner_id FROM ticket_scans WHERE ticket_id = $1 AND result = 'admitted' AND scanned_at <= $2 ORDER BY scanned_at DESC LIMIT 1",
+            &[&ticket_id, &at]
+        ).await?
+        .map(|row| Admission { gate: row.get(0), scanned_at: row.get(1), scanner_id: row.get(2) });
+
+    Ok(Attendance {
+        entries: counts.get(0),
+        entries_today: counts.get(1),
+        inside: match (&last_admission, last_exit) {
+            (Some(admission), Some(last_exit)) => admission.scanned_at > last_exit,
+            (Some(_), None) => true,
+            (None, _) => false,
+        },
+        last_admission,
+    })
+}
+
+// Entry rules of a ticket, see Ticket.
+pub struct EntryRules {
+    pub max_entries: i32,
+    pub requires_checkout: bool,
+    pub daily_entry_limit: i32,
+}
+
+impl EntryRules {
+    // Why the rules do not allow another entry, None if they do.
+    pub fn deny_entry(&self, attendance: &Attendance) -> Option<&'static str> {
+        if self.requires_checkout && attendance.inside {
+            Some("already_inside")
+        } else if self.max_entries > 0 && attendance.entries >= self.max_entries as i64 {
+            Some("max_entries_reached")
+        } else if self.daily_entry_limit > 0 && attendance.entries_today >= self.daily_entry_limit as i64 {
+            Some("daily_limit_reached")
+        } else {
+            None
+        }
+    }
+}
+
+// Reasons of the entry rules, as opposed to reasons the ticket itself is not valid.
+const RULE_REASONS: [&str; 3] = ["already_inside", "max_entries_reached", "daily_limit_reached"];
+
+pub struct Evaluation {
+    pub verification: Verification,
+    attendance: Attendance,
+    rules: EntryRules,
+}
+
+impl Evaluation {
+    // Result stored in ticket_scans.
+    fn result(&self, direction: Direction) -> &'static str {
+        match (self.verification.valid, direction, self.verification.reason) {
+            (true, Direction::Entry, _) => "admitted",
+            (true, Direction::Exit, _) => "exited",
+            (false, _, Some(reason)) if RULE_REASONS.contains(&reason) => "conflict",
+            (false, _, _) => "rejected",
+        }
+    }
+
+    // Reason stored with a scan that was not accepted.
+    fn reason(&self) -> Option<&'static str> {
+        match (self.verification.valid, self.verification.reason) {
+            (true, _) => None,
+            (false, Some(reason)) => Some(reason),
+            (false, None) => Some("inactive"),
+        }
+    }
+}
+
+// Checks a scan of a ticket at the given time without recording it. The code version is checked
+// if given; with an event name, tickets of other events are treated as unknown. Returns None if
+// the account has no such ticket.
+pub async fn evaluate(
+    client: &impl GenericClient,
+    caller: &Caller,
+    ticket_id: i64,
+    version: Option<i32>,
+    event_name: Option<&str>,
+    direction: Direction,
+    at: DateTime<Utc>
+) -> Result<Option<Evaluation>, Error> {
+    let row = client.query_opt(
+        "SELECT status, event_name, holder_name, code_version, max_entries, requires_checkout, daily_entry_limit FROM tickets WHERE id = $1 AND owner_id = $2 AND ($3::text IS NULL OR event_name = $3)",
+        &[&ticket_id, &caller.account_id, &event_name]
+    ).await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let status: String = row.get(0);
+    let current = version.is_none_or(|version| version == row.get::<_, i32>(3));
+    let rules = EntryRules { max_entries: row.get(4), requires_checkout: row.get(5), daily_entry_limit: row.get(6) };
+    let attendance = attendance(client, ticket_id, at).await?;
+
+    // Holders may always leave, but only with a current code and after entering.
+    let (valid, reason) = match direction {
+        _ if !current => (false, Some("superseded")),
+        Direction::Entry if status != "Active" => (false, None),
+        Direction::Entry => {
+            let reason = rules.deny_entry(&attendance);
+            (reason.is_none(), reason)
+        }
+        Direction::Exit if !attendance.inside => (false, Some("not_inside")),
+        Direction::Exit => (true, None),
+    };
+
+    let entries = attendance.entries + i64::from(valid && direction == Direction::Entry);
+    Ok(
+        Some(Evaluation {
+            verification: Verification {
+                ticket_id,
+                valid,
+                status,
+                event_name: row.get(1),
+                holder_name: row.get(2),
+                reason,
+                entries: Some(entries),
+            },
+            attendance,
+            rules,
+        })
+    )
+}
+
+// Inserts a scan. Returns false if the scanner already recorded a scan of the ticket at that time.
+async fn insert_scan(
+    client: &impl GenericClient,
+    caller: &Caller,
+    evaluation: &Evaluation,
+    direction: Direction,
+    scanner_id: &str,
+    gate: &str,
+    scanned_at: DateTime<Utc>
+) -> Result<bool, Error> {
+    let inserted = client.execute(
+        "INSERT INTO ticket_scans (ticket_id, owner_id, direction, scanner_id, gate, scanned_at, result, reason, key_id, user_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (ticket_id, scanner_id, scanned_at) DO NOTHING",
+        &[
+            &evaluation.verification.ticket_id,
+            &caller.account_id,
+            &(match direction {
+                Direction::Entry => "entry",
+                Direction::Exit => "exit",
+            }),
+            &scanner_id,
+            &gate,
+            &scanned_at,
+            &evaluation.result(direction),
+            &evaluation.reason(),
+            &caller.key_id,
+            &caller.user_id,
+        ]
+    ).await?;
+
+    Ok(inserted > 0)
+}
+
+// Serializes scans of tickets with concurrent scans and uploads. Door staff may not lock the
+// ticket rows themselves. The locks are taken in key order, so uploads that lock several tickets
+// cannot deadlock each other.
+async fn lock_tickets(client: &impl GenericClient, ticket_ids: impl IntoIterator<Item = i64>) -> Result<(), Error> {
+    let keys: BTreeSet<i64> = ticket_ids.into_iter().map(ticket_lock_key).collect();
+    for key in keys {
+        client.execute("SELECT pg_advisory_xact_lock($1)", &[&key]).await?;
+    }
+    Ok(())
+}
+
+fn ticket_lock_key(ticket_id: i64) -> i64 {
+    TICKET_LOCK_NAMESPACE | (ticket_id & 0xffff_ffff_ffff)
+}
+
+// A recorded scan of a ticket, as far as the entry rules are concerned.
+struct RecordedScan {
+    id: i64,
+    direction: Direction,
+    scanned_at: DateTime<Utc>,
+    result: String,
+    reason: Option<String>,
+}
+
+impl RecordedScan {
+    // Whether the entry rules decided the result, as opposed to the ticket or its code.
+    fn follows_rules(&self) -> bool {
+        match self.result.as_str() {
+            "admitted" | "exited" | "conflict" => true,
+            _ => self.reason.as_deref() == Some("not_inside"),
+        }
+    }
+}
+
+// Applies the entry rules to a ticket's scans in time order. Returns the scans whose result
+// differs from the recorded one, with their new result and reason.
+fn replay(rules: &EntryRules, scans: &[RecordedScan]) -> Vec<(i64, &'static str, Option<&'static str>)> {
+    let mut attendance = Attendance { entries: 0, entries_today: 0, inside: false, last_admission: None };
+    let mut day = None;
+    let mut changed = Vec::new();
+
+    for scan in scans.iter().filter(|scan| scan.follows_rules()) {
+        let scan_day = scan.scanned_at.date_naive();
+        if day != Some(scan_day) {
+            day = Some(scan_day);
+            attendance.entries_today = 0;
+        }
+
+        let (result, reason) = match scan.direction {
+            Direction::Entry => match rules.deny_entry(&attendance) {
+                Some(reason) => ("conflict", Some(reason)),
+                None => {
+                    attendance.entries += 1;
+                    attendance.entries_today += 1;
+                    attendance.inside = true;
+                    ("admitted", None)
+                }
+            },
+            Direction::Exit if attendance.inside => {
+                attendance.inside = false;
+                ("exited", None)
+            }
+            Direction::Exit => ("rejected", Some("not_inside")),
+        };
+        if scan.result != result || scan.reason.as_deref() != reason {
+            changed.push((scan.id, result, reason));
+        }
+    }
+
+    changed
+}
+
+// Re-applies the entry rules to the scans recorded after an offline scan that was uploaded late,
+// so that for example a later entry becomes a conflict once an earlier entry at another gate is
+// known. Returns the number of scans whose result changed.
+async fn reconcile(client: &impl GenericClient, ticket_id: i64, rules: &EntryRules, scanned_at: DateTime<Utc>) -> Result<usize, Error> {
+    let later: bool = client.query_one(
+        "SELECT EXISTS (SELECT 1 FROM ticket_scans WHERE ticket_id = $1 AND scanned_at > $2)",
+        &[&ticket_id, &scanned_at]
+    ).await?.get(0);
+    if !later {
+        return Ok(0);
+    }
+
+    let scans: Vec<RecordedScan> = client
+        .query(
+            "SELECT id, direction, scanned_at, result, reason FROM ticket_scans WHERE ticket_id = $1 ORDER BY scanned_at, id",
+            &[&ticket_id]
+        ).await?
+        .iter()
+        .map(|row| RecordedScan {
+            id: row.get(0),
+            direction: if row.get::<_, &str>(1) == "exit" { Direction::Exit } else { Direction::Entry },
+            scanned_at: row.get(2),
+            result: row.get(3),
+            reason: row.get(4),
+        })
+        .collect();
+
+    let changed = replay(rules, &scans);
+    for (id, result, reason) in &changed {
+        client.execute("UPDATE ticket_scans SET result = $2, reason = $3 WHERE id = $1", &[id, result, reason]).await?;
+    }
+
+    Ok(changed.len())
+}
+
+#[derive(Deserialize)]
+pub struct ScanRequest {
+    #[serde(default)]
+    pub direction: Direction,
+    #[serde(default)]
+    pub gate: String,
+    // Defaults to "api".
+    pub scanner_id: Option<String>,
+}
+
+// Online scan of a code at a door: checks it like a verification and records the entry or exit.
+// Returns None for forged codes and codes of tickets the account does not own.
+pub async fn record_scan(caller: &Caller, code: &str, request: &ScanRequest) -> Result<Option<Verification>, Error> {
+    let Some((ticket_id, version)) = codes::parse(code) else {
+        return Ok(None);
+    };
+
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    lock_tickets(&transaction, [ticket_id]).await?;
+    let now = Utc::now();
+    let Some(evaluation) = evaluate(&transaction, caller, ticket_id, Some(version), None, request.direction, now).await? else {
+        return Ok(None);
+    };
+    let scanner_id = request.scanner_id.as_deref().unwrap_or("api");
+    insert_scan(&transaction, caller, &evaluation, request.direction, scanner_id, &request.gate, now).await?;
+
+    transaction.commit().await?;
+
+    Ok(Some(evaluation.verification))
+}
+
+#[derive(Deserialize)]
+pub struct ScanLog {
+    // Identifies the device, so re-uploading a log does not record its scans twice.
+    pub scanner_id: String,
+    pub scans: Vec<OfflineScan>,
+}
+
+#[derive(Deserialize)]
+pub struct OfflineScan {
+    pub code: String,
+    #[serde(default)]
+    pub direction: Direction,
+    pub gate: String,
+    // RFC 3339 time of the scan on the scanner.
+    pub scanned_at: String,
+}
+
+#[derive(Serialize)]
+pub struct ScanReport {
+    pub admitted: usize,
+    pub exited: usize,
+    // Scans recorded by an earlier upload of the same log.
+    pub duplicates: usize,
+    // Scans recorded before the upload whose result changed because an uploaded scan precedes
+    // them, for example an entry that turned out to be a second one.
+    pub revised: usize,
+    pub rejected: Vec<RejectedScan>,
+    pub conflicts: Vec<ScanConflict>,
+}
+
+#[derive(Serialize)]
+pub struct RejectedScan {
+    pub code: String,
+    pub gate: String,
+    pub scanned_at: String,
+    // invalid, invalid_time, unknown, superseded, inactive or not_inside
+    pub reason: &'static str,
+}
+
+// Entry the ticket's rules did not allow, with the latest admission before it.
+#[derive(Serialize)]
+pub struct ScanConflict {
+    pub ticket_id: i64,
+    pub gate: String,
+    pub scanned_at: String,
+    pub scanner_id: String,
+    // already_inside, max_entries_reached or daily_limit_reached
+    pub reason: &'static str,
+    pub admitted_gate: String,
+    pub admitted_at: String,
+    pub admitted_by: String,
+}
+
+enum ScanResult {
+    Admitted,
+    Exited,
+    Duplicate,
+    Rejected(&'static str),
+    Conflict(ScanConflict),
+}
+
+// Records an uploaded scan. Returns its result and the number of later scans it revised.
+async fn apply_scan(
+    client: &impl GenericClient,
+    caller: &Caller,
+    event_name: &str,
+    scanner_id: &str,
+    scan: &OfflineScan,
+    scanned_at: DateTime<Utc>
+) -> Result<(ScanResult, usize), Error> {
+    let Some((ticket_id, version)) = codes::parse(&scan.code) else {
+        return Ok((ScanResult::Rejected("invalid"), 0));
+    };
+
+    let evaluation = evaluate(client, caller, ticket_id, Some(version), Some(event_name), scan.direction, scanned_at).await?;
+    let Some(evaluation) = evaluation else {
+        return Ok((ScanResult::Rejected("unknown"), 0));
+    };
+    if !insert_scan(client, caller, &evaluation, scan.direction, scanner_id, &scan.gate, scanned_at).await? {
+        return Ok((ScanResult::Duplicate, 0));
+    }
+    let revised = reconcile(client, ticket_id, &evaluation.rules, scanned_at).await?;
+
+    let result = match (evaluation.result(scan.direction), evaluation.reason(), &evaluation.attendance.last_admission) {
+        ("admitted", _, _) => ScanResult::Admitted,
+        ("exited", _, _) => ScanResult::Exited,
+        ("conflict", Some(reason), Some(admission)) =>
+            ScanResult::Conflict(ScanConflict {
+                ticket_id,
+                gate: scan.gate.clone(),
+                scanned_at: scanned_at.to_rfc3339(),
+                scanner_id: scanner_id.to_string(),
+                reason,
+                admitted_gate: admission.gate.clone(),
+                admitted_at: admission.scanned_at.to_rfc3339(),
+                admitted_by: admission.scanner_id.clone(),
+            }),
+        (_, reason, _) => ScanResult::Rejected(reason.unwrap_or("inactive")),
+    };
+    Ok((result, revised))
+}
+
+// Applies an uploaded scan log in one transaction, oldest scan first.
+pub async fn sync(caller: &Caller, event_name: &str, log: &ScanLog) -> Result<ScanReport, Error> {
+    let mut report = ScanReport { admitted: 0, exited: 0, duplicates: 0, revised: 0, rejected: Vec::new(), conflicts: Vec::new() };
+    let reject = |scan: &OfflineScan, reason| RejectedScan {
+        code: scan.code.clone(),
+        gate: scan.gate.clone(),
+        scanned_at: scan.scanned_at.clone(),
+        reason,
+    };
+
+    let mut scans = Vec::with_capacity(log.scans.len());
+    for scan in &log.scans {
+        match DateTime::parse_from_rfc3339(&scan.scanned_at) {
+            Ok(scanned_at) => scans.push((scanned_at.with_timezone(&Utc), scan)),
+            Err(_) => report.rejected.push(reject(scan, "invalid_time")),
+        }
+    }
+    scans.sort_by_key(|(scanned_at, _)| *scanned_at);
+
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    // All tickets of the log are locked before the first scan is applied.
+    let ticket_ids = scans.iter().filter_map(|(_, scan)| codes::parse(&scan.code)).map(|(ticket_id, _)| ticket_id);
+    lock_tickets(&transaction, ticket_ids).await?;
+
+    for (scanned_at, scan) in scans {
+        let (result, revised) = apply_scan(&transaction, caller, event_name, &log.scanner_id, scan, scanned_at).await?;
+        report.revised += revised;
+        match result {
+            ScanResult::Admitted => {
+                report.admitted += 1;
+            }
+            ScanResult::Exited => {
+                report.exited += 1;
+            }
+            ScanResult::Duplicate => {
+                report.duplicates += 1;
+            }
+            ScanResult::Rejected(reason) => report.rejected.push(reject(scan, reason)),
+            ScanResult::Conflict(conflict) => report.conflicts.push(conflict),
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attendance(entries: i64, entries_today: i64, inside: bool) -> Attendance {
+        Attendance { entries, entries_today, inside, last_admission: None }
+    }
+
+    #[test]
+    fn default_rules_admit_once() {
+        let rules = EntryRules { max_entries: 1, requires_checkout: true, daily_entry_limit: 0 };
+
+        assert_eq!(rules.deny_entry(&attendance(0, 0, false)), None);
+        assert_eq!(rules.deny_entry(&attendance(1, 1, true)), Some("already_inside"));
+        assert_eq!(rules.deny_entry(&attendance(1, 1, false)), Some("max_entries_reached"));
+    }
+
+    #[test]
+    fn zero_means_unlimited() {
+        let rules = EntryRules { max_entries: 0, requires_checkout: false, daily_entry_limit: 0 };
+
+        assert_eq!(rules.deny_entry(&attendance(1_000, 1_000, true)), None);
+    }
+
+    #[test]
+    fn daily_limit_counts_todays_entries() {
+        let rules = EntryRules { max_entries: 0, requires_checkout: true, daily_entry_limit: 2 };
+
+        assert_eq!(rules.deny_entry(&attendance(5, 1, false)), None);
+        assert_eq!(rules.deny_entry(&attendance(5, 2, false)), Some("daily_limit_reached"));
+    }
+
+    #[test]
+    fn holder_inside_is_reported_first() {
+        let rules = EntryRules { max_entries: 3, requires_checkout: true, daily_entry_limit: 1 };
+
+        assert_eq!(rules.deny_entry(&attendance(3, 1, true)), Some("already_inside"));
+        assert_eq!(rules.deny_entry(&attendance(3, 1, false)), Some("max_entries_reached"));
+        assert_eq!(rules.deny_entry(&attendance(2, 1, false)), Some("daily_limit_reached"));
+    }
+
+    fn scan(id: i64, direction: Direction, time: &str, result: &str, reason: Option<&str>) -> RecordedScan {
+        RecordedScan {
+            id,
+            direction,
+            scanned_at: DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc),
+            result: result.to_string(),
+            reason: reason.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn earlier_offline_entry_turns_later_entry_into_conflict() {
+        let rules = EntryRules { max_entries: 1, requires_checkout: true, daily_entry_limit: 0 };
+        let scans = [
+            scan(2, Direction::Entry, "2026-05-01T19:00:00Z", "admitted", None),
+            scan(1, Direction::Entry, "2026-05-01T19:30:00Z", "admitted", None),
+        ];
+
+        assert_eq!(replay(&rules, &scans), vec![(1, "conflict", Some("already_inside"))]);
+    }
+
+    #[test]
+    fn earlier_offline_exit_turns_later_entry_into_reentry() {
+        let rules = EntryRules { max_entries: 2, requires_checkout: true, daily_entry_limit: 0 };
+        let scans = [
+            scan(1, Direction::Entry, "2026-05-01T19:00:00Z", "admitted", None),
+            scan(3, Direction::Exit, "2026-05-01T19:10:00Z", "exited", None),
+            scan(2, Direction::Entry, "2026-05-01T19:30:00Z", "conflict", Some("already_inside")),
+        ];
+
+        assert_eq!(replay(&rules, &scans), vec![(2, "admitted", None)]);
+    }
+
+    #[test]
+    fn earlier_offline_entry_lets_later_exit_through() {
+        let rules = EntryRules { max_entries: 1, requires_checkout: true, daily_entry_limit: 0 };
+        let scans = [
+            scan(2, Direction::Entry, "2026-05-01T19:00:00Z", "admitted", None),
+            scan(1, Direction::Exit, "2026-05-01T22:00:00Z", "rejected", Some("not_inside")),
+        ];
+
+        assert_eq!(replay(&rules, &scans), vec![(1, "exited", None)]);
+    }
+
+    #[test]
+    fn replay_keeps_scans_rejected_for_the_ticket() {
+        let rules = EntryRules { max_entries: 1, requires_checkout: false, daily_entry_limit: 1 };
+        let scans = [
+            scan(3, Direction::Entry, "2026-05-01T18:00:00Z", "rejected", Some("superseded")),
+            scan(1, Direction::Entry, "2026-05-01T19:00:00Z", "admitted", None),
+            scan(2, Direction::Entry, "2026-05-02T19:00:00Z", "conflict", Some("max_entries_reached")),
+        ];
+
+        assert!(replay(&rules, &scans).is_empty());
+    }
+
+    #[test]
+    fn ticket_lock_keys_keep_large_ids_apart() {
+        assert_ne!(ticket_lock_key(1), ticket_lock_key(1 + (1 << 32)));
+        assert_eq!(ticket_lock_key(7) >> 48, TICKET_LOCK_NAMESPACE >> 48);
+    }
+}
@@ -0,0 +1,102 @@
+// Permissions of callers. Routes take a Scoped<S> guard instead of Caller to require scope S.
+
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use rocket::request::{ self, FromRequest, Outcome, Request };
+
+use crate::{ reject, Caller, AuthError };
+
+pub const ALL: [&str; 8] = [
+    TicketsRead::NAME,
+    TicketsWrite::NAME,
+    TicketsDelete::NAME,
+    TicketsVerify::NAME,
+    UsageRead::NAME,
+    KeysManage::NAME,
+    MembersRead::NAME,
+    MembersManage::NAME,
+];
+
+pub trait Scope {
+    const NAME: &'static str;
+}
+
+pub struct TicketsRead;
+pub struct TicketsWrite;
+pub struct TicketsDelete;
+pub struct TicketsVerify;
+pub struct UsageRead;
+pub struct KeysManage;
+pub struct MembersRead;
+pub struct MembersManage;
+
+impl Scope for TicketsRead {
+    const NAME: &'static str = "tickets:read";
+}
+
+impl Scope for TicketsWrite {
+    const NAME: &'static str = "tickets:write";
+}
+
+impl Scope for TicketsDelete {
+    const NAME: &'static str = "tickets:delete";
+}
+
+impl Scope for TicketsVerify {
+    const NAME: &'static str = "tickets:verify";
+}
+
+impl Scope for UsageRead {
+    const NAME: &'static str = "usage:read";
+}
+
+impl Scope for KeysManage {
+    const NAME: &'static str = "keys:manage";
+}
+
+impl Scope for MembersRead {
+    const NAME: &'static str = "members:read";
+}
+
+impl Scope for MembersManage {
+    const NAME: &'static str = "members:manage";
+}
+
+pub fn is_known(scope: &str) -> bool {
+    ALL.contains(&scope)
+}
+
+// An authenticated caller that holds scope S.
+pub(crate) struct Scoped<S: Scope>(Caller, PhantomData<S>);
+
+impl<S: Scope> Deref for Scoped<S> {
+    type Target = Caller;
+
+    fn deref(&self) -> &Caller {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, S: Scope> FromRequest<'r> for Scoped<S> {
+    type Error = AuthError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let caller = match req.guard::<Caller>().await {
+            Outcome::Success(caller) => caller,
+            Outcome::Error(error) => {
+                return Outcome::Error(error);
+            }
+            Outcome::Forward(status) => {
+                return Outcome::Forward(status);
+            }
+        };
+
+        if caller.scopes.iter().any(|scope| scope == S::NAME) {
+            Outcome::Success(Scoped(caller, PhantomData))
+        } else {
+            reject(req, AuthError::InsufficientScope)
+        }
+    }
+}
@@ -0,0 +1,404 @@
+// Ticket storage. Every query runs as the caller (see db::begin_as) and is additionally limited
+// to tickets owned by the caller's account.
+
+use chrono::{ DateTime, FixedOffset, Offset, Utc };
+use rocket::http::Status;
+use rocket::serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use tokio_postgres::{ Error, GenericClient, Row, Transaction };
+use uuid::Uuid;
+
+use crate::mail::{ Mail, Template };
+use crate::passes;
+use crate::scans::{ self, Direction };
+use crate::{ codes, db, Caller };
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ticket {
+    pub id: Option<i64>,
+    pub event_name: Option<String>,
+    pub event_location: Option<String>,
+    pub event_date: Option<String>,
+    // End of the ticket's validity, e.g. the last day of a festival. Tickets expire after it, or
+    // after event_date if it is not set.
+    pub valid_until: Option<String>,
+    pub status: Option<String>,
+    pub holder_name: Option<String>,
+    pub holder_email: Option<String>,
+    pub notes: Option<String>,
+    pub terms_and_conditions: Option<String>,
+    // Entries the ticket allows in total, 0 for unlimited. Defaults to 1.
+    pub max_entries: Option<i32>,
+    // Whether a holder must be scanned out before entering again. Defaults to true.
+    pub requires_checkout: Option<bool>,
+    // Entries allowed per day (UTC), 0 for no daily limit. Defaults to 0.
+    pub daily_entry_limit: Option<i32>,
+    // Signed code for the ticket's QR code, changes when the ticket is transferred.
+    #[serde(skip_deserializing)]
+    pub code: Option<String>,
+}
+
+const TICKET_COLUMNS: &str =
+    "id, event_name, event_location, event_date, status, holder_name, holder_email, notes, terms_and_conditions, code_version, max_entries, requires_checkout, daily_entry_limit, valid_until, event_utc_offset";
+
+// UTC offset in seconds of a date given with one, so it can be shown in the event's local time.
+fn utc_offset(date: Option<&str>) -> Option<i32> {
+    DateTime::parse_from_rfc3339(date?).ok().map(|date| date.offset().local_minus_utc())
+}
+
+impl Ticket {
+    fn from_row(row: &Row) -> Ticket {
+        let event_date: Option<DateTime<Utc>> = row.get(3);
+        let valid_until: Option<DateTime<Utc>> = row.get(13);
+        let offset = FixedOffset::east_opt(row.get(14)).unwrap_or(Utc.fix());
+        let local = |date: DateTime<Utc>| date.with_timezone(&offset).to_rfc3339();
+        let id: i64 = row.get(0);
+
+        Ticket {
+            id: Some(id),
+            event_name: row.get(1),
+            event_location: row.get(2),
+            event_date: event_date.map(local),
+            valid_until: valid_until.map(local),
+            status: row.get(4),
+            holder_name: row.get(5),
+            holder_email: row.get(6),
+            notes: row.get(7),
+            terms_and_conditions: row.get(8),
+            max_entries: row.get(10),
+            requires_checkout: row.get(11),
+            daily_entry_limit: row.get(12),
+            code: Some(codes::sign(id, row.get(9))),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub ticket_id: i64,
+    pub valid: bool,
+    pub status: String,
+    pub event_name: String,
+    pub holder_name: String,
+    // Why a ticket that is otherwise active is not valid, e.g. "superseded" for a code issued
+    // before the ticket was transferred or "max_entries_reached" when its entry rules do not
+    // allow another entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    // Entries recorded for the ticket, including an entry made by this scan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<i64>,
+}
+
+// Entry in a ticket's history.
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    pub action: String,
+    pub details: Value,
+    pub key_id: Option<i64>,
+    pub user_id: Option<Uuid>,
+    pub created_at: String,
+}
+
+// Ticket creation. Missing fields are stored as empty strings, missing dates as NULL and missing
+// entry rules as the defaults.
+pub async fn insert_ticket(caller: &Caller, ticket: &Ticket) -> Result<i64, Error> {
+    let event_name = ticket.event_name.clone().unwrap_or_default();
+    let event_location = ticket.event_location.clone().unwrap_or_default();
+    let event_date = ticket.event_date.clone().unwrap_or_default();
+    let valid_until = ticket.valid_until.clone().unwrap_or_default();
+    let status = ticket.status.clone().unwrap_or_default();
+    let holder_name = ticket.holder_name.clone().unwrap_or_default();
+    let holder_email = ticket.holder_email.clone().unwrap_or_default();
+    let notes = ticket.notes.clone().unwrap_or_default();
+    let terms_and_conditions = ticket.terms_and_conditions.clone().unwrap_or_default();
+
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query =
+        "INSERT INTO tickets (event_name, event_location, event_date, status, holder_name, holder_email, notes, terms_and_conditions, key_id, owner_id, max_entries, requires_checkout, daily_entry_limit, valid_until, event_utc_offset) VALUES ($1, $2, NULLIF($3::text, '')::timestamptz, $4, $5, $6, $7, $8, $9, $10, COALESCE($11, 1), COALESCE($12, TRUE), COALESCE($13, 0), NULLIF($14::text, '')::timestamptz, COALESCE($15, 0)) RETURNING id";
+    let row = transaction.query_one(
+        query,
+        &[
+            &event_name,
+            &event_location,
+            &event_date,
+            &status,
+            &holder_name,
+            &holder_email,
+            &notes,
+            &terms_and_conditions,
+            &caller.key_id,
+            &caller.account_id,
+            &ticket.max_entries,
+            &ticket.requires_checkout,
+            &ticket.daily_entry_limit,
+            &valid_until,
+            &utc_offset(ticket.event_date.as_deref()),
+        ]
+    ).await?;
+    let ticket_id: i64 = row.get(0);
+    record_history(&transaction, caller, ticket_id, "created", Value::Null).await?;
+
+    transaction.commit().await?;
+
+    Ok(ticket_id)
+}
+
+// Status of a ticket before and after an update.
+pub struct StatusChange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl StatusChange {
+    pub fn cancelled(&self) -> bool {
+        self.to.as_deref() == Some("Cancelled") && self.from != self.to
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    NotFound,
+    // The update would change the holder, which only a transfer may do.
+    HolderChanged,
+    DatabaseError,
+}
+
+impl From<Error> for UpdateError {
+    fn from(_: Error) -> UpdateError {
+        UpdateError::DatabaseError
+    }
+}
+
+impl UpdateError {
+    pub fn status(&self) -> Status {
+        match self {
+            UpdateError::NotFound => Status::NotFound,
+            UpdateError::HolderChanged => Status::Conflict,
+            UpdateError::DatabaseError => Status::InternalServerError,
+        }
+    }
+}
+
+// Ticket update. Only fields that are present and not empty are changed. The holder cannot be
+// changed here: a new holder gets the ticket through a transfer, which follows the event's
+// transfer policy and invalidates the previous holder's code.
+pub async fn update_ticket(caller: &Caller, ticket_id: i64, ticket: &Ticket) -> Result<StatusChange, UpdateError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let holder = transaction
+        .query_opt(
+            "SELECT holder_name, holder_email FROM tickets WHERE id = $1 AND owner_id = $2 FOR UPDATE",
+            &[&ticket_id, &caller.account_id]
+        ).await?
+        .ok_or(UpdateError::NotFound)?;
+    let changes = |requested: &Option<String>, current: Option<String>| {
+        requested.as_deref().is_some_and(|requested| !requested.is_empty() && Some(requested) != current.as_deref())
+    };
+    if changes(&ticket.holder_name, holder.get(0)) || changes(&ticket.holder_email, holder.get(1)) {
+        return Err(UpdateError::HolderChanged);
+    }
+
+    let query =
+        "WITH old AS (SELECT id AS old_id, status AS old_status FROM tickets WHERE id = $1 AND owner_id = $2) UPDATE tickets SET updated_at = NOW(), event_name = COALESCE(NULLIF($3, ''), event_name), event_location = COALESCE(NULLIF($4, ''), event_location), event_date = COALESCE(NULLIF($5::text, '')::timestamptz, event_date), status = COALESCE(NULLIF($6, ''), status), notes = COALESCE(NULLIF($7, ''), notes), terms_and_conditions = COALESCE(NULLIF($8, ''), terms_and_conditions), max_entries = COALESCE($9, max_entries), requires_checkout = COALESCE($10, requires_checkout), daily_entry_limit = COALESCE($11, daily_entry_limit), valid_until = COALESCE(NULLIF($12::text, '')::timestamptz, valid_until), event_utc_offset = CASE WHEN NULLIF($5::text, '') IS NULL THEN event_utc_offset ELSE COALESCE($13, 0) END FROM old WHERE id = old.old_id RETURNING old.old_status, status";
+    let row = transaction.query_one(
+        query,
+        &[
+            &ticket_id,
+            &caller.account_id,
+            &ticket.event_name,
+            &ticket.event_location,
+            &ticket.event_date,
+            &ticket.status,
+            &ticket.notes,
+            &ticket.terms_and_conditions,
+            &ticket.max_entries,
+            &ticket.requires_checkout,
+            &ticket.daily_entry_limit,
+            &ticket.valid_until,
+            &utc_offset(ticket.event_date.as_deref()),
+        ]
+    ).await?;
+    let change = StatusChange { from: row.get(0), to: row.get(1) };
+    record_history(&transaction, caller, ticket_id, "updated", Value::Null).await?;
+    passes::queue_push(&transaction, caller.account_id, ticket_id).await?;
+
+    transaction.commit().await?;
+
+    Ok(change)
+}
+
+pub async fn get_ticket(caller: &Caller, ticket_id: i64) -> Result<Option<Ticket>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query = format!("SELECT {} FROM tickets WHERE id = $1 AND owner_id = $2", TICKET_COLUMNS);
+    let row = transaction.query_opt(&query, &[&ticket_id, &caller.account_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(row.as_ref().map(Ticket::from_row))
+}
+
+// Ticket lookup as part of a larger change, e.g. to email it in the transaction that transferred
+// it. Not limited to an account; the caller has checked ownership.
+pub async fn load(client: &impl GenericClient, ticket_id: i64) -> Result<Option<Ticket>, Error> {
+    let query = format!("SELECT {} FROM tickets WHERE id = $1", TICKET_COLUMNS);
+    let row = client.query_opt(&query, &[&ticket_id]).await?;
+
+    Ok(row.as_ref().map(Ticket::from_row))
+}
+
+// Ticket with the data only needed internally.
+pub struct StoredTicket {
+    pub ticket: Ticket,
+    pub owner_id: Uuid,
+    pub code_version: i32,
+    pub updated_at: DateTime<Utc>,
+}
+
+// Ticket lookup without a caller, for clients that authenticate with a per-ticket token (Wallet).
+pub async fn find_ticket(ticket_id: i64) -> Result<Option<StoredTicket>, Error> {
+    let client = db::connect().await?;
+
+    let query = format!(
+        "SELECT {}, owner_id, COALESCE(updated_at, created_at) FROM tickets WHERE id = $1",
+        TICKET_COLUMNS
+    );
+    let row = client.query_opt(&query, &[&ticket_id]).await?;
+
+    Ok(
+        row.map(|row| StoredTicket {
+            ticket: Ticket::from_row(&row),
+            code_version: row.get(9),
+            owner_id: row.get(15),
+            updated_at: row.get(16),
+        })
+    )
+}
+
+// Ticket verification for door scanners: whether the ticket would be admitted now. Only "Active"
+// tickets whose entry rules allow another entry are valid. Nothing is recorded, see
+// scans::record_scan.
+pub async fn verify_ticket(caller: &Caller, ticket_id: i64) -> Result<Option<Verification>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let evaluation = scans::evaluate(&transaction, caller, ticket_id, None, None, Direction::Entry, Utc::now()).await?;
+
+    transaction.commit().await?;
+
+    Ok(evaluation.map(|evaluation| evaluation.verification))
+}
+
+// Verification of a scanned code. Returns None for forged codes and codes of tickets the
+// account does not own.
+pub async fn verify_code(caller: &Caller, code: &str) -> Result<Option<Verification>, Error> {
+    let Some((ticket_id, version)) = codes::parse(code) else {
+        return Ok(None);
+    };
+
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let evaluation = scans::evaluate(&transaction, caller, ticket_id, Some(version), None, Direction::Entry, Utc::now()).await?;
+
+    transaction.commit().await?;
+
+    Ok(evaluation.map(|evaluation| evaluation.verification))
+}
+
+// Adds an entry to a ticket's history, as part of the transaction that made the change.
+pub async fn record_history(
+    transaction: &Transaction<'_>,
+    caller: &Caller,
+    ticket_id: i64,
+    action: &str,
+    details: Value
+) -> Result<(), Error> {
+    record_history_of(transaction, caller.account_id, caller.key_id, Some(caller.user_id), ticket_id, action, details).await
+}
+
+// Adds an entry to the history of an account's ticket, made by the given key or user if any
+// (None for changes without a caller, such as a transfer accepted with its token).
+pub async fn record_history_of(
+    transaction: &Transaction<'_>,
+    owner_id: Uuid,
+    key_id: Option<i64>,
+    user_id: Option<Uuid>,
+    ticket_id: i64,
+    action: &str,
+    details: Value
+) -> Result<(), Error> {
+    transaction.execute(
+        "INSERT INTO ticket_history (ticket_id, owner_id, action, details, key_id, user_id) VALUES ($1, $2, $3, $4, $5, $6)",
+        &[&ticket_id, &owner_id, &action, &details, &key_id, &user_id]
+    ).await?;
+
+    Ok(())
+}
+
+// History of a ticket, oldest first. Returns None if the account has no such ticket.
+pub async fn get_history(caller: &Caller, ticket_id: i64) -> Result<Option<Vec<HistoryEntry>>, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let exists = transaction
+        .query_opt("SELECT 1 FROM tickets WHERE id = $1 AND owner_id = $2", &[&ticket_id, &caller.account_id]).await?
+        .is_some();
+    if !exists {
+        return Ok(None);
+    }
+
+    let rows = transaction.query(
+        "SELECT action, details, key_id, user_id, created_at FROM ticket_history WHERE ticket_id = $1 ORDER BY id",
+        &[&ticket_id]
+    ).await?;
+
+    transaction.commit().await?;
+
+    Ok(
+        Some(
+            rows
+                .iter()
+                .map(|row| {
+                    let created_at: DateTime<Utc> = row.get(4);
+                    HistoryEntry {
+                        action: row.get(0),
+                        details: row.get(1),
+                        key_id: row.get(2),
+                        user_id: row.get(3),
+                        created_at: created_at.to_rfc3339(),
+                    }
+                })
+                .collect()
+        )
+    )
+}
+
+// Deletes a ticket and tells its holder it was cancelled, unless it already was. The email's
+// delivery record outlives the ticket. Returns false if the account has no such ticket.
+pub async fn delete_ticket(caller: &Caller, mail: &Mail, ticket_id: i64) -> Result<bool, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let query = format!("SELECT {} FROM tickets WHERE id = $1 AND owner_id = $2 FOR UPDATE", TICKET_COLUMNS);
+    let Some(row) = transaction.query_opt(&query, &[&ticket_id, &caller.account_id]).await? else {
+        return Ok(false);
+    };
+    let ticket = Ticket::from_row(&row);
+    if let Some(recipient) = ticket.holder_email.as_deref().filter(|email| !email.is_empty()) {
+        if ticket.status.as_deref() != Some("Cancelled") {
+            mail.queue(&transaction, caller.account_id, &ticket, Template::Cancelled, recipient, &[]).await?;
+        }
+    }
+
+    transaction.execute("DELETE FROM tickets WHERE id = $1", &[&ticket_id]).await?;
+
+    transaction.commit().await?;
+
+    Ok(true)
+}
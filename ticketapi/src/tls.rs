@@ -0,0 +1,350 @@
+// TLS for database connections. The mode is taken from the sslmode parameter of database_url,
+// like libpq does:
+//
+// disable      no TLS
+// prefer       TLS if the server supports it, without verifying its certificate (the default)
+// require      TLS, without verifying the server's certificate
+// verify-full  TLS with a certificate issued by a trusted CA for the host connected to
+//
+// Trusted CAs are those in sslrootcert (or database_ca_file), a PEM bundle, or the Mozilla root
+// store if neither is set. A client certificate and key (sslcert and sslkey, or
+// database_client_certificate and database_client_key) are presented if the server asks for one.
+// tokio-postgres does not know the libpq-only parameters, so they are removed from the connection
+// string before it is parsed.
+
+use std::fs;
+use std::io::BufReader;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use rustls::client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier };
+use rustls::crypto::{ self, CryptoProvider };
+use rustls::pki_types::{ CertificateDer, PrivateKeyDer, ServerName, UnixTime };
+use rustls::{ ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme };
+use tokio_postgres::config::SslMode as PostgresSslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::config::Settings;
+
+// Connection string parameters handled here instead of by tokio-postgres.
+const TLS_PARAMETERS: [&str; 4] = ["sslmode", "sslrootcert", "sslcert", "sslkey"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyFull,
+}
+
+impl SslMode {
+    fn parse(value: &str) -> Result<SslMode, String> {
+        match value {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            other => Err(format!("sslmode must be disable, prefer, require or verify-full, not {}", other)),
+        }
+    }
+}
+
+// Connection settings of the database: what tokio-postgres needs, and the TLS connector unless
+// TLS is disabled.
+pub struct Target {
+    pub config: tokio_postgres::Config,
+    pub tls: Option<MakeRustlsConnect>,
+}
+
+pub fn target(settings: &Settings) -> Result<Target, String> {
+    let (database_url, parameters) = split_parameters(&settings.database_url)?;
+    let parameter = |name: &str| {
+        parameters
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+
+    let mut config: tokio_postgres::Config = database_url
+        .parse()
+        .map_err(|e| format!("database_url is not a valid connection string: {}", e))?;
+    let mode = parameter("sslmode").map(|mode| SslMode::parse(&mode)).transpose()?.unwrap_or(SslMode::Prefer);
+    config.ssl_mode(match mode {
+        SslMode::Disable => PostgresSslMode::Disable,
+        SslMode::Prefer => PostgresSslMode::Prefer,
+        SslMode::Require | SslMode::VerifyFull => PostgresSslMode::Require,
+    });
+
+    if mode == SslMode::Disable {
+        return Ok(Target { config, tls: None });
+    }
+
+    let ca_file = settings.database_ca_file.clone().or_else(|| parameter("sslrootcert").map(PathBuf::from));
+    let certificate = settings.database_client_certificate.clone().or_else(|| parameter("sslcert").map(PathBuf::from));
+    let key = settings.database_client_key.clone().or_else(|| parameter("sslkey").map(PathBuf::from));
+
+    let provider = Arc::new(crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Cannot set up TLS: {}", e))?;
+    let builder = if mode == SslMode::VerifyFull {
+        let mut roots = RootCertStore::empty();
+        match &ca_file {
+            Some(file) => {
+                for certificate in read_certificates(file)? {
+                    roots.add(certificate).map_err(|e| format!("Invalid CA certificate in {}: {}", file.display(), e))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots)
+    } else {
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+    };
+
+    let client_config = match (certificate, key) {
+        (Some(certificate), Some(key)) =>
+            builder
+                .with_client_auth_cert(read_certificates(&certificate)?, read_key(&key)?)
+                .map_err(|e| format!("Cannot use client certificate {}: {}", certificate.display(), e))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err("A client certificate needs both a certificate and a key (sslcert and sslkey)".to_string());
+        }
+    };
+
+    Ok(Target { config, tls: Some(MakeRustlsConnect::new(client_config)) })
+}
+
+// Removes the TLS parameters from a connection string, either a URL or key=value pairs.
+fn split_parameters(database_url: &str) -> Result<(String, Vec<(String, String)>), String> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        return Ok(split_url_parameters(database_url));
+    }
+
+    let mut kept = Vec::new();
+    let mut parameters = Vec::new();
+    let mut rest = database_url.trim_start();
+    while !rest.is_empty() {
+        let (key, value, raw, remainder) = next_pair(rest)?;
+        if TLS_PARAMETERS.contains(&key) {
+            parameters.push((key.to_string(), value));
+        } else {
+            kept.push(raw);
+        }
+        rest = remainder.trim_start();
+    }
+
+    Ok((kept.join(" "), parameters))
+}
+
+fn split_url_parameters(database_url: &str) -> (String, Vec<(String, String)>) {
+    let Some((base, query)) = database_url.split_once('?') else {
+        return (database_url.to_string(), Vec::new());
+    };
+
+    let mut kept = Vec::new();
+    let mut parameters = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if TLS_PARAMETERS.contains(&key) {
+            parameters.push((key.to_string(), percent_decode(value)));
+        } else {
+            kept.push(pair);
+        }
+    }
+
+    if kept.is_empty() {
+        (base.to_string(), parameters)
+    } else {
+        (format!("{}?{}", base, kept.join("&")), parameters)
+    }
+}
+
+// Next "key = value" pair of a key=value connection string: key, unquoted value, the pair as
+// written and the text after it.
+fn next_pair(input: &str) -> Result<(&str, String, &str, &str), String> {
+    let key_end = input.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(input.len());
+    let key = &input[..key_end];
+    let after_key = input[key_end..].trim_start();
+    let Some(after_equals) = after_key.strip_prefix('=') else {
+        return Err(format!("database_url is not a valid connection string: missing = after {}", key));
+    };
+    let value_start = after_equals.trim_start();
+
+    let mut value = String::new();
+    let mut chars = value_start.char_indices();
+    let end = if value_start.starts_with('\'') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some((_, '\\')) => {
+                    if let Some((_, c)) = chars.next() {
+                        value.push(c);
+                    }
+                }
+                Some((i, '\'')) => break i + 1,
+                Some((_, c)) => value.push(c),
+                None => return Err("database_url is not a valid connection string: unterminated quote".to_string()),
+            }
+        }
+    } else {
+        loop {
+            match chars.next() {
+                Some((i, c)) if c.is_whitespace() => break i,
+                Some((_, '\\')) => {
+                    if let Some((_, c)) = chars.next() {
+                        value.push(c);
+                    }
+                }
+                Some((_, c)) => value.push(c),
+                None => break value_start.len(),
+            }
+        }
+    };
+
+    let consumed = input.len() - value_start.len() + end;
+    Ok((key, value, &input[..consumed], &input[consumed..]))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let certificates = rustls_pemfile
+        ::certs(&mut BufReader::new(pem.as_slice()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{} is not a PEM certificate file: {}", path.display(), e))?;
+    if certificates.is_empty() {
+        return Err(format!("{} contains no certificates", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    rustls_pemfile
+        ::private_key(&mut BufReader::new(pem.as_slice()))
+        .map_err(|e| format!("{} is not a PEM private key: {}", path.display(), e))?
+        .ok_or_else(|| format!("{} contains no private key", path.display()))
+}
+
+// Certificate check of the prefer and require modes: the connection is encrypted, but the
+// server's identity is not checked. Handshake signatures are still verified.
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, certificate, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, certificate, signature, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn splits_url_parameters() {
+        let (url, parameters) = split_parameters(
+            "postgres://user:pw@db.example.com:5432/postgres?sslmode=verify-full&application_name=api&sslrootcert=%2Fetc%2Fca.pem"
+        ).unwrap();
+
+        assert_eq!(url, "postgres://user:pw@db.example.com:5432/postgres?application_name=api");
+        assert_eq!(parameters, pairs(&[("sslmode", "verify-full"), ("sslrootcert", "/etc/ca.pem")]));
+    }
+
+    #[test]
+    fn url_without_other_parameters_loses_its_query() {
+        let (url, parameters) = split_parameters("postgresql://localhost/postgres?sslmode=disable").unwrap();
+
+        assert_eq!(url, "postgresql://localhost/postgres");
+        assert_eq!(parameters, pairs(&[("sslmode", "disable")]));
+
+        let (url, parameters) = split_parameters("postgres://localhost/postgres").unwrap();
+        assert_eq!(url, "postgres://localhost/postgres");
+        assert!(parameters.is_empty());
+    }
+
+    #[test]
+    fn splits_key_value_parameters() {
+        let (rest, parameters) = split_parameters(
+            r"host=localhost sslmode = require password='my \'secret\' pw' sslkey=/etc/key\ file.pem dbname=postgres"
+        ).unwrap();
+
+        // Other parameters are kept as written, quotes and escapes included.
+        assert_eq!(rest, r"host=localhost password='my \'secret\' pw' dbname=postgres");
+        assert_eq!(parameters, pairs(&[("sslmode", "require"), ("sslkey", "/etc/key file.pem")]));
+    }
+
+    #[test]
+    fn reads_pairs() {
+        let (key, value, raw, rest) = next_pair("sslrootcert = '/etc/ca bundle.pem'  host=db").unwrap();
+        assert_eq!((key, value.as_str(), raw, rest), ("sslrootcert", "/etc/ca bundle.pem", "sslrootcert = '/etc/ca bundle.pem'", "  host=db"));
+
+        let (key, value, raw, rest) = next_pair("host=").unwrap();
+        assert_eq!((key, value.as_str(), raw, rest), ("host", "", "host=", ""));
+    }
+
+    #[test]
+    fn rejects_malformed_pairs() {
+        assert!(next_pair("host localhost").is_err());
+        assert!(next_pair("password='open").is_err());
+        assert!(split_parameters("host=localhost sslmode").is_err());
+    }
+}
@@ -0,0 +1,324 @@
+// Transfers of tickets to a new holder. A transfer either completes immediately or waits for the
+// recipient to accept it with a one-time token, which is emailed to them and never shown to the
+// account that transfers the ticket. Completing it reassigns the holder and bumps the ticket's
+// code version in one statement, so the previous holder's QR code stops working, emails the
+// ticket to the new holder and tells the previous one. Tokens expire after TOKEN_LIFETIME_DAYS.
+
+use chrono::{ DateTime, Duration, Utc };
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::http::Status;
+use rocket::serde::{ Deserialize, Serialize };
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+use tokio_postgres::{ Error, Row, Transaction };
+use uuid::Uuid;
+
+use crate::mail::{ Mail, Template };
+use crate::tickets::{ self, record_history };
+use crate::{ db, events, passes, Caller };
+
+const TOKEN_LEN: usize = 32;
+
+// Days a recipient has to accept a transfer.
+const TOKEN_LIFETIME_DAYS: i64 = 7;
+
+#[derive(Deserialize)]
+pub struct TransferRequest {
+    pub to_name: String,
+    pub to_email: String,
+    // Whether the recipient has to accept the transfer with the token emailed to them.
+    #[serde(default)]
+    pub require_acceptance: bool,
+}
+
+#[derive(Deserialize)]
+pub struct AcceptRequest {
+    pub token: String,
+}
+
+#[derive(Serialize)]
+pub struct Transfer {
+    pub id: i64,
+    pub ticket_id: i64,
+    pub from_name: String,
+    pub from_email: String,
+    pub to_name: String,
+    pub to_email: String,
+    pub status: String,
+    pub created_at: String,
+    pub completed_at: Option<String>,
+    // Time the acceptance token expires, None if the transfer needs no acceptance.
+    pub expires_at: Option<String>,
+}
+
+const TRANSFER_COLUMNS: &str =
+    "id, ticket_id, from_name, from_email, to_name, to_email, status, created_at, completed_at, code_version, token_hash, owner_id, expires_at";
+
+impl Transfer {
+    fn from_row(row: &Row) -> Transfer {
+        let created_at: DateTime<Utc> = row.get(7);
+        let completed_at: Option<DateTime<Utc>> = row.get(8);
+        let expires_at: Option<DateTime<Utc>> = row.get(12);
+
+        Transfer {
+            id: row.get(0),
+            ticket_id: row.get(1),
+            from_name: row.get(2),
+            from_email: row.get(3),
+            to_name: row.get(4),
+            to_email: row.get(5),
+            status: row.get(6),
+            created_at: created_at.to_rfc3339(),
+            completed_at: completed_at.map(|completed_at| completed_at.to_rfc3339()),
+            expires_at: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    NotFound,
+    // Transfers are disabled for the ticket's event.
+    Disabled,
+    // The ticket is not active.
+    NotTransferable,
+    // The transfer is no longer pending.
+    NotPending,
+    // The ticket changed hands or status since the transfer was created.
+    Stale,
+    InvalidToken,
+    // The recipient did not accept the transfer in time.
+    Expired,
+    DatabaseError,
+}
+
+impl From<Error> for TransferError {
+    fn from(_: Error) -> TransferError {
+        TransferError::DatabaseError
+    }
+}
+
+impl TransferError {
+    pub fn status(&self) -> Status {
+        match self {
+            TransferError::NotFound => Status::NotFound,
+            TransferError::Disabled | TransferError::InvalidToken => Status::Forbidden,
+            TransferError::NotTransferable | TransferError::NotPending | TransferError::Stale => Status::Conflict,
+            TransferError::Expired => Status::Gone,
+            TransferError::DatabaseError => Status::InternalServerError,
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Starts a transfer of a ticket. Pending transfers of the same ticket are cancelled.
+pub async fn initiate(caller: &Caller, mail: &Mail, ticket_id: i64, request: &TransferRequest) -> Result<Transfer, TransferError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let ticket = transaction
+        .query_opt(
+            "SELECT status, event_name, COALESCE(holder_name, ''), COALESCE(holder_email, ''), code_version FROM tickets WHERE id = $1 AND owner_id = $2 FOR UPDATE",
+            &[&ticket_id, &caller.account_id]
+        ).await?
+        .ok_or(TransferError::NotFound)?;
+
+    let status: String = ticket.get(0);
+    if status != "Active" {
+        return Err(TransferError::NotTransferable);
+    }
+    let event_name: String = ticket.get(1);
+    if !events::policy_of(&transaction, caller.account_id, &event_name).await?.transfers_enabled {
+        return Err(TransferError::Disabled);
+    }
+
+    transaction.execute(
+        "UPDATE ticket_transfers SET status = 'cancelled' WHERE ticket_id = $1 AND status = 'pending'",
+        &[&ticket_id]
+    ).await?;
+
+    let token: Option<String> = request.require_acceptance.then(|| {
+        rand::thread_rng().sample_iter(&Alphanumeric).take(TOKEN_LEN).map(char::from).collect()
+    });
+    let token_hash = token.as_deref().map(hash_token);
+    let expires_at = token.is_some().then(|| Utc::now() + Duration::days(TOKEN_LIFETIME_DAYS));
+
+    let query = format!(
+        "INSERT INTO ticket_transfers (ticket_id, owner_id, from_name, from_email, to_name, to_email, code_version, token_hash, expires_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING {}",
+        TRANSFER_COLUMNS
+    );
+    let row = transaction.query_one(
+        &query,
+        &[
+            &ticket_id,
+            &caller.account_id,
+            &ticket.get::<_, String>(2),
+            &ticket.get::<_, String>(3),
+            &request.to_name,
+            &request.to_email,
+            &ticket.get::<_, i32>(4),
+            &token_hash,
+            &expires_at,
+        ]
+    ).await?;
+
+    let mut transfer = Transfer::from_row(&row);
+    record_history(
+        &transaction,
+        caller,
+        ticket_id,
+        "transfer_initiated",
+        json!({ "transfer_id": transfer.id, "to_email": request.to_email })
+    ).await?;
+
+    match token {
+        Some(token) => {
+            // The recipient does not get the code before accepting.
+            let mut ticket = tickets::load(&transaction, ticket_id).await?.ok_or(TransferError::NotFound)?;
+            ticket.code = None;
+            let expires_at = transfer.expires_at.as_deref().unwrap_or("");
+            let fields = [
+                ("to_name", transfer.to_name.as_str()),
+                ("from_name", transfer.from_name.as_str()),
+                ("token", token.as_str()),
+                ("expires_at", expires_at),
+            ];
+            mail.queue(&transaction, caller.account_id, &ticket, Template::TransferOffer, &transfer.to_email, &fields).await?;
+        }
+        None => {
+            transfer = complete(&transaction, mail, &row).await?;
+            record_history(&transaction, caller, ticket_id, "transferred", transferred(&transfer)).await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(transfer)
+}
+
+// Completes a pending transfer that required acceptance. Anyone with the token may accept it, so
+// this runs without a caller; the history entry names no key or user.
+pub async fn accept(mail: &Mail, token: &str) -> Result<Transfer, TransferError> {
+    let mut client = db::connect().await?;
+    // There is no user to run as, so this transaction bypasses row level security. The token is
+    // what authorizes it: every query below is limited to the transfer it belongs to.
+    let transaction = client.transaction().await?;
+
+    let query = format!("SELECT {} FROM ticket_transfers WHERE token_hash = $1 FOR UPDATE", TRANSFER_COLUMNS);
+    let row = transaction
+        .query_opt(&query, &[&hash_token(token)]).await?
+        .ok_or(TransferError::InvalidToken)?;
+    if row.get::<_, &str>(6) != "pending" {
+        return Err(TransferError::NotPending);
+    }
+    let expires_at: Option<DateTime<Utc>> = row.get(12);
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(TransferError::Expired);
+    }
+
+    // The event may have stopped allowing transfers since the transfer was created.
+    let owner_id: Uuid = row.get(11);
+    let event_name: String = transaction
+        .query_opt("SELECT event_name FROM tickets WHERE id = $1 AND owner_id = $2", &[&row.get::<_, i64>(1), &owner_id]).await?
+        .ok_or(TransferError::NotFound)?
+        .get(0);
+    if !events::policy_of(&transaction, owner_id, &event_name).await?.transfers_enabled {
+        return Err(TransferError::Disabled);
+    }
+
+    let transfer = complete(&transaction, mail, &row).await?;
+    let details = transferred(&transfer);
+    tickets::record_history_of(&transaction, owner_id, None, None, transfer.ticket_id, "transferred", details).await?;
+
+    transaction.commit().await?;
+
+    Ok(transfer)
+}
+
+pub async fn cancel(caller: &Caller, transfer_id: i64) -> Result<Transfer, TransferError> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, caller.user_id).await?;
+
+    let row = pending(&transaction, caller, transfer_id).await?;
+    let query = format!("UPDATE ticket_transfers SET status = 'cancelled' WHERE id = $1 RETURNING {}", TRANSFER_COLUMNS);
+    let row = transaction.query_one(&query, &[&row.get::<_, i64>(0)]).await?;
+    let transfer = Transfer::from_row(&row);
+
+    record_history(
+        &transaction,
+        caller,
+        transfer.ticket_id,
+        "transfer_cancelled",
+        json!({ "transfer_id": transfer.id })
+    ).await?;
+
+    transaction.commit().await?;
+
+    Ok(transfer)
+}
+
+// Locks a pending transfer of the caller's account.
+async fn pending(transaction: &Transaction<'_>, caller: &Caller, transfer_id: i64) -> Result<Row, TransferError> {
+    let query = format!("SELECT {} FROM ticket_transfers WHERE id = $1 AND owner_id = $2 FOR UPDATE", TRANSFER_COLUMNS);
+    let row = transaction
+        .query_opt(&query, &[&transfer_id, &caller.account_id]).await?
+        .ok_or(TransferError::NotFound)?;
+
+    if row.get::<_, &str>(6) != "pending" {
+        return Err(TransferError::NotPending);
+    }
+
+    Ok(row)
+}
+
+// Reassigns the ticket, provided it is still active and has not changed hands since the
+// transfer was created, emails it to the new holder and tells the previous one. The caller
+// records the history entry.
+async fn complete(transaction: &Transaction<'_>, mail: &Mail, row: &Row) -> Result<Transfer, TransferError> {
+    let transfer = Transfer::from_row(row);
+    let code_version: i32 = row.get(9);
+    let owner_id: Uuid = row.get(11);
+
+    let reassigned = transaction.execute(
+        "UPDATE tickets SET holder_name = $1, holder_email = $2, code_version = code_version + 1, updated_at = NOW() WHERE id = $3 AND owner_id = $4 AND code_version = $5 AND status = 'Active'",
+        &[&transfer.to_name, &transfer.to_email, &transfer.ticket_id, &owner_id, &code_version]
+    ).await?;
+    if reassigned == 0 {
+        return Err(TransferError::Stale);
+    }
+    passes::queue_push(transaction, owner_id, transfer.ticket_id).await?;
+
+    let query = format!(
+        "UPDATE ticket_transfers SET status = 'completed', completed_at = NOW() WHERE id = $1 RETURNING {}",
+        TRANSFER_COLUMNS
+    );
+    let row = transaction.query_one(&query, &[&transfer.id]).await?;
+
+    let mut ticket = tickets::load(transaction, transfer.ticket_id).await?.ok_or(TransferError::NotFound)?;
+    if !transfer.to_email.is_empty() {
+        mail.queue(transaction, owner_id, &ticket, Template::Transferred, &transfer.to_email, &[]).await?;
+    }
+    if !transfer.from_email.is_empty() && transfer.from_email != transfer.to_email {
+        // The previous holder must not get the new code.
+        ticket.code = None;
+        let fields = [("from_name", transfer.from_name.as_str()), ("to_name", transfer.to_name.as_str())];
+        mail.queue(transaction, owner_id, &ticket, Template::TransferredAway, &transfer.from_email, &fields).await?;
+    }
+
+    Ok(Transfer::from_row(&row))
+}
+
+// History details of a completed transfer.
+fn transferred(transfer: &Transfer) -> Value {
+    json!({
+        "transfer_id": transfer.id,
+        "from_name": transfer.from_name,
+        "from_email": transfer.from_email,
+        "to_name": transfer.to_name,
+        "to_email": transfer.to_email,
+    })
+}
@@ -0,0 +1,298 @@
+// Per-key, per-day request analytics and the usage counters of keys (total_uses, last_used and the
+// monthly quota). Requests are counted in memory and flushed to usage_daily and keys in batches by
+// a background task, off the request path. Quota checks therefore see requests of the last flush
+// interval late.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{ DateTime, Datelike, NaiveDate, NaiveTime, Utc };
+use rocket::fairing::{ Fairing, Info, Kind };
+use rocket::serde::Serialize;
+use rocket::{ Request, Response };
+use tokio::sync::mpsc::{ self, UnboundedReceiver, UnboundedSender };
+use tokio_postgres::Error;
+use uuid::Uuid;
+
+use crate::{ config, db };
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct UsageBucket {
+    key_id: i64,
+    day: NaiveDate,
+    endpoint: String,
+    status: i16,
+}
+
+// Key id of the authenticated request, remembered by the Caller guard.
+#[derive(Debug, Clone, Copy)]
+struct RecordedKey(i64);
+
+pub fn remember(req: &Request<'_>, key_id: i64) {
+    req.local_cache(|| Some(RecordedKey(key_id)));
+}
+
+// Keys of a request that passed authentication, rate limiting and the quota: the key used (None
+// for sessions), whose total and last_used are updated when it is answered, and the key whose
+// quota it counts against.
+#[derive(Debug, Clone, Copy)]
+struct MeteredKey {
+    key_id: Option<i64>,
+    quota_key_id: i64,
+}
+
+pub fn meter(req: &Request<'_>, key_id: Option<i64>, quota_key_id: i64) {
+    req.local_cache(|| Some(MeteredKey { key_id, quota_key_id }));
+}
+
+// What the fairing sends to the flushing task for a response.
+enum UsageEvent {
+    Request(UsageBucket),
+    Metered(MeteredKey, DateTime<Utc>),
+}
+
+// Counts of the keys since the last flush.
+#[derive(Default)]
+struct KeyCounts {
+    // Requests and latest use per key used.
+    uses: HashMap<i64, (i64, DateTime<Utc>)>,
+    // Requests per quota key in the month (its start) of the latest request. Requests of an
+    // earlier month no longer count once a new month started.
+    periods: HashMap<i64, (DateTime<Utc>, i64)>,
+}
+
+impl KeyCounts {
+    fn add(&mut self, metered: MeteredKey, at: DateTime<Utc>) {
+        if let Some(key_id) = metered.key_id {
+            let (uses, last_used) = self.uses.entry(key_id).or_insert((0, at));
+            *uses += 1;
+            *last_used = (*last_used).max(at);
+        }
+
+        let period = at.date_naive().with_day(1).expect("every month has a first day").and_time(NaiveTime::MIN).and_utc();
+        let (start, uses) = self.periods.entry(metered.quota_key_id).or_insert((period, 0));
+        if period > *start {
+            *start = period;
+            *uses = 0;
+        }
+        if period == *start {
+            *uses += 1;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.uses.is_empty() && self.periods.is_empty()
+    }
+}
+
+pub struct UsageRecorder {
+    sender: UnboundedSender<UsageEvent>,
+}
+
+impl UsageRecorder {
+    // Spawns the flushing task. Must be called from within the tokio runtime.
+    pub fn start() -> UsageRecorder {
+        let interval = config::get().usage_flush_interval;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run(receiver, Duration::from_secs(interval)));
+
+        UsageRecorder { sender }
+    }
+}
+
+async fn run(mut receiver: UnboundedReceiver<UsageEvent>, interval: Duration) {
+    let mut pending: HashMap<UsageBucket, i64> = HashMap::new();
+    let mut keys = KeyCounts::default();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(UsageEvent::Request(bucket)) => *pending.entry(bucket).or_insert(0) += 1,
+                Some(UsageEvent::Metered(metered, at)) => keys.add(metered, at),
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if pending.is_empty() && keys.is_empty() {
+                    continue;
+                }
+                // Counts that could not be written are kept for the next flush.
+                if let Err(e) = flush(&pending, &keys).await {
+                    eprintln!("usage flush error: {}", e);
+                    continue;
+                }
+                pending.clear();
+                keys = KeyCounts::default();
+            }
+        }
+    }
+
+    if !pending.is_empty() || !keys.is_empty() {
+        if let Err(e) = flush(&pending, &keys).await {
+            eprintln!("usage flush error: {}", e);
+        }
+    }
+}
+
+// Writes the counts in one transaction, so a failed flush can be repeated without counting twice.
+async fn flush(pending: &HashMap<UsageBucket, i64>, keys: &KeyCounts) -> Result<(), Error> {
+    let mut client = db::connect().await?;
+    let transaction = client.transaction().await?;
+
+    let mut key_ids = Vec::with_capacity(pending.len());
+    let mut days = Vec::with_capacity(pending.len());
+    let mut endpoints = Vec::with_capacity(pending.len());
+    let mut statuses = Vec::with_capacity(pending.len());
+    let mut counts = Vec::with_capacity(pending.len());
+
+    for (bucket, count) in pending {
+        key_ids.push(bucket.key_id);
+        days.push(bucket.day);
+        endpoints.push(bucket.endpoint.clone());
+        statuses.push(bucket.status);
+        counts.push(*count);
+    }
+
+    let query =
+        "INSERT INTO usage_daily (key_id, day, endpoint, status, requests) SELECT * FROM UNNEST($1::bigint[], $2::date[], $3::text[], $4::smallint[], $5::bigint[]) ON CONFLICT (key_id, day, endpoint, status) DO UPDATE SET requests = usage_daily.requests + EXCLUDED.requests";
+    transaction.execute(query, &[&key_ids, &days, &endpoints, &statuses, &counts]).await?;
+
+    let mut key_ids = Vec::with_capacity(keys.uses.len());
+    let mut uses = Vec::with_capacity(keys.uses.len());
+    let mut last_used = Vec::with_capacity(keys.uses.len());
+    for (key_id, (count, at)) in &keys.uses {
+        key_ids.push(*key_id);
+        uses.push(*count);
+        last_used.push(*at);
+    }
+    let query =
+        "UPDATE keys k SET total_uses = k.total_uses + u.uses, last_used = GREATEST(k.last_used, u.last_used) FROM UNNEST($1::bigint[], $2::bigint[], $3::timestamptz[]) AS u(id, uses, last_used) WHERE k.id = u.id";
+    transaction.execute(query, &[&key_ids, &uses, &last_used]).await?;
+
+    // A period newer than the stored one starts the month over; counts of an older one are late.
+    let mut key_ids = Vec::with_capacity(keys.periods.len());
+    let mut periods = Vec::with_capacity(keys.periods.len());
+    let mut uses = Vec::with_capacity(keys.periods.len());
+    for (key_id, (period, count)) in &keys.periods {
+        key_ids.push(*key_id);
+        periods.push(*period);
+        uses.push(*count);
+    }
+    let query =
+        "UPDATE keys k SET period_uses = CASE WHEN k.period_start > u.period THEN k.period_uses WHEN k.period_start = u.period THEN k.period_uses + u.uses ELSE u.uses END, period_start = GREATEST(k.period_start, u.period) FROM UNNEST($1::bigint[], $2::timestamptz[], $3::bigint[]) AS u(id, period, uses) WHERE k.id = u.id";
+    transaction.execute(query, &[&key_ids, &periods, &uses]).await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+// Counts every response to an authenticated request by route and status, and every accepted
+// request against its key's totals and quota.
+pub struct UsageTracking;
+
+#[rocket::async_trait]
+impl Fairing for UsageTracking {
+    fn info(&self) -> Info {
+        Info { name: "Usage tracking", kind: Kind::Response }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let Some(recorder) = req.rocket().state::<UsageRecorder>() else {
+            return;
+        };
+
+        if let Some(metered) = req.local_cache(|| None::<MeteredKey>) {
+            let _ = recorder.sender.send(UsageEvent::Metered(*metered, Utc::now()));
+        }
+
+        if let (Some(RecordedKey(key_id)), Some(route)) = (req.local_cache(|| None::<RecordedKey>), req.route()) {
+            let _ = recorder.sender.send(UsageEvent::Request(UsageBucket {
+                key_id: *key_id,
+                day: Utc::now().date_naive(),
+                endpoint: format!("{} {}", route.method, route.uri),
+                status: res.status().code as i16,
+            }));
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct UsageRow {
+    pub period: String,
+    pub endpoint: String,
+    pub status: i16,
+    pub requests: i64,
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub from: String,
+    pub to: String,
+    pub interval: String,
+    pub total: i64,
+    pub usage: Vec<UsageRow>,
+}
+
+// Aggregates the usage of all keys of a user (or one of them) over a date range.
+pub async fn get_usage(
+    user_id: Uuid,
+    key_id: Option<i64>,
+    from: NaiveDate,
+    to: NaiveDate,
+    interval: &str
+) -> Result<UsageReport, Error> {
+    let mut client = db::connect().await?;
+    let transaction = db::begin_as(&mut client, user_id).await?;
+
+    let query =
+        "SELECT date_trunc($4, u.day)::date, u.endpoint, u.status, SUM(u.requests)::bigint FROM usage_daily u JOIN keys k ON k.id = u.key_id WHERE k.user_id = $1 AND u.day BETWEEN $2 AND $3 AND ($5::bigint IS NULL OR u.key_id = $5) GROUP BY 1, 2, 3 ORDER BY 1, 2, 3";
+    let rows = transaction.query(query, &[&user_id, &from, &to, &interval, &key_id]).await?;
+    transaction.commit().await?;
+
+    let usage: Vec<UsageRow> = rows
+        .iter()
+        .map(|row| UsageRow {
+            period: row.get::<_, NaiveDate>(0).to_string(),
+            endpoint: row.get(1),
+            status: row.get(2),
+            requests: row.get(3),
+        })
+        .collect();
+
+    Ok(UsageReport {
+        from: from.to_string(),
+        to: to.to_string(),
+        interval: interval.to_string(),
+        total: usage.iter().map(|row| row.requests).sum(),
+        usage,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn counts_uses_per_key_and_quota_per_month() {
+        let mut keys = KeyCounts::default();
+        let march = Utc.with_ymd_and_hms(2026, 3, 31, 23, 0, 0).unwrap();
+        let april = Utc.with_ymd_and_hms(2026, 4, 1, 0, 30, 0).unwrap();
+
+        // A rotated key and its successor share the successor's quota.
+        keys.add(MeteredKey { key_id: Some(1), quota_key_id: 2 }, march);
+        keys.add(MeteredKey { key_id: Some(2), quota_key_id: 2 }, march);
+        keys.add(MeteredKey { key_id: Some(2), quota_key_id: 2 }, april);
+        // Sessions count against the quota only.
+        keys.add(MeteredKey { key_id: None, quota_key_id: 2 }, april);
+        // Late arrival from the previous month.
+        keys.add(MeteredKey { key_id: Some(1), quota_key_id: 2 }, march);
+
+        assert_eq!(keys.uses[&1], (2, march));
+        assert_eq!(keys.uses[&2], (2, april));
+        assert_eq!(keys.periods[&2], (Utc.with_ymd_and_hms(2026, 4, 1, 0, 0, 0).unwrap(), 2));
+    }
+}